@@ -18,7 +18,7 @@ fn main() {
                 Err(ref err) => {
                     println!("There was an error! {}", err);
                 },
-                Ok(()) => {
+                Ok(_) => {
                     println!("It worked");
                 },
             }
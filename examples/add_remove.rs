@@ -24,7 +24,7 @@ fn main() {
             match gateway.add_port(igd::PortMappingProtocol::TCP, remote_port,
                                 local_addr, 60, "crust") {
                 Err(ref err) => println!("{:?}", err),
-                Ok(()) => {
+                Ok(_) => {
                     println!("AddPortMapping successful.");
                     match gateway.remove_port(igd::PortMappingProtocol::TCP, remote_port) {
                         Err(ref err) => println!("Error removing: {:?}", err),
@@ -1,38 +1,67 @@
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
 use std::hash::{Hash, Hasher};
 use std::fmt;
+use std::io;
+use std::mem;
+use std::str::FromStr;
+use std::cell::Cell;
+use std::rc::Rc;
+#[cfg(test)]
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use rand::distributions::IndependentSample;
 
 use xmltree;
-use futures::Future;
+use futures::{Future, Stream};
 use futures::future;
+use futures::stream;
+use tokio_core::net::TcpStream;
 use tokio_core::reactor::Handle;
+use tokio_timer::Timer;
 use tokio_retry::{Error as RetryError, RetryIf};
-use tokio_retry::strategy::FixedInterval;
+use tokio_retry::strategy::jitter;
 use rand;
 use soap;
-use errors::{AddAnyPortError, AddPortError, GetExternalIpError, RemovePortError, RequestError};
+use messages;
+use gateway::{ControlUrl, Gateway as SyncGateway, RequestMetrics, CONNECTION_POLL_INTERVAL};
+use errors::{AddAnyPortError, AddPinholeError, AddPortError, GetExternalIpError,
+             GetGenericPortMappingEntryError, GetSpecificPortMappingEntryError, PinholeError,
+             RemovePortError, RemovePortsError, RequestError, SetPortMappingEnabledError};
 
-use PortMappingProtocol;
+use {ConnectionStatus, ConnectionTypeInfo, NatRsipStatus, PortMappingEntry, PortMappingProtocol,
+     Reachability, StatusInfo};
+
+lazy_static! {
+    // Timer of the request timeouts, shared by all the gateways since each timer runs on its own
+    // thread, and the blocking gateway converts itself to an asynchronous one for every request.
+    static ref TIMER: Timer = Timer::default();
+}
 
 /// This structure represents a gateway found by the search functions.
+///
+/// All the gateways share the timer thread the request timeouts run on.
 #[derive(Clone, Debug)]
 pub struct Gateway {
-    /// Socket address of the gateway
-    addr: SocketAddrV4,
-    /// Control url of the device
-    control_url: String,
-
+    inner: SyncGateway,
     handle: Handle,
+    timer: Timer,
 }
 
 impl Gateway {
     /// Create a new Gateway for a given Handle to a control loop
+    ///
+    /// The gateway is assumed to expose the WANIPConnection service.
     pub fn new(addr: SocketAddrV4, control_url: String, handle: Handle) -> Gateway {
+        Gateway::from_gateway(SyncGateway::new(addr, &control_url), handle)
+    }
+
+    /// Create a new Gateway for a given Handle to a control loop from a gateway found by the
+    /// synchronous search functions
+    pub fn from_gateway(gateway: SyncGateway, handle: Handle) -> Gateway {
         Gateway {
-            addr: addr,
-            control_url: control_url,
+            inner: gateway,
             handle: handle,
+            timer: TIMER.clone(),
         }
     }
 
@@ -43,35 +72,375 @@ impl Gateway {
         ok: &str,
     ) -> Box<Future<Item = (String, xmltree::Element), Error = RequestError>> {
         let url = format!("{}", self);
+        self.perform_request_at(&url, header, body, ok)
+    }
+
+    // Perform a request on the WANCommonInterfaceConfig service.
+    fn perform_common_interface_request(
+        &self,
+        header: &str,
+        body: &str,
+        ok: &str,
+    ) -> Box<Future<Item = (String, xmltree::Element), Error = RequestError>> {
+        let control_url = self.inner.common_interface_control_url.as_ref();
+        self.perform_service_request(control_url, "WANCommonInterfaceConfig", header, body, ok)
+    }
+
+    // Perform a request on the WANIPv6FirewallControl service.
+    fn perform_firewall_request(
+        &self,
+        header: &str,
+        body: &str,
+        ok: &str,
+    ) -> Box<Future<Item = (String, xmltree::Element), Error = RequestError>> {
+        let control_url = self.inner.ipv6_firewall_control_url.as_ref();
+        self.perform_service_request(control_url, "WANIPv6FirewallControl", header, body, ok)
+    }
+
+    // Perform a request on an optional service, failing when the gateway does not expose it.
+    fn perform_service_request(
+        &self,
+        control_url: Option<&String>,
+        service: &str,
+        header: &str,
+        body: &str,
+        ok: &str,
+    ) -> Box<Future<Item = (String, xmltree::Element), Error = RequestError>> {
+        let url = match control_url {
+            Some(control_url) => ControlUrl::new(control_url).resolve(self.inner.addr),
+            None => {
+                return Box::new(future::err(RequestError::UnsupportedAction(format!(
+                    "The gateway does not expose the {} service",
+                    service
+                ))))
+            }
+        };
+        self.perform_request_at(&url, header, body, ok)
+    }
+
+    fn perform_request_at(
+        &self,
+        url: &str,
+        header: &str,
+        body: &str,
+        ok: &str,
+    ) -> Box<Future<Item = (String, xmltree::Element), Error = RequestError>> {
+        let gateway = self.clone();
+        let url = url.to_owned();
+        let body = body.to_owned();
+        let ok = ok.to_owned();
+        let quoting = self.inner.action_quoting.clone();
+        let known = quoting.get();
+        let action = match known {
+            Some(quoted) => soap::Action::new(header).with_quoting(quoted),
+            None => soap::Action::new(header),
+        };
+        let future = self.request_parsed(&url, action.as_str(), &body, &ok).then(
+            move |result| -> Box<Future<Item = _, Error = _>> {
+                if known.is_some() {
+                    return Box::new(future::result(result));
+                }
+                let err = match result {
+                    Err(err) => err,
+                    Ok(response) => {
+                        quoting.set(action.is_quoted());
+                        return Box::new(future::ok(response));
+                    }
+                };
+                if !is_invalid_action(&err) {
+                    if is_answer(&err) {
+                        quoting.set(action.is_quoted());
+                    }
+                    return Box::new(future::err(err));
+                }
+                // Firmwares are strict about the quoting of the SOAPAction header, in opposite
+                // directions, try the other style once before giving up.
+                let flipped = action.with_flipped_quoting();
+                debug!("action rejected, retrying with SOAPAction {}", flipped.as_str());
+                let retry = gateway.request_parsed(&url, flipped.as_str(), &body, &ok).then(
+                    move |retry| match retry {
+                        Err(ref retry_err) if is_invalid_action(retry_err) => {
+                            quoting.set(action.is_quoted());
+                            Err(err)
+                        }
+                        Err(retry_err) => {
+                            if is_answer(&retry_err) {
+                                quoting.set(flipped.is_quoted());
+                            }
+                            Err(retry_err)
+                        }
+                        Ok(response) => {
+                            quoting.set(flipped.is_quoted());
+                            Ok(response)
+                        }
+                    },
+                );
+                Box::new(retry)
+            },
+        );
+        Box::new(future)
+    }
+
+    // Send a request and parse its response, turning faults into error codes.
+    fn request_parsed(
+        &self,
+        url: &str,
+        header: &str,
+        body: &str,
+        ok: &str,
+    ) -> Box<Future<Item = (String, xmltree::Element), Error = RequestError>> {
         let ok = ok.to_owned();
-        let future = soap::send_async(&url, soap::Action::new(header), body, &self.handle)
-            .map_err(|err| RequestError::from(err))
-            .and_then(move |text| parse_response(text, &ok));
+        let future = self.send_request_at(url, header, body, String::new()).then(move |result| {
+            let result = match result {
+                Ok(text) => parse_response(text, &ok),
+                // Faults are sent with a 500 status, prefer the error code they carry.
+                Err(RequestError::HttpStatus(status, text, headers)) => {
+                    match parse_response(text.clone(), &ok) {
+                        Err(RequestError::ErrorCode(code, description)) => {
+                            Err(RequestError::ErrorCode(code, description))
+                        }
+                        _ => Err(RequestError::HttpStatus(status, text, headers)),
+                    }
+                }
+                Err(e) => Err(e),
+            };
+            result.map_err(|err| {
+                if let RequestError::ErrorCode(code, ref description) = err {
+                    warn!("gateway returned error code {}: {}", code, description);
+                }
+                err
+            })
+        });
+        Box::new(future)
+    }
+
+    // Send a request, retrying on network errors, and return the text of the response. The first
+    // attempt reads the response into the buffer.
+    fn send_request_at(
+        &self,
+        url: &str,
+        header: &str,
+        body: &str,
+        buffer: String,
+    ) -> Box<Future<Item = String, Error = RequestError>> {
+        let url = url.to_owned();
+        let header = header.to_owned();
+        let body = body.to_owned();
+        let timeout = self.inner.request_timeout;
+        let user_agent = self.inner.user_agent.clone();
+        let http_options = self.inner.http_options.clone();
+        let transport = self.inner.transport.clone();
+        let timer = self.timer.clone();
+        let metrics_callback = self.inner.metrics_callback.clone();
+        let action = header.clone();
+        let start = Instant::now();
+        let attempts = Rc::new(Cell::new(0));
+        let counter = attempts.clone();
+        let cancel = self.inner.retry_options.cancel.clone();
+        let cancelled = cancel.clone();
+        let stopped = cancel.clone();
+        let mut buffer = buffer;
+        let future = RetryIf::spawn(
+            self.handle.clone(),
+            self.inner.retry_options.delays(),
+            move || -> Box<Future<Item = String, Error = RequestError>> {
+                if cancel.is_cancelled() {
+                    return Box::new(future::err(RequestError::Cancelled));
+                }
+                debug!("sending {} to {}: {}", header, url, body);
+                counter.set(counter.get() + 1);
+                let buffer = mem::replace(&mut buffer, String::new());
+                let request =
+                    transport.send_into(&url, &header, &body, &user_agent, &http_options, buffer);
+                Box::new(timer.timeout(request, timeout))
+            },
+            move |err: &RequestError| match err {
+                &RequestError::HttpError(_) | &RequestError::IoError(_) => {
+                    if cancelled.is_cancelled() {
+                        return false;
+                    }
+                    warn!("request failed, retrying: {}", err);
+                    true
+                }
+                _ => false,
+            },
+        ).map_err(move |err| match err {
+            // The retries stopped because of the flag rather than because of the error.
+            RetryError::OperationError(RequestError::HttpError(_))
+            | RetryError::OperationError(RequestError::IoError(_))
+                if stopped.is_cancelled() =>
+            {
+                RequestError::Cancelled
+            }
+            RetryError::OperationError(e) => e,
+            RetryError::TimerError(io_error) => RequestError::from(io_error),
+        })
+            .then(move |result| {
+                let response_bytes = match result {
+                    Ok(ref text) | Err(RequestError::HttpStatus(_, ref text, _)) => text.len(),
+                    Err(_) => 0,
+                };
+                metrics_callback.record(&RequestMetrics {
+                    action: action,
+                    duration: start.elapsed(),
+                    attempts: attempts.get(),
+                    response_bytes: response_bytes,
+                });
+                result
+            })
+            .map(|text| {
+                trace!("received response: {}", text);
+                text
+            });
         Box::new(future)
     }
 
+    /// Send an arbitrary action to the connection service of the gateway in a tokio compatible
+    /// way
+    ///
+    /// The body must be a complete SOAP envelope. The text of the response is returned as is so
+    /// that it can be parsed by the caller. Responses with a status other than 2xx, such as SOAP
+    /// faults, are returned as `RequestError::HttpStatus`, which holds their text
+    /// and headers.
+    pub fn send_raw(
+        &self,
+        action: &str,
+        body: &str,
+    ) -> Box<Future<Item = String, Error = RequestError>> {
+        self.send_raw_into(action, body, String::new())
+    }
+
+    /// Same as `send_raw`, reading the response into the given buffer, which is returned holding
+    /// the text of the response.
+    ///
+    /// Passing the buffer returned by the previous call saves an allocation per request, which
+    /// helps applications polling the gateway on a tight schedule.
+    pub fn send_raw_into(
+        &self,
+        action: &str,
+        body: &str,
+        buffer: String,
+    ) -> Box<Future<Item = String, Error = RequestError>> {
+        let header = messages::soap_action(&self.inner.service_type, action);
+        let url = format!("{}", self);
+        self.send_request_at(&url, &header, body, buffer)
+    }
+
+    /// Send the requests to the connection service with the given `serviceId`.
+    ///
+    /// `false` is returned, and the gateway left unchanged, when the gateway has no such service.
+    pub fn select_connection_service(&mut self, service_id: &str) -> bool {
+        self.inner.select_connection_service(service_id)
+    }
+
+    /// Whether the gateway exposes a WANIPConnection or WANPPPConnection service with a control
+    /// url, which is required to add port mappings.
+    pub fn supports_port_mapping(&self) -> bool {
+        self.inner.supports_port_mapping()
+    }
+
+    /// Get the local IP address the system uses to reach the gateway.
+    ///
+    /// No packet is sent, so this does not need to run on the event loop.
+    pub fn get_local_ip(&self) -> Result<Ipv4Addr, RequestError> {
+        self.inner.get_local_ip()
+    }
+
     /// Get the external IP address of the gateway in a tokio compatible way
     pub fn get_external_ip(&self) -> Box<Future<Item = Ipv4Addr, Error = GetExternalIpError>> {
-        let header = "\"urn:schemas-upnp-org:service:WANIPConnection:1#GetExternalIPAddress\"";
-        let body = "<?xml version=\"1.0\"?>
-        <SOAP-ENV:Envelope SOAP-ENV:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\" xmlns:SOAP-ENV=\"http://schemas.xmlsoap.org/soap/envelope/\">
-            <SOAP-ENV:Body>
-                <m:GetExternalIPAddress xmlns:m=\"urn:schemas-upnp-org:service:WANIPConnection:1\">
-                </m:GetExternalIPAddress>
-            </SOAP-ENV:Body>
-        </SOAP-ENV:Envelope>";
-        let future = self.perform_request(header, body, "GetExternalIPAddressResponse")
-            .then(|result| match result {
-                Ok((text, response)) => match response
-                    .get_child("NewExternalIPAddress")
-                    .and_then(|e| e.text.as_ref())
-                    .and_then(|t| t.parse::<Ipv4Addr>().ok())
-                {
-                    Some(ipv4_addr) => Ok(ipv4_addr),
-                    None => Err(GetExternalIpError::RequestError(
-                        RequestError::InvalidResponse(text),
-                    )),
-                },
+        self.get_external_address(false)
+    }
+
+    /// Get the external IP address of the gateway in a tokio compatible way, reusing the last
+    /// address if it was fetched less than `ttl` ago
+    pub fn get_external_ip_cached(
+        &self,
+        ttl: Duration,
+    ) -> Box<Future<Item = Ipv4Addr, Error = GetExternalIpError>> {
+        if let Some(ip) = self.inner.external_ip_cache.get(ttl) {
+            return Box::new(future::ok(ip));
+        }
+        let cache = self.inner.external_ip_cache.clone();
+        let future = self.get_external_ip().map(move |ip| {
+            cache.set(ip);
+            ip
+        });
+        Box::new(future)
+    }
+
+    /// Forget the cached external IP address, so that the next call to `get_external_ip_cached`
+    /// fetches it from the gateway
+    pub fn invalidate_external_ip(&self) {
+        self.inner.external_ip_cache.clear();
+    }
+
+    /// Get the external IP address of the gateway in a tokio compatible way, accepting both IPv4
+    /// and IPv6 addresses
+    pub fn get_external_ip_any(&self) -> Box<Future<Item = IpAddr, Error = GetExternalIpError>> {
+        self.get_external_address(false)
+    }
+
+    /// Get the external IP address of the gateway in a tokio compatible way, returning the
+    /// unspecified address (`0.0.0.0` or `::`) as is
+    ///
+    /// Gateways answer with the unspecified address while their WAN connection is renegotiated,
+    /// which the other functions report as `RequestError::ExternalIpUnavailable`. This is for the
+    /// callers which want to see exactly what the gateway answered.
+    pub fn get_external_ip_raw(&self) -> Box<Future<Item = IpAddr, Error = GetExternalIpError>> {
+        self.get_external_address(true)
+    }
+
+    /// Check in a tokio compatible way whether the mapped external port can be reached from the
+    /// internet.
+    ///
+    /// A successful port mapping does not mean traffic arrives, the gateway may sit behind
+    /// another NAT or a carrier-grade NAT, which is detected from its external IP address. When
+    /// that address is public and the protocol is TCP, a connection to it is attempted within the
+    /// timeout, which only succeeds if something listens on the local side of the mapping and the
+    /// gateway supports hairpinning.
+    pub fn verify_reachable(
+        &self,
+        external_port: u16,
+        protocol: PortMappingProtocol,
+        timeout: Duration,
+    ) -> Box<Future<Item = Reachability, Error = GetExternalIpError>> {
+        let handle = self.handle.clone();
+        let timer = self.timer.clone();
+        let future = self.get_external_ip().and_then(move |ip| -> Box<Future<Item = _, Error = _>> {
+            if let Some(reachability) = external_ip_reachability(ip) {
+                return Box::new(future::ok(reachability));
+            }
+            if protocol == PortMappingProtocol::UDP {
+                return Box::new(future::ok(Reachability::Unverified(ip)));
+            }
+            let addr = SocketAddr::V4(SocketAddrV4::new(ip, external_port));
+            let connect = TcpStream::connect(&addr, &handle).map_err(RequestError::from);
+            let future = timer
+                .timeout(connect, timeout)
+                .then(move |result| match result {
+                    Ok(_) => Ok(Reachability::Reachable),
+                    Err(_) => Ok(Reachability::Unverified(ip)),
+                });
+            Box::new(future)
+        });
+        Box::new(future)
+    }
+
+    fn get_external_address<T>(
+        &self,
+        allow_unspecified: bool,
+    ) -> Box<Future<Item = T, Error = GetExternalIpError>>
+    where
+        T: FromStr + 'static,
+    {
+        let header = messages::get_external_ip_soap_action(&self.inner.service_type);
+        let body = messages::format_get_external_ip_message(&self.inner.service_type);
+        let future = self.perform_request(&header, &body, "GetExternalIPAddressResponse")
+            .then(move |result| match result {
+                Ok((text, response)) => {
+                    external_ip_from_response(text, &response, allow_unspecified)
+                        .map_err(GetExternalIpError::RequestError)
+                }
                 Err(RequestError::ErrorCode(606, _)) => {
                     Err(GetExternalIpError::ActionNotAuthorized)
                 }
@@ -80,6 +449,322 @@ impl Gateway {
         Box::new(future)
     }
 
+    /// Get the status of the WAN connection of the gateway in a tokio compatible way
+    pub fn get_status_info(&self) -> Box<Future<Item = StatusInfo, Error = RequestError>> {
+        let header = messages::get_status_info_soap_action(&self.inner.service_type);
+        let body = messages::format_get_status_info_message(&self.inner.service_type);
+        let future = self.perform_request(&header, &body, "GetStatusInfoResponse")
+            .and_then(|(text, response)| match parse_status_info(&response) {
+                Some(status_info) => Ok(status_info),
+                None => Err(RequestError::InvalidResponse(text)),
+            });
+        Box::new(future)
+    }
+
+    /// Wait for the WAN connection of the gateway to be established in a tokio compatible way
+    ///
+    /// The status of the connection is polled every `CONNECTION_POLL_INTERVAL` until it is
+    /// `Connected`. `RequestError::Timeout` is returned when it is still not connected once the
+    /// timeout expired.
+    pub fn wait_for_connection(
+        &self,
+        timeout: Duration,
+    ) -> Box<Future<Item = (), Error = RequestError>> {
+        let gateway = self.clone();
+        let timer = self.timer.clone();
+        let poll_timer = timer.clone();
+        let poll = future::loop_fn((), move |()| {
+            let timer = poll_timer.clone();
+            gateway.get_status_info().and_then(move |status_info| {
+                if status_info.connection_status == ConnectionStatus::Connected {
+                    return future::Either::A(future::ok(future::Loop::Break(())));
+                }
+                let sleep = timer
+                    .sleep(CONNECTION_POLL_INTERVAL)
+                    .map(|()| future::Loop::Continue(()))
+                    .map_err(|err| RequestError::from(io::Error::from(err)));
+                future::Either::B(sleep)
+            })
+        });
+        Box::new(timer.timeout(poll, timeout))
+    }
+
+    /// Terminate the WAN connection of the gateway in a tokio compatible way
+    ///
+    /// Faults such as 606 (action not authorized) or 720 (cannot process request) are returned
+    /// as `RequestError::ErrorCode`.
+    pub fn force_termination(&self) -> Box<Future<Item = (), Error = RequestError>> {
+        let header = messages::force_termination_soap_action(&self.inner.service_type);
+        let body = messages::format_force_termination_message(&self.inner.service_type);
+        let future = self.perform_request(&header, &body, "ForceTerminationResponse")
+            .map(|_| ());
+        Box::new(future)
+    }
+
+    /// Establish the WAN connection of the gateway in a tokio compatible way
+    ///
+    /// Faults such as 606 (action not authorized) or 720 (cannot process request) are returned
+    /// as `RequestError::ErrorCode`.
+    pub fn request_connection(&self) -> Box<Future<Item = (), Error = RequestError>> {
+        let header = messages::request_connection_soap_action(&self.inner.service_type);
+        let body = messages::format_request_connection_message(&self.inner.service_type);
+        let future = self.perform_request(&header, &body, "RequestConnectionResponse")
+            .map(|_| ());
+        Box::new(future)
+    }
+
+    /// Get the delay between the warning of the users and the termination of the WAN connection
+    /// in a tokio compatible way
+    ///
+    /// Faults such as 606 (action not authorized) are returned as `RequestError::ErrorCode`.
+    pub fn get_warn_disconnect_delay(&self) -> Box<Future<Item = Duration, Error = RequestError>> {
+        self.get_duration("WarnDisconnectDelay")
+    }
+
+    /// Set the delay between the warning of the users and the termination of the WAN connection
+    /// in a tokio compatible way
+    ///
+    /// The delay is sent in whole seconds. Faults such as 606 (action not authorized) are returned
+    /// as `RequestError::ErrorCode`.
+    pub fn set_warn_disconnect_delay(
+        &self,
+        delay: Duration,
+    ) -> Box<Future<Item = (), Error = RequestError>> {
+        self.set_duration("WarnDisconnectDelay", delay)
+    }
+
+    /// Get the idle time after which the gateway terminates the WAN connection in a tokio
+    /// compatible way
+    ///
+    /// Faults such as 606 (action not authorized) are returned as `RequestError::ErrorCode`.
+    pub fn get_auto_disconnect_time(&self) -> Box<Future<Item = Duration, Error = RequestError>> {
+        self.get_duration("AutoDisconnectTime")
+    }
+
+    /// Set the idle time after which the gateway terminates the WAN connection in a tokio
+    /// compatible way
+    ///
+    /// The time is sent in whole seconds. Faults such as 606 (action not authorized) are returned
+    /// as `RequestError::ErrorCode`.
+    pub fn set_auto_disconnect_time(
+        &self,
+        time: Duration,
+    ) -> Box<Future<Item = (), Error = RequestError>> {
+        self.set_duration("AutoDisconnectTime", time)
+    }
+
+    // Get a duration in seconds with the Get<name> action, answered in its New<name> argument.
+    fn get_duration(&self, name: &str) -> Box<Future<Item = Duration, Error = RequestError>> {
+        let action = format!("Get{}", name);
+        let argument = format!("New{}", name);
+        let header = messages::soap_action(&self.inner.service_type, &action);
+        let body = messages::format_get_duration_message(&self.inner.service_type, name);
+        let future = self.perform_request(&header, &body, &format!("{}Response", action))
+            .and_then(move |(text, response)| {
+                match child_text(&response, &argument).and_then(|d| parse_number(&d)) {
+                    Some(secs) => Ok(Duration::from_secs(secs)),
+                    None => Err(RequestError::InvalidResponse(text)),
+                }
+            });
+        Box::new(future)
+    }
+
+    // Set a duration in whole seconds with the Set<name> action and its New<name> argument.
+    fn set_duration(
+        &self,
+        name: &str,
+        duration: Duration,
+    ) -> Box<Future<Item = (), Error = RequestError>> {
+        if duration.as_secs() > u64::from(u32::max_value()) {
+            let message = format!("{} of {}s", name, duration.as_secs());
+            return Box::new(future::err(RequestError::InvalidArgument(message)));
+        }
+        let action = format!("Set{}", name);
+        let header = messages::soap_action(&self.inner.service_type, &action);
+        let body = messages::format_set_duration_message(
+            &self.inner.service_type,
+            name,
+            duration.as_secs() as u32,
+        );
+        let future = self.perform_request(&header, &body, &format!("{}Response", action))
+            .map(|_| ());
+        Box::new(future)
+    }
+
+    /// Get the connection type of the gateway in a tokio compatible way
+    pub fn get_connection_type_info(
+        &self,
+    ) -> Box<Future<Item = ConnectionTypeInfo, Error = RequestError>> {
+        let header = messages::get_connection_type_info_soap_action(&self.inner.service_type);
+        let body = messages::format_get_connection_type_info_message(&self.inner.service_type);
+        let future = self.perform_request(&header, &body, "GetConnectionTypeInfoResponse")
+            .and_then(|(text, response)| match parse_connection_type_info(&response) {
+                Some(info) => Ok(info),
+                None => Err(RequestError::InvalidResponse(text)),
+            });
+        Box::new(future)
+    }
+
+    /// Get whether NAT and RSIP are available on the gateway in a tokio compatible way
+    pub fn get_nat_rsip_status(&self) -> Box<Future<Item = NatRsipStatus, Error = RequestError>> {
+        let header = messages::get_nat_rsip_status_soap_action(&self.inner.service_type);
+        let body = messages::format_get_nat_rsip_status_message(&self.inner.service_type);
+        let future = self.perform_request(&header, &body, "GetNATRSIPStatusResponse")
+            .and_then(|(text, response)| match parse_nat_rsip_status(&response) {
+                Some(status) => Ok(status),
+                None => Err(RequestError::InvalidResponse(text)),
+            });
+        Box::new(future)
+    }
+
+    /// Get the total number of bytes sent on the WAN connection in a tokio compatible way
+    ///
+    /// Many gateways use a 32 bits counter which wraps around at 2^32. The raw value reported by
+    /// the gateway is returned.
+    pub fn get_total_bytes_sent(&self) -> Box<Future<Item = u64, Error = RequestError>> {
+        self.get_counter(
+            &messages::get_total_bytes_sent_soap_action(),
+            &messages::format_get_total_bytes_sent_message(),
+            "GetTotalBytesSentResponse",
+            "NewTotalBytesSent",
+        )
+    }
+
+    /// Get the total number of bytes received on the WAN connection in a tokio compatible way
+    ///
+    /// Many gateways use a 32 bits counter which wraps around at 2^32. The raw value reported by
+    /// the gateway is returned.
+    pub fn get_total_bytes_received(&self) -> Box<Future<Item = u64, Error = RequestError>> {
+        self.get_counter(
+            &messages::get_total_bytes_received_soap_action(),
+            &messages::format_get_total_bytes_received_message(),
+            "GetTotalBytesReceivedResponse",
+            "NewTotalBytesReceived",
+        )
+    }
+
+    /// Get the total number of packets sent on the WAN connection in a tokio compatible way
+    ///
+    /// The counter wraps around at 2^32. The raw value reported by the gateway is returned.
+    pub fn get_total_packets_sent(&self) -> Box<Future<Item = u32, Error = RequestError>> {
+        self.get_counter(
+            &messages::get_total_packets_sent_soap_action(),
+            &messages::format_get_total_packets_sent_message(),
+            "GetTotalPacketsSentResponse",
+            "NewTotalPacketsSent",
+        )
+    }
+
+    /// Get the total number of packets received on the WAN connection in a tokio compatible way
+    ///
+    /// The counter wraps around at 2^32. The raw value reported by the gateway is returned.
+    pub fn get_total_packets_received(&self) -> Box<Future<Item = u32, Error = RequestError>> {
+        self.get_counter(
+            &messages::get_total_packets_received_soap_action(),
+            &messages::format_get_total_packets_received_message(),
+            "GetTotalPacketsReceivedResponse",
+            "NewTotalPacketsReceived",
+        )
+    }
+
+    fn get_counter<T>(
+        &self,
+        header: &str,
+        body: &str,
+        ok: &str,
+        name: &'static str,
+    ) -> Box<Future<Item = T, Error = RequestError>>
+    where
+        T: FromStr + 'static,
+    {
+        let future = self.perform_common_interface_request(header, body, ok)
+            .and_then(move |(text, response)| {
+                match child_text(&response, name).and_then(|t| t.trim().parse::<T>().ok()) {
+                    Some(value) => Ok(value),
+                    None => Err(RequestError::InvalidResponse(text)),
+                }
+            });
+        Box::new(future)
+    }
+
+    /// Open a pinhole in the IPv6 firewall of the gateway in a tokio compatible way
+    ///
+    /// The remote host and port restrict the traffic allowed through the pinhole, `None` and 0
+    /// are wildcards. The lease_time parameter is in seconds. The unique id the gateway assigned
+    /// to the pinhole is returned, it is needed to delete it.
+    ///
+    /// This requires the WANIPv6FirewallControl service, see
+    /// `Gateway::ipv6_firewall_control_url`.
+    pub fn add_pinhole(
+        &self,
+        remote_host: Option<Ipv6Addr>,
+        remote_port: u16,
+        internal_client: Ipv6Addr,
+        internal_port: u16,
+        protocol: PortMappingProtocol,
+        lease_time: u32,
+    ) -> Box<Future<Item = u16, Error = AddPinholeError>> {
+        let future = self.perform_firewall_request(
+            &messages::add_pinhole_soap_action(),
+            &messages::format_add_pinhole_message(
+                remote_host,
+                remote_port,
+                internal_client,
+                internal_port,
+                protocol,
+                lease_time,
+            ),
+            "AddPinholeResponse",
+        ).then(|result| match result {
+            Ok((text, response)) => {
+                match child_text(&response, "UniqueID").and_then(|t| t.trim().parse().ok()) {
+                    Some(unique_id) => Ok(unique_id),
+                    None => Err(AddPinholeError::RequestError(RequestError::InvalidResponse(text))),
+                }
+            }
+            Err(RequestError::ErrorCode(606, _)) => Err(AddPinholeError::ActionNotAuthorized),
+            Err(RequestError::ErrorCode(701, _)) => Err(AddPinholeError::PinholeSpaceExhausted),
+            Err(RequestError::ErrorCode(702, _)) => Err(AddPinholeError::FirewallDisabled),
+            Err(RequestError::ErrorCode(703, _)) => Err(AddPinholeError::InboundPinholeNotAllowed),
+            Err(e) => Err(AddPinholeError::RequestError(e)),
+        });
+        Box::new(future)
+    }
+
+    /// Delete a pinhole opened by `add_pinhole` in a tokio compatible way
+    pub fn delete_pinhole(&self, unique_id: u16) -> Box<Future<Item = (), Error = PinholeError>> {
+        let future = self.perform_firewall_request(
+            &messages::delete_pinhole_soap_action(),
+            &messages::format_delete_pinhole_message(unique_id),
+            "DeletePinholeResponse",
+        ).then(|result| match result {
+            Ok(_) => Ok(()),
+            Err(e) => Err(pinhole_error(e)),
+        });
+        Box::new(future)
+    }
+
+    /// Get the number of packets which went through a pinhole in a tokio compatible way
+    pub fn get_pinhole_packets(
+        &self,
+        unique_id: u16,
+    ) -> Box<Future<Item = u32, Error = PinholeError>> {
+        let future = self.perform_firewall_request(
+            &messages::get_pinhole_packets_soap_action(),
+            &messages::format_get_pinhole_packets_message(unique_id),
+            "GetPinholePacketsResponse",
+        ).then(|result| match result {
+            Ok((text, response)) => {
+                match child_text(&response, "PinholePackets").and_then(|t| t.trim().parse().ok()) {
+                    Some(packets) => Ok(packets),
+                    None => Err(PinholeError::RequestError(RequestError::InvalidResponse(text))),
+                }
+            }
+            Err(e) => Err(pinhole_error(e)),
+        });
+        Box::new(future)
+    }
+
     /// Get an external socket address with our external ip and any port. This is a convenience
     /// function that calls `get_external_ip` followed by `add_any_port`
     ///
@@ -113,6 +798,12 @@ impl Gateway {
 
     /// Add a port mapping.with any external port.
     ///
+    /// When the gateway supports version 2 of WANIPConnection, the gateway picks the port itself.
+    /// Otherwise the external port matching the local port is tried first. If it is already in
+    /// use, random ports of the dynamic range, 49152 to 65535, are tried instead, widening to
+    /// 1024 to 65535 after repeated conflicts. A short randomized delay growing with each
+    /// conflict is waited between the attempts.
+    ///
     /// The local_addr is the address where the traffic is sent to.
     /// The lease_duration parameter is in seconds. A value of 0 is infinite.
     ///
@@ -126,127 +817,189 @@ impl Gateway {
         lease_duration: u32,
         description: &str,
     ) -> Box<Future<Item = u16, Error = AddAnyPortError>> {
-        // This function first attempts to call AddAnyPortMapping on the IGD with a random port
-        // number. If that fails due to the method being unknown it attempts to call AddPortMapping
-        // instead with a random port number. If that fails due to ConflictInMappingEntry it retrys
-        // with another port up to a maximum of 20 times. If it fails due to SamePortValuesRequired
-        // it retrys once with the same port values.
+        let future = self.add_any_port_with_attempts(
+            protocol,
+            local_addr,
+            lease_duration,
+            description,
+        ).map(|(port, _)| port);
+        Box::new(future)
+    }
+
+    /// Add a port mapping with any external port, like `add_any_port`, also returning the number
+    /// of ports tried before one could be mapped.
+    pub fn add_any_port_with_attempts(
+        &self,
+        protocol: PortMappingProtocol,
+        local_addr: SocketAddrV4,
+        lease_duration: u32,
+        description: &str,
+    ) -> Box<Future<Item = (u16, u32), Error = AddAnyPortError>> {
+        // When the IGD exposes WANIPConnection:2, this function first attempts to call
+        // AddAnyPortMapping with the local port number as a hint. If the IGD only exposes version
+        // 1 of the service, or if AddAnyPortMapping fails due to the method being unknown, it
+        // calls AddPortMapping instead with the local port number, then with random port numbers.
+        // If that fails due to ConflictInMappingEntry it retrys with another port up to a maximum
+        // of 20 times, backing off a little more after each conflict. If it fails due to
+        // SamePortValuesRequired it retrys once with the same port values.
 
         if local_addr.port() == 0 {
             return Box::new(future::err(AddAnyPortError::InternalPortZeroInvalid));
         }
 
-        let port_range = rand::distributions::Range::new(32_768_u16, 65_535_u16);
-        let mut rng = rand::thread_rng();
-        let external_port = port_range.ind_sample(&mut rng);
-
-        let header = "\"urn:schemas-upnp-org:service:WANIPConnection:1#AddAnyPortMapping\"";
-        let body = format!(
-            "<?xml version=\"1.0\"?>
-        <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">
-        <s:Body>
-            <u:AddAnyPortMapping xmlns:u=\"urn:schemas-upnp-org:service:WANIPConnection:1\">
-                <NewProtocol>{}</NewProtocol>
-                <NewExternalPort>{}</NewExternalPort>
-                <NewInternalClient>{}</NewInternalClient>
-                <NewInternalPort>{}</NewInternalPort>
-                <NewLeaseDuration>{}</NewLeaseDuration>
-                <NewPortMappingDescription>{}</NewPortMappingDescription>
-                <NewEnabled>1</NewEnabled>
-                <NewRemoteHost></NewRemoteHost>
-            </u:AddAnyPortMapping>
-        </s:Body>
-        </s:Envelope>
-        ",
-            protocol,
-            external_port,
-            local_addr.ip(),
-            local_addr.port(),
-            lease_duration,
-            description
-        );
+        if self.inner.service_type != messages::WAN_IP_CONNECTION_2 {
+            // AddAnyPortMapping was introduced in version 2 of the service.
+            return self.retry_add_random_port_mapping(
+                protocol,
+                local_addr,
+                lease_duration,
+                description,
+            );
+        }
+
         let gateway = self.clone();
         let description = description.to_owned();
         // First, attempt to call the AddAnyPortMapping method.
-        let future = self.perform_request(header, &*body, "AddAnyPortMappingResponse")
-            .and_then(|(text, response)| {
-                match response
-                    .get_child("NewReservedPort")
-                    .and_then(|e| e.text.as_ref())
-                    .and_then(|t| t.parse::<u16>().ok())
-                {
-                    Some(port) => Ok(port),
-                    None => Err(RequestError::InvalidResponse(text)),
-                }
-            })
-            .or_else(move |err| {
-                match err {
-                    // The router doesn't know the AddAnyPortMapping method. Try using AddPortMapping
-                    // instead.
-                    RequestError::ErrorCode(401, _) => {
-                        // Try a bunch of random ports.
-                        gateway.retry_add_random_port_mapping(
-                            protocol,
+        let future = self.add_any_port_mapping(
+            protocol,
+            local_addr.port(),
+            local_addr,
+            lease_duration,
+            &description,
+        ).map(|port| (port, 1))
+            .or_else(move |err| match err {
+                // The router doesn't know the AddAnyPortMapping method. Try using AddPortMapping
+                // instead.
+                AddAnyPortError::RequestError(RequestError::ErrorCode(401, _)) => {
+                    // Try a bunch of random ports.
+                    let future = gateway
+                        .retry_add_random_port_mapping(
+                            protocol,
                             local_addr,
                             lease_duration,
                             &description,
                         )
-                    }
-                    e => {
-                        let err = match e {
-                            RequestError::ErrorCode(605, _) => AddAnyPortError::DescriptionTooLong,
-                            RequestError::ErrorCode(606, _) => AddAnyPortError::ActionNotAuthorized,
-                            RequestError::ErrorCode(728, _) => AddAnyPortError::NoPortsAvailable,
-                            e => AddAnyPortError::RequestError(e),
-                        };
-                        Box::new(future::err(err))
-                    }
+                        .map(|(port, attempts)| (port, attempts + 1));
+                    future::Either::A(future)
                 }
+                e => future::Either::B(future::err(e)),
             });
         Box::new(future)
     }
 
-    fn retry_add_random_port_mapping(
+    /// Add a port mapping with the AddAnyPortMapping action, letting the gateway pick the
+    /// external port.
+    ///
+    /// The external_port is only a hint, the gateway returns the port it actually reserved. The
+    /// action only exists in version 2 of WANIPConnection, `UnsupportedAction` is returned by
+    /// older gateways. Use `add_any_port` to fall back to AddPortMapping transparently.
+    ///
+    /// The local_addr is the address where the traffic is sent to.
+    /// The lease_duration parameter is in seconds. A value of 0 is infinite.
+    pub fn add_any_port_mapping(
         &self,
         protocol: PortMappingProtocol,
+        external_port: u16,
         local_addr: SocketAddrV4,
         lease_duration: u32,
         description: &str,
     ) -> Box<Future<Item = u16, Error = AddAnyPortError>> {
+        if local_addr.port() == 0 {
+            return Box::new(future::err(AddAnyPortError::InternalPortZeroInvalid));
+        }
+        if self.inner.service_type != messages::WAN_IP_CONNECTION_2 {
+            return Box::new(future::err(AddAnyPortError::RequestError(
+                RequestError::UnsupportedAction(
+                    "AddAnyPortMapping requires WANIPConnection:2, use add_any_port instead"
+                        .to_string(),
+                ),
+            )));
+        }
+
+        let header = messages::add_any_port_soap_action(&self.inner.service_type);
+        let body = messages::format_add_any_port_mapping_message(
+            &self.inner.service_type,
+            &new_entry(
+                protocol,
+                None,
+                external_port,
+                local_addr,
+                lease_duration,
+                description,
+            ),
+        );
+        let future = self.perform_request(&header, &body, "AddAnyPortMappingResponse")
+            .and_then(|(text, response)| {
+                match child_text(&response, "NewReservedPort").and_then(|t| parse_number(&t)) {
+                    Some(port) => Ok(port),
+                    None => Err(RequestError::InvalidResponse(text)),
+                }
+            })
+            .map_err(|err| match err {
+                RequestError::ErrorCode(605, _) => AddAnyPortError::DescriptionTooLong,
+                RequestError::ErrorCode(606, _) => AddAnyPortError::ActionNotAuthorized,
+                RequestError::ErrorCode(728, _) => AddAnyPortError::NoPortsAvailable,
+                e => AddAnyPortError::RequestError(e),
+            });
+        Box::new(future)
+    }
+
+    fn retry_add_random_port_mapping(
+        &self,
+        protocol: PortMappingProtocol,
+        local_addr: SocketAddrV4,
+        lease_duration: u32,
+        description: &str,
+    ) -> Box<Future<Item = (u16, u32), Error = AddAnyPortError>> {
         let description = description.to_owned();
         let gateway = self.clone();
-        let retry_strategy = FixedInterval::from_millis(0).take(20);
+        let attempts = Rc::new(Cell::new(0));
+        let counter = attempts.clone();
+        // Try the local port first, then random ports.
         let future = RetryIf::spawn(
             gateway.handle.clone(),
-            retry_strategy,
+            random_port_delays(),
             move || {
-                gateway.add_random_port_mapping(protocol, local_addr, lease_duration, &description)
+                counter.set(counter.get() + 1);
+                let external_port = match counter.get() {
+                    1 => local_addr.port(),
+                    n => random_port(n),
+                };
+                gateway.try_add_port_mapping(
+                    protocol,
+                    external_port,
+                    local_addr,
+                    lease_duration,
+                    &description,
+                )
             },
             |err: &AddAnyPortError| match err {
                 &AddAnyPortError::NoPortsAvailable => true,
                 _ => false,
             },
-        ).map_err(|err| match err {
-            RetryError::OperationError(e) => e,
-            RetryError::TimerError(io_error) => AddAnyPortError::from(RequestError::from(io_error)),
-        });
+        ).map(move |port| (port, attempts.get()))
+            .map_err(|err| match err {
+                RetryError::OperationError(e) => e,
+                RetryError::TimerError(io_error) => {
+                    AddAnyPortError::from(RequestError::from(io_error))
+                }
+            });
         Box::new(future)
     }
 
-    fn add_random_port_mapping(
+    fn try_add_port_mapping(
         &self,
         protocol: PortMappingProtocol,
+        external_port: u16,
         local_addr: SocketAddrV4,
         lease_duration: u32,
         description: &str,
     ) -> Box<Future<Item = u16, Error = AddAnyPortError>> {
         let description = description.to_owned();
         let gateway = self.clone();
-        let port_range = rand::distributions::Range::new(32_768_u16, 65_535_u16);
-        let mut rng = rand::thread_rng();
-        let external_port = port_range.ind_sample(&mut rng);
-        let future = self.add_port_mapping(
+        let future = self.request_port_mapping(
             protocol,
+            None,
             external_port,
             local_addr,
             lease_duration,
@@ -278,8 +1031,9 @@ impl Gateway {
         lease_duration: u32,
         description: &str,
     ) -> Box<Future<Item = u16, Error = AddAnyPortError>> {
-        let future = self.add_port_mapping(
+        let future = self.request_port_mapping(
             protocol,
+            None,
             local_addr.port(),
             local_addr,
             lease_duration,
@@ -296,48 +1050,42 @@ impl Gateway {
         Box::new(future)
     }
 
-    fn add_port_mapping(
+    fn request_port_mapping(
         &self,
         protocol: PortMappingProtocol,
+        remote_host: Option<Ipv4Addr>,
         external_port: u16,
         local_addr: SocketAddrV4,
         lease_duration: u32,
         description: &str,
     ) -> Box<Future<Item = (), Error = RequestError>> {
-        let header = "\"urn:schemas-upnp-org:service:WANIPConnection:1#AddPortMapping\"";
-        let body = format!(
-            "<?xml version=\"1.0\"?>
-        <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">
-        <s:Body>
-            <u:AddPortMapping xmlns:u=\"urn:schemas-upnp-org:service:WANIPConnection:1\">
-                <NewProtocol>{}</NewProtocol>
-                <NewExternalPort>{}</NewExternalPort>
-                <NewInternalClient>{}</NewInternalClient>
-                <NewInternalPort>{}</NewInternalPort>
-                <NewLeaseDuration>{}</NewLeaseDuration>
-                <NewPortMappingDescription>{}</NewPortMappingDescription>
-                <NewEnabled>1</NewEnabled>
-                <NewRemoteHost></NewRemoteHost>
-            </u:AddPortMapping>
-        </s:Body>
-        </s:Envelope>
-        ",
+        self.add_entry_mapping(&new_entry(
             protocol,
+            remote_host,
             external_port,
-            local_addr.ip(),
-            local_addr.port(),
+            local_addr,
             lease_duration,
-            description
-        );
-        let future = self.perform_request(header, &*body, "AddPortMappingResponse")
+            description,
+        ))
+    }
+
+    fn add_entry_mapping(
+        &self,
+        entry: &PortMappingEntry,
+    ) -> Box<Future<Item = (), Error = RequestError>> {
+        let header = messages::add_port_soap_action(&self.inner.service_type);
+        let body = messages::format_add_port_mapping_message(&self.inner.service_type, entry);
+        let future = self.perform_request(&header, &body, "AddPortMappingResponse")
             .map(|_| ());
         Box::new(future)
     }
 
     /// Add a port mapping.
     ///
-    /// The local_addr is the address where the traffic is sent to.
+    /// The local_addr is the address where the traffic is sent to, its port is the internal port
+    /// of the port mapping. See `add_port_mapping` to name both ports separately.
     /// The lease_duration parameter is in seconds. A value of 0 is infinite.
+    /// The port mapping added is returned, for instance to renew or remove it later.
     pub fn add_port(
         &self,
         protocol: PortMappingProtocol,
@@ -345,20 +1093,128 @@ impl Gateway {
         local_addr: SocketAddrV4,
         lease_duration: u32,
         description: &str,
+    ) -> Box<Future<Item = PortMappingEntry, Error = AddPortError>> {
+        self.add_port_mapping(
+            protocol,
+            external_port,
+            *local_addr.ip(),
+            local_addr.port(),
+            lease_duration,
+            description,
+        )
+    }
+
+    /// Add a port mapping, naming the external and the internal port separately.
+    ///
+    /// The traffic received on the external port is sent to the internal port of the
+    /// internal_client. The lease_duration parameter is in seconds. A value of 0 is infinite.
+    /// The port mapping added is returned.
+    pub fn add_port_mapping(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        internal_client: Ipv4Addr,
+        internal_port: u16,
+        lease_duration: u32,
+        description: &str,
+    ) -> Box<Future<Item = PortMappingEntry, Error = AddPortError>> {
+        self.add_port_with_remote_host(
+            protocol,
+            None,
+            external_port,
+            SocketAddrV4::new(internal_client, internal_port),
+            lease_duration,
+            description,
+        )
+    }
+
+    /// Add a port mapping to the given port of this host in a tokio compatible way.
+    ///
+    /// The traffic is sent to the local IP address the system uses to reach the gateway, see
+    /// `get_local_ip`, and the same port is used externally. The local address mapped is returned.
+    /// The lease_duration parameter is in seconds. A value of 0 is infinite.
+    pub fn add_port_for_local(
+        &self,
+        protocol: PortMappingProtocol,
+        local_port: u16,
+        lease_duration: u32,
+        description: &str,
+    ) -> Box<Future<Item = SocketAddrV4, Error = AddPortError>> {
+        let local_addr = match self.get_local_ip() {
+            Ok(local_ip) => SocketAddrV4::new(local_ip, local_port),
+            Err(err) => return Box::new(future::err(AddPortError::RequestError(err))),
+        };
+        Box::new(
+            self.add_port(protocol, local_port, local_addr, lease_duration, description)
+                .map(move |_| local_addr),
+        )
+    }
+
+    /// Add a port mapping which only accepts traffic from the given remote host.
+    ///
+    /// A remote_host of `None` accepts traffic from any host, like `add_port`.
+    /// The local_addr is the address where the traffic is sent to.
+    /// The lease_duration parameter is in seconds. A value of 0 is infinite.
+    /// The port mapping added is returned.
+    pub fn add_port_with_remote_host(
+        &self,
+        protocol: PortMappingProtocol,
+        remote_host: Option<Ipv4Addr>,
+        external_port: u16,
+        local_addr: SocketAddrV4,
+        lease_duration: u32,
+        description: &str,
+    ) -> Box<Future<Item = PortMappingEntry, Error = AddPortError>> {
+        let entry = new_entry(
+            protocol,
+            remote_host,
+            external_port,
+            local_addr,
+            lease_duration,
+            description,
+        );
+        Box::new(self.add_entry(&entry).map(move |()| entry))
+    }
+
+    /// Renew an existing port mapping.
+    ///
+    /// The port mapping is added again with the given lease duration, which restarts its lease.
+    /// The parameters must match the ones the port mapping was added with. To renew a port
+    /// mapping from a `PortMappingEntry`, use `add_entry`.
+    pub fn renew_port(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        local_addr: SocketAddrV4,
+        lease_duration: u32,
+        description: &str,
     ) -> Box<Future<Item = (), Error = AddPortError>> {
-        if external_port == 0 {
-            return Box::new(future::err(AddPortError::ExternalPortZeroInvalid));
-        }
-        if local_addr.port() == 0 {
-            return Box::new(future::err(AddPortError::InternalPortZeroInvalid));
-        }
-        let future = self.add_port_mapping(
+        let future = self.add_port(
             protocol,
             external_port,
             local_addr,
             lease_duration,
             description,
-        ).map_err(|err| match err {
+        );
+        Box::new(future.map(|_| ()))
+    }
+
+    /// Add a port mapping described by the given entry.
+    ///
+    /// This gives full control over the port mapping, for instance to add a disabled port
+    /// mapping. Adding an entry for an existing external port and protocol updates the existing
+    /// port mapping.
+    pub fn add_entry(
+        &self,
+        entry: &PortMappingEntry,
+    ) -> Box<Future<Item = (), Error = AddPortError>> {
+        if entry.external_port == 0 {
+            return Box::new(future::err(AddPortError::ExternalPortZeroInvalid));
+        }
+        if entry.internal_port == 0 {
+            return Box::new(future::err(AddPortError::InternalPortZeroInvalid));
+        }
+        let future = self.add_entry_mapping(entry).map_err(|err| match err {
             RequestError::ErrorCode(605, _) => AddPortError::DescriptionTooLong,
             RequestError::ErrorCode(606, _) => AddPortError::ActionNotAuthorized,
             RequestError::ErrorCode(718, _) => AddPortError::PortInUse,
@@ -369,30 +1225,186 @@ impl Gateway {
         Box::new(future)
     }
 
+    /// Add several port mappings, removing the ones already added when one of them fails.
+    ///
+    /// The port mappings are added in order. On the first failure, the port mappings added
+    /// before are removed on a best effort basis and the error is returned. The added port
+    /// mappings are returned on success.
+    pub fn add_ports(
+        &self,
+        entries: &[PortMappingEntry],
+    ) -> Box<Future<Item = Vec<PortMappingEntry>, Error = AddPortError>> {
+        let gateway = self.clone();
+        let future = stream::iter_ok::<_, AddPortError>(entries.to_vec()).fold(
+            Vec::new(),
+            move |mut added, entry| {
+                let gateway = gateway.clone();
+                gateway.add_entry(&entry).then(move |result| match result {
+                    Ok(()) => {
+                        added.push(entry);
+                        future::Either::A(future::ok(added))
+                    }
+                    Err(err) => future::Either::B(gateway.remove_entries(added).then(|_| Err(err))),
+                })
+            },
+        );
+        Box::new(future)
+    }
+
+    /// Add the same port mapping for both TCP and UDP.
+    ///
+    /// The parameters are the same as for `add_port`. The TCP port mapping is added first, if
+    /// the UDP one then fails the TCP one is removed on a best effort basis and the error is
+    /// returned. The TCP and the UDP port mappings added are returned on success.
+    pub fn add_port_both_protocols(
+        &self,
+        external_port: u16,
+        local_addr: SocketAddrV4,
+        lease_duration: u32,
+        description: &str,
+    ) -> Box<Future<Item = (PortMappingEntry, PortMappingEntry), Error = AddPortError>> {
+        let gateway = self.clone();
+        let description = description.to_string();
+        let future = self.add_port(
+            PortMappingProtocol::TCP,
+            external_port,
+            local_addr,
+            lease_duration,
+            &description,
+        ).and_then(move |tcp| {
+            gateway
+                .add_port(
+                    PortMappingProtocol::UDP,
+                    external_port,
+                    local_addr,
+                    lease_duration,
+                    &description,
+                )
+                .then(move |result| match result {
+                    Ok(udp) => future::Either::A(future::ok((tcp, udp))),
+                    Err(err) => {
+                        future::Either::B(gateway.remove_entries(vec![tcp]).then(|_| Err(err)))
+                    }
+                })
+        });
+        Box::new(future)
+    }
+
+    // Remove the given port mappings, logging failures.
+    fn remove_entries(
+        &self,
+        entries: Vec<PortMappingEntry>,
+    ) -> Box<Future<Item = (), Error = ()>> {
+        let gateway = self.clone();
+        let future = stream::iter_ok(entries).for_each(move |entry| {
+            gateway.remove_entry(&entry).then(move |result| {
+                if let Err(err) = result {
+                    warn!(
+                        "failed to remove port mapping {} {}: {}",
+                        entry.protocol,
+                        entry.external_port,
+                        err
+                    );
+                }
+                Ok(())
+            })
+        });
+        Box::new(future)
+    }
+
+    /// Enable or disable an existing port mapping.
+    ///
+    /// The port mapping is updated in place with the same parameters, it is not removed.
+    pub fn set_port_mapping_enabled(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        enabled: bool,
+    ) -> Box<Future<Item = (), Error = SetPortMappingEnabledError>> {
+        let gateway = self.clone();
+        let future = self.get_specific_port_mapping_entry(protocol, external_port)
+            .map_err(|err| match err {
+                GetSpecificPortMappingEntryError::ActionNotAuthorized => {
+                    SetPortMappingEnabledError::ActionNotAuthorized
+                }
+                GetSpecificPortMappingEntryError::RequestError(e) => {
+                    SetPortMappingEnabledError::RequestError(e)
+                }
+            })
+            .and_then(|entry| entry.ok_or(SetPortMappingEnabledError::NoSuchPortMapping))
+            .and_then(move |mut entry| {
+                entry.enabled = enabled;
+                gateway.add_entry(&entry).map_err(|err| match err {
+                    AddPortError::ActionNotAuthorized => {
+                        SetPortMappingEnabledError::ActionNotAuthorized
+                    }
+                    e => SetPortMappingEnabledError::AddPortError(e),
+                })
+            });
+        Box::new(future)
+    }
+
     /// Remove a port mapping.
     pub fn remove_port(
         &self,
         protocol: PortMappingProtocol,
         external_port: u16,
     ) -> Box<Future<Item = (), Error = RemovePortError>> {
-        let header = "\"urn:schemas-upnp-org:service:WANIPConnection:1#DeletePortMapping\"";
-        let body = format!(
-            "<?xml version=\"1.0\"?>
-        <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">
-        <s:Body>
-            <u:DeletePortMapping xmlns:u=\"urn:schemas-upnp-org:service:WANIPConnection:1\">
-                <NewProtocol>{}</NewProtocol>
-                <NewExternalPort>{}</NewExternalPort>
-                <NewRemoteHost></NewRemoteHost>
-            </u:DeletePortMapping>
-        </s:Body>
-        </s:Envelope>
-        ",
+        self.remove_port_with_remote_host(protocol, None, external_port)
+    }
+
+    /// Remove a port mapping if it exists in a tokio compatible way.
+    ///
+    /// `false` is returned when the gateway has no such port mapping instead of failing with
+    /// `NoSuchPortMapping`, which makes cleanup code idempotent.
+    pub fn remove_port_if_exists(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+    ) -> Box<Future<Item = bool, Error = RemovePortError>> {
+        let future = self.remove_port(protocol, external_port)
+            .then(|result| match result {
+                Ok(()) => Ok(true),
+                Err(RemovePortError::NoSuchPortMapping) => Ok(false),
+                Err(e) => Err(e),
+            });
+        Box::new(future)
+    }
+
+    /// Remove a port mapping which was added for the given remote host.
+    ///
+    /// The remote_host must match the one the port mapping was added with.
+    pub fn remove_port_with_remote_host(
+        &self,
+        protocol: PortMappingProtocol,
+        remote_host: Option<Ipv4Addr>,
+        external_port: u16,
+    ) -> Box<Future<Item = (), Error = RemovePortError>> {
+        let remote_host = messages::format_remote_host(remote_host);
+        self.delete_port_mapping(protocol, &remote_host, external_port)
+    }
+
+    // Send a DeletePortMapping request, with the remote host as formatted in the messages.
+    fn delete_port_mapping(
+        &self,
+        protocol: PortMappingProtocol,
+        remote_host: &str,
+        external_port: u16,
+    ) -> Box<Future<Item = (), Error = RemovePortError>> {
+        if external_port == 0 {
+            return Box::new(future::err(RemovePortError::RequestError(
+                RequestError::InvalidArgument("The external port can not be 0".to_string()),
+            )));
+        }
+        let header = messages::delete_port_soap_action(&self.inner.service_type);
+        let body = messages::format_delete_port_message(
+            &self.inner.service_type,
             protocol,
-            external_port
+            remote_host,
+            external_port,
         );
 
-        let future = self.perform_request(header, &*body, "DeletePortMappingResponse")
+        let future = self.perform_request(&header, &body, "DeletePortMappingResponse")
             .map(|_| ())
             .map_err(|err| match err {
                 RequestError::ErrorCode(606, _) => RemovePortError::ActionNotAuthorized,
@@ -401,17 +1413,197 @@ impl Gateway {
             });
         Box::new(future)
     }
+
+    /// Remove the port mapping described by the given entry, such as one returned by
+    /// `port_mappings`.
+    ///
+    /// The remote host of the entry is used, so that port mappings added for a specific remote
+    /// host are removed as well. The remote host is sent as listed by the gateway, even when it is
+    /// not an IPv4 address, so only an empty remote host is the wildcard.
+    pub fn remove_entry(
+        &self,
+        entry: &PortMappingEntry,
+    ) -> Box<Future<Item = (), Error = RemovePortError>> {
+        self.delete_port_mapping(entry.protocol, entry.remote_host.trim(), entry.external_port)
+    }
+
+    /// Remove all the port mappings whose description starts with the given prefix.
+    ///
+    /// The port mappings are all listed before any of them is removed, so that the removals do
+    /// not shift the indices of the remaining ones. Port mappings which disappear in the meantime
+    /// are skipped. The protocol and external port of the removed port mappings are returned.
+    pub fn remove_ports_by_description(
+        &self,
+        prefix: &str,
+    ) -> Box<Future<Item = Vec<(PortMappingProtocol, u16)>, Error = RemovePortsError>> {
+        let prefix = prefix.to_owned();
+        let gateway = self.clone();
+        let future = self.port_mappings()
+            .filter(move |entry| entry.port_mapping_description.starts_with(&prefix))
+            .collect()
+            .map_err(|err| RemovePortsError::GetGenericPortMappingEntryError(err))
+            .and_then(move |entries| {
+                stream::iter_ok(entries)
+                    .and_then(move |entry| {
+                        let protocol = entry.protocol;
+                        let external_port = entry.external_port;
+                        gateway
+                            .remove_entry(&entry)
+                            .then(move |result| match result {
+                                Ok(()) => Ok(Some((protocol, external_port))),
+                                Err(RemovePortError::NoSuchPortMapping) => Ok(None),
+                                Err(e) => Err(RemovePortsError::RemovePortError(e)),
+                            })
+                    })
+                    .filter_map(|removed| removed)
+                    .collect()
+            });
+        Box::new(future)
+    }
+
+    /// Get one of the existing port mappings by its index.
+    ///
+    /// Port mappings are indexed from 0. `SpecifiedArrayIndexInvalid` is returned once the index
+    /// goes past the last port mapping.
+    pub fn get_generic_port_mapping_entry(
+        &self,
+        index: u32,
+    ) -> Box<Future<Item = PortMappingEntry, Error = GetGenericPortMappingEntryError>> {
+        let header = messages::get_generic_port_mapping_entry_soap_action(&self.inner.service_type);
+        let body = messages::format_get_generic_port_mapping_entry_message(
+            &self.inner.service_type,
+            index,
+        );
+        let future = self.perform_request(&header, &body, "GetGenericPortMappingEntryResponse")
+            .then(|result| match result {
+                Ok((text, response)) => match parse_port_mapping_entry(&response) {
+                    Some(entry) => Ok(entry),
+                    None => Err(GetGenericPortMappingEntryError::RequestError(
+                        RequestError::InvalidResponse(text),
+                    )),
+                },
+                Err(RequestError::ErrorCode(606, _)) => {
+                    Err(GetGenericPortMappingEntryError::ActionNotAuthorized)
+                }
+                Err(RequestError::ErrorCode(713, _)) => {
+                    Err(GetGenericPortMappingEntryError::SpecifiedArrayIndexInvalid)
+                }
+                Err(e) => Err(GetGenericPortMappingEntryError::RequestError(e)),
+            });
+        Box::new(future)
+    }
+
+    /// Get the port mapping of the given external port, if any.
+    ///
+    /// `None` is returned when the gateway has no such port mapping.
+    pub fn get_specific_port_mapping_entry(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+    ) -> Box<Future<Item = Option<PortMappingEntry>, Error = GetSpecificPortMappingEntryError>> {
+        self.get_specific_port_mapping_entry_with_remote_host(protocol, None, external_port)
+    }
+
+    /// Get the port mapping of the given external port which was added for the given remote
+    /// host, if any.
+    ///
+    /// The remote_host must match the one the port mapping was added with.
+    pub fn get_specific_port_mapping_entry_with_remote_host(
+        &self,
+        protocol: PortMappingProtocol,
+        remote_host: Option<Ipv4Addr>,
+        external_port: u16,
+    ) -> Box<Future<Item = Option<PortMappingEntry>, Error = GetSpecificPortMappingEntryError>> {
+        let remote_host = messages::format_remote_host(remote_host);
+        let header =
+            messages::get_specific_port_mapping_entry_soap_action(&self.inner.service_type);
+        let body = messages::format_get_specific_port_mapping_entry_message(
+            &self.inner.service_type,
+            protocol,
+            &remote_host,
+            external_port,
+        );
+        let future = self.perform_request(&header, &body, "GetSpecificPortMappingEntryResponse")
+            .then(move |result| match result {
+                Ok((text, response)) => match parse_port_mapping_fields(
+                    &response,
+                    remote_host,
+                    external_port,
+                    protocol,
+                ) {
+                    Some(entry) => Ok(Some(entry)),
+                    None => Err(GetSpecificPortMappingEntryError::RequestError(
+                        RequestError::InvalidResponse(text),
+                    )),
+                },
+                Err(RequestError::ErrorCode(606, _)) => {
+                    Err(GetSpecificPortMappingEntryError::ActionNotAuthorized)
+                }
+                Err(RequestError::ErrorCode(714, _)) => Ok(None),
+                Err(e) => Err(GetSpecificPortMappingEntryError::RequestError(e)),
+            });
+        Box::new(future)
+    }
+
+    /// Get the lease duration left for the port mapping of the given external port, if any.
+    ///
+    /// Most gateways count the lease down and report the time left, but some report the lease
+    /// duration the port mapping was added with. A duration of 0 is a permanent lease. `None` is
+    /// returned when the gateway has no such port mapping.
+    pub fn get_remaining_lease(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+    ) -> Box<Future<Item = Option<Duration>, Error = GetSpecificPortMappingEntryError>> {
+        let future = self.get_specific_port_mapping_entry(protocol, external_port)
+            .map(|entry| entry.map(|entry| Duration::from_secs(entry.lease_duration as u64)));
+        Box::new(future)
+    }
+
+    /// Get all the existing port mappings.
+    ///
+    /// The port mappings are requested one by one, starting from index 0, until the gateway
+    /// reports that the index is past the last port mapping.
+    pub fn port_mappings(
+        &self,
+    ) -> Box<Stream<Item = PortMappingEntry, Error = GetGenericPortMappingEntryError>> {
+        let gateway = self.clone();
+        let stream = stream::unfold(Some(0), move |index| {
+            index.map(|index| {
+                gateway
+                    .get_generic_port_mapping_entry(index)
+                    .then(move |result| match result {
+                        Ok(entry) => Ok((Some(entry), Some(index + 1))),
+                        Err(GetGenericPortMappingEntryError::SpecifiedArrayIndexInvalid) => {
+                            Ok((None, None))
+                        }
+                        Err(e) => Err(e),
+                    })
+            })
+        }).filter_map(|entry| entry);
+        Box::new(stream)
+    }
+
+    /// Count the existing port mappings in a tokio compatible way.
+    ///
+    /// The port mappings are requested one by one like with `port_mappings`, but only their
+    /// number is kept.
+    pub fn count_port_mappings(
+        &self,
+    ) -> Box<Future<Item = u32, Error = GetGenericPortMappingEntryError>> {
+        Box::new(self.port_mappings().fold(0, |count, _| Ok(count + 1)))
+    }
 }
 
 impl fmt::Display for Gateway {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "http://{}{}", self.addr, self.control_url)
+        write!(f, "{}", self.inner)
     }
 }
 
 impl PartialEq for Gateway {
     fn eq(&self, other: &Gateway) -> bool {
-        self.addr == other.addr && self.control_url == other.control_url
+        self.inner == other.inner
     }
 }
 
@@ -419,13 +1611,250 @@ impl Eq for Gateway {}
 
 impl Hash for Gateway {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        self.addr.hash(state);
-        self.control_url.hash(state);
+        self.inner.hash(state);
     }
 }
 
+// Gateways whose WAN connection is down may answer with an empty or unspecified address.
+fn is_external_ip_unavailable(response: &xmltree::Element, allow_unspecified: bool) -> bool {
+    match child_text(response, "NewExternalIPAddress") {
+        Some(text) => {
+            let text = text.trim();
+            let unspecified = parse_canonical_ip(text).map(|ip| ip.is_unspecified());
+            text.is_empty() || (!allow_unspecified && unspecified.unwrap_or(false))
+        }
+        None => false,
+    }
+}
+
+// The address is canonicalized first, so that an IPv4-mapped address is returned as an IPv4
+// address, whatever the type asked for.
+fn parse_external_ip_address<T: FromStr>(response: &xmltree::Element) -> Option<T> {
+    let ip = parse_canonical_ip(&child_text(response, "NewExternalIPAddress")?)?;
+    ip.to_string().parse().ok()
+}
+
+// Parse an IP address, unwrapping the IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`) some
+// gateways report.
+fn parse_canonical_ip(text: &str) -> Option<IpAddr> {
+    match text.trim().parse().ok()? {
+        IpAddr::V6(ip) => match ip.octets() {
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => {
+                Some(IpAddr::V4(Ipv4Addr::new(a, b, c, d)))
+            }
+            _ => Some(IpAddr::V6(ip)),
+        },
+        ip => Some(ip),
+    }
+}
+
+fn external_ip_from_response<T: FromStr>(
+    text: String,
+    response: &xmltree::Element,
+    allow_unspecified: bool,
+) -> Result<T, RequestError> {
+    if is_external_ip_unavailable(response, allow_unspecified) {
+        return Err(RequestError::ExternalIpUnavailable);
+    }
+    parse_external_ip_address(response).ok_or(RequestError::InvalidResponse(text))
+}
+
+/// Parse the external IP address from the text of a `GetExternalIPAddress` response.
+///
+/// This is how `Gateway::get_external_ip` reads the responses of the gateway. The error of the
+/// gateway is returned if the response is a fault, and `ExternalIpUnavailable` if the gateway has
+/// no external IP address yet. IPv4-mapped addresses such as `::ffff:203.0.113.5` are returned as
+/// IPv4 addresses.
+pub fn parse_external_ip(text: &str) -> Result<IpAddr, RequestError> {
+    let (text, response) = try!(parse_response(text.to_string(), "GetExternalIPAddressResponse"));
+    external_ip_from_response(text, &response, false)
+}
+
+fn parse_connection_type_info(response: &xmltree::Element) -> Option<ConnectionTypeInfo> {
+    let connection_type = child_text(response, "NewConnectionType")?.trim().to_string();
+    // The possible connection types are a comma separated list.
+    let possible_connection_types = child_text(response, "NewPossibleConnectionTypes")?
+        .split(',')
+        .map(|connection_type| connection_type.trim().to_string())
+        .filter(|connection_type| !connection_type.is_empty())
+        .collect();
+    Some(ConnectionTypeInfo {
+        connection_type: connection_type,
+        possible_connection_types: possible_connection_types,
+    })
+}
+
+fn parse_nat_rsip_status(response: &xmltree::Element) -> Option<NatRsipStatus> {
+    Some(NatRsipStatus {
+        rsip_available: parse_bool(&child_text(response, "NewRSIPAvailable")?)?,
+        nat_enabled: parse_bool(&child_text(response, "NewNATEnabled")?)?,
+    })
+}
+
+// Parse a UPnP boolean, which is most commonly sent as 0 or 1.
+fn parse_bool(text: &str) -> Option<bool> {
+    match text.trim() {
+        "1" | "true" | "yes" => Some(true),
+        "0" | "false" | "no" => Some(false),
+        _ => None,
+    }
+}
+
+fn parse_status_info(response: &xmltree::Element) -> Option<StatusInfo> {
+    let connection_status = match child_text(response, "NewConnectionStatus")?.trim() {
+        "Unconfigured" => ConnectionStatus::Unconfigured,
+        "Connecting" => ConnectionStatus::Connecting,
+        "Connected" => ConnectionStatus::Connected,
+        "PendingDisconnect" => ConnectionStatus::PendingDisconnect,
+        "Disconnecting" => ConnectionStatus::Disconnecting,
+        "Disconnected" => ConnectionStatus::Disconnected,
+        _ => return None,
+    };
+    Some(StatusInfo {
+        connection_status: connection_status,
+        last_connection_error: child_text(response, "NewLastConnectionError").unwrap_or_default(),
+        uptime: Duration::from_secs(child_text(response, "NewUptime")?.trim().parse().ok()?),
+    })
+}
+
+fn new_entry(
+    protocol: PortMappingProtocol,
+    remote_host: Option<Ipv4Addr>,
+    external_port: u16,
+    local_addr: SocketAddrV4,
+    lease_duration: u32,
+    description: &str,
+) -> PortMappingEntry {
+    PortMappingEntry {
+        remote_host: messages::format_remote_host(remote_host),
+        external_port: external_port,
+        protocol: protocol,
+        internal_port: local_addr.port(),
+        internal_client: local_addr.ip().to_string(),
+        enabled: true,
+        port_mapping_description: description.to_string(),
+        lease_duration: lease_duration,
+    }
+}
+
+// Map the error codes shared by the actions on an existing pinhole.
+fn pinhole_error(err: RequestError) -> PinholeError {
+    match err {
+        RequestError::ErrorCode(606, _) => PinholeError::ActionNotAuthorized,
+        RequestError::ErrorCode(704, _) => PinholeError::NoSuchEntry,
+        e => PinholeError::RequestError(e),
+    }
+}
+
+// Number of random ports picked in the dynamic range before widening the search.
+const DYNAMIC_PORT_ATTEMPTS: u32 = 10;
+
+// Pick a random external port for the given attempt. The dynamic range is tried first, as it is
+// less likely to collide with well-known services, then any port outside of the well-known range.
+fn random_port(attempt: u32) -> u16 {
+    let low = if attempt <= DYNAMIC_PORT_ATTEMPTS {
+        49_152_u32
+    } else {
+        1024_u32
+    };
+    let port_range = rand::distributions::Range::new(low, 65_536_u32);
+    let mut rng = rand::thread_rng();
+    port_range.ind_sample(&mut rng) as u16
+}
+
+// Delays to wait between the ports tried by add_any_port. They double after each conflict, up to
+// 200ms, and are randomized so that clients racing for ports do not retry in lockstep.
+fn random_port_delays() -> Vec<Duration> {
+    (0..19)
+        .map(|n| jitter(Duration::from_millis(5 * 2u64.pow(n)).min(Duration::from_millis(200))))
+        .collect()
+}
+
+// Get the text of a child element. An empty element yields an empty string.
+fn child_text(element: &xmltree::Element, name: &str) -> Option<String> {
+    element
+        .get_child(name)
+        .map(|e| e.text.clone().unwrap_or_default())
+}
+
+fn parse_port_mapping_entry(response: &xmltree::Element) -> Option<PortMappingEntry> {
+    let protocol = child_text(response, "NewProtocol")?.trim().parse().ok()?;
+    parse_port_mapping_fields(
+        response,
+        child_text(response, "NewRemoteHost").unwrap_or_default(),
+        parse_number(&child_text(response, "NewExternalPort")?)?,
+        protocol,
+    )
+}
+
+// Parse the fields describing the internal side of a port mapping.
+fn parse_port_mapping_fields(
+    response: &xmltree::Element,
+    remote_host: String,
+    external_port: u16,
+    protocol: PortMappingProtocol,
+) -> Option<PortMappingEntry> {
+    Some(PortMappingEntry {
+        remote_host: remote_host,
+        external_port: external_port,
+        protocol: protocol,
+        internal_port: parse_number(&child_text(response, "NewInternalPort")?)?,
+        internal_client: child_text(response, "NewInternalClient")?,
+        enabled: parse_bool(&child_text(response, "NewEnabled")?)?,
+        port_mapping_description: child_text(response, "NewPortMappingDescription")
+            .unwrap_or_default(),
+        lease_duration: parse_lease_duration(&child_text(response, "NewLeaseDuration")?)?,
+    })
+}
+
+// Tell whether the port mappings of a gateway with the given external IP address can not be
+// reached, because the address is private (RFC 1918) or shared (RFC 6598, 100.64.0.0/10).
+fn external_ip_reachability(ip: Ipv4Addr) -> Option<Reachability> {
+    let octets = ip.octets();
+    if ip.is_private() {
+        Some(Reachability::BehindNat(ip))
+    } else if octets[0] == 100 && octets[1] & 0xc0 == 64 {
+        Some(Reachability::CarrierGradeNat(ip))
+    } else {
+        None
+    }
+}
+
+// Whether the error was answered by the gateway, as opposed to a network error or a timeout.
+fn is_answer(err: &RequestError) -> bool {
+    match *err {
+        RequestError::ErrorCode(..) | RequestError::HttpStatus(..) => true,
+        _ => false,
+    }
+}
+
+// Whether the gateway rejected the action itself, with error 401 (invalid action) or with a 500
+// status and no fault.
+fn is_invalid_action(err: &RequestError) -> bool {
+    match *err {
+        RequestError::ErrorCode(401, _) | RequestError::HttpStatus(500, ..) => true,
+        _ => false,
+    }
+}
+
+// Parse a number, ignoring the whitespace some gateways pad it with.
+fn parse_number<T: FromStr>(text: &str) -> Option<T> {
+    text.trim().parse().ok()
+}
+
+// Parse a lease duration, which some gateways leave empty for infinite leases.
+fn parse_lease_duration(text: &str) -> Option<u32> {
+    match text.trim() {
+        "" => Some(0),
+        text => parse_number(text),
+    }
+}
+
+// Find the element named `ok` in the body of the response. Elements are matched by their local
+// name, whatever the namespace prefix the gateway uses. Whitespace around the document is ignored,
+// since the XML declaration must otherwise come first.
 fn parse_response(text: String, ok: &str) -> Result<(String, xmltree::Element), RequestError> {
-    let mut xml = match xmltree::Element::parse(text.as_bytes()) {
+    let mut xml = match xmltree::Element::parse(text.trim().as_bytes()) {
         Ok(xml) => xml,
         Err(..) => return Err(RequestError::InvalidResponse(text)),
     };
@@ -443,17 +1872,949 @@ fn parse_response(text: String, ok: &str) -> Result<(String, xmltree::Element),
         Some(upnp_error) => upnp_error,
         None => return Err(RequestError::InvalidResponse(text)),
     };
-    match (
-        upnp_error.get_child("errorCode"),
-        upnp_error.get_child("errorDescription"),
-    ) {
-        (Some(e), Some(d)) => match (e.text.as_ref(), d.text.as_ref()) {
-            (Some(et), Some(dt)) => match et.parse::<u16>() {
-                Ok(en) => Err(RequestError::ErrorCode(en, From::from(&dt[..]))),
-                Err(..) => Err(RequestError::InvalidResponse(text)),
-            },
-            _ => Err(RequestError::InvalidResponse(text)),
-        },
+    // Some gateways leave out the description, only the code is required.
+    let description = child_text(upnp_error, "errorDescription").unwrap_or_default();
+    match child_text(upnp_error, "errorCode").map(|code| code.trim().parse::<u16>()) {
+        Some(Ok(code)) => Err(RequestError::ErrorCode(code, description)),
         _ => Err(RequestError::InvalidResponse(text)),
     }
 }
+
+#[test]
+fn test_parse_port_mapping_entry() {
+    let text = "<?xml version=\"1.0\"?>
+<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">
+<s:Body>
+<u:GetGenericPortMappingEntryResponse xmlns:u=\"urn:schemas-upnp-org:service:WANIPConnection:1\">
+<NewRemoteHost></NewRemoteHost>
+<NewExternalPort>1234</NewExternalPort>
+<NewProtocol>TCP</NewProtocol>
+<NewInternalPort>4321</NewInternalPort>
+<NewInternalClient>192.168.1.210</NewInternalClient>
+<NewEnabled>1</NewEnabled>
+<NewPortMappingDescription>rust-igd</NewPortMappingDescription>
+<NewLeaseDuration>0</NewLeaseDuration>
+</u:GetGenericPortMappingEntryResponse>
+</s:Body>
+</s:Envelope>";
+    let (_, response) =
+        parse_response(text.to_string(), "GetGenericPortMappingEntryResponse").unwrap();
+    let entry = parse_port_mapping_entry(&response).unwrap();
+    assert_eq!(entry.remote_host, "");
+    assert_eq!(entry.external_port, 1234);
+    assert_eq!(entry.protocol, PortMappingProtocol::TCP);
+    assert_eq!(entry.internal_port, 4321);
+    assert_eq!(entry.internal_client, "192.168.1.210");
+    assert!(entry.enabled);
+    assert_eq!(entry.port_mapping_description, "rust-igd");
+    assert_eq!(entry.lease_duration, 0);
+}
+
+#[test]
+fn test_parse_port_mapping_entry_padded() {
+    let text = "<?xml version=\"1.0\"?>
+<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">
+<s:Body>
+<u:GetGenericPortMappingEntryResponse xmlns:u=\"urn:schemas-upnp-org:service:WANIPConnection:1\">
+<NewRemoteHost></NewRemoteHost>
+<NewExternalPort> 1234 </NewExternalPort>
+<NewProtocol>TCP</NewProtocol>
+<NewInternalPort>
+4321
+</NewInternalPort>
+<NewInternalClient>192.168.1.210</NewInternalClient>
+<NewEnabled> 1
+</NewEnabled>
+<NewPortMappingDescription>rust-igd</NewPortMappingDescription>
+<NewLeaseDuration> </NewLeaseDuration>
+</u:GetGenericPortMappingEntryResponse>
+</s:Body>
+</s:Envelope>";
+    let (_, response) =
+        parse_response(text.to_string(), "GetGenericPortMappingEntryResponse").unwrap();
+    let entry = parse_port_mapping_entry(&response).unwrap();
+    assert_eq!(entry.external_port, 1234);
+    assert_eq!(entry.internal_port, 4321);
+    assert!(entry.enabled);
+    assert_eq!(entry.lease_duration, 0);
+
+    let text = text.replace(
+        "<NewLeaseDuration> </NewLeaseDuration>",
+        "<NewLeaseDuration>forever</NewLeaseDuration>",
+    );
+    let (_, response) = parse_response(text, "GetGenericPortMappingEntryResponse").unwrap();
+    assert!(parse_port_mapping_entry(&response).is_none());
+}
+
+#[test]
+fn test_parse_response_fault() {
+    let text = "<?xml version=\"1.0\"?>
+<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">
+<s:Body>
+<s:Fault>
+<faultcode>s:Client</faultcode>
+<faultstring>UPnPError</faultstring>
+<detail>
+<UPnPError xmlns=\"urn:schemas-upnp-org:control-1-0\">
+<errorCode>718</errorCode>
+<errorDescription>ConflictInMappingEntry</errorDescription>
+</UPnPError>
+</detail>
+</s:Fault>
+</s:Body>
+</s:Envelope>";
+    match parse_response(text.to_string(), "AddPortMappingResponse") {
+        Err(RequestError::ErrorCode(718, ref description)) => {
+            assert_eq!(description, "ConflictInMappingEntry")
+        }
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn test_parse_external_ip_address_out_of_range() {
+    let text = "<?xml version=\"1.0\"?>
+<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">
+<s:Body>
+<u:GetExternalIPAddressResponse xmlns:u=\"urn:schemas-upnp-org:service:WANIPConnection:1\">
+<NewExternalIPAddress>999.999.999.999</NewExternalIPAddress>
+</u:GetExternalIPAddressResponse>
+</s:Body>
+</s:Envelope>";
+    let (_, response) = parse_response(text.to_string(), "GetExternalIPAddressResponse").unwrap();
+    assert_eq!(parse_external_ip_address::<Ipv4Addr>(&response), None);
+}
+
+#[test]
+fn test_parse_external_ip_address_v6() {
+    let text = "<?xml version=\"1.0\"?>
+<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">
+<s:Body>
+<u:GetExternalIPAddressResponse xmlns:u=\"urn:schemas-upnp-org:service:WANIPConnection:1\">
+<NewExternalIPAddress>2001:db8::1</NewExternalIPAddress>
+</u:GetExternalIPAddressResponse>
+</s:Body>
+</s:Envelope>";
+    let (_, response) = parse_response(text.to_string(), "GetExternalIPAddressResponse").unwrap();
+    assert_eq!(
+        parse_external_ip_address::<IpAddr>(&response),
+        Some("2001:db8::1".parse().unwrap())
+    );
+    assert_eq!(parse_external_ip_address::<Ipv4Addr>(&response), None);
+
+    let text = text.replace("2001:db8::1", "::ffff:203.0.113.5");
+    let (_, response) = parse_response(text, "GetExternalIPAddressResponse").unwrap();
+    let ip = Ipv4Addr::new(203, 0, 113, 5);
+    assert_eq!(parse_external_ip_address::<IpAddr>(&response), Some(IpAddr::V4(ip)));
+    assert_eq!(parse_external_ip_address::<Ipv4Addr>(&response), Some(ip));
+}
+
+#[test]
+fn test_external_ip_unavailable() {
+    let format = |ip: &str| {
+        format!("<?xml version=\"1.0\"?>
+<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\">
+<s:Body>
+<u:GetExternalIPAddressResponse xmlns:u=\"urn:schemas-upnp-org:service:WANIPConnection:1\">
+<NewExternalIPAddress>{}</NewExternalIPAddress>
+</u:GetExternalIPAddressResponse>
+</s:Body>
+</s:Envelope>", ip)
+    };
+    for ip in &["", " ", "0.0.0.0", "::", "::ffff:0.0.0.0"] {
+        let (_, response) = parse_response(format(ip), "GetExternalIPAddressResponse").unwrap();
+        assert!(is_external_ip_unavailable(&response, false), "{:?}", ip);
+    }
+    let text = format("203.0.113.1");
+    let (_, response) = parse_response(text, "GetExternalIPAddressResponse").unwrap();
+    assert!(!is_external_ip_unavailable(&response, false));
+}
+
+#[test]
+fn test_parse_external_ip() {
+    let text = "<?xml version=\"1.0\"?>
+<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\">
+<s:Body>
+<m:GetExternalIPAddressResponse xmlns:m=\"urn:schemas-upnp-org:service:WANIPConnection:1\">
+<NewExternalIPAddress> 203.0.113.1 </NewExternalIPAddress>
+</m:GetExternalIPAddressResponse>
+</s:Body>
+</s:Envelope>";
+    assert_eq!(parse_external_ip(text).unwrap(), IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1)));
+    match parse_external_ip(&text.replace("203.0.113.1", "0.0.0.0")) {
+        Err(RequestError::ExternalIpUnavailable) => (),
+        result => panic!("unexpected result: {:?}", result),
+    }
+    match parse_external_ip(&text.replace("203.0.113.1", "gateway")) {
+        Err(RequestError::InvalidResponse(_)) => (),
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn test_remove_port_zero_invalid() {
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let gateway = Gateway::new(
+        "192.168.1.1:1900".parse().unwrap(),
+        "/ctl/IPConn".to_string(),
+        core.handle(),
+    );
+    match core.run(gateway.remove_port(PortMappingProtocol::TCP, 0)) {
+        Err(RemovePortError::RequestError(RequestError::InvalidArgument(_))) => (),
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn test_parse_nat_rsip_status() {
+    let text = "<?xml version=\"1.0\"?>
+<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">
+<s:Body>
+<u:GetNATRSIPStatusResponse xmlns:u=\"urn:schemas-upnp-org:service:WANIPConnection:1\">
+<NewRSIPAvailable>0</NewRSIPAvailable>
+<NewNATEnabled>1</NewNATEnabled>
+</u:GetNATRSIPStatusResponse>
+</s:Body>
+</s:Envelope>";
+    let (_, response) = parse_response(text.to_string(), "GetNATRSIPStatusResponse").unwrap();
+    assert_eq!(
+        parse_nat_rsip_status(&response),
+        Some(NatRsipStatus {
+            rsip_available: false,
+            nat_enabled: true,
+        })
+    );
+}
+
+#[test]
+fn test_parse_connection_type_info() {
+    let text = "<?xml version=\"1.0\"?>
+<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">
+<s:Body>
+<u:GetConnectionTypeInfoResponse xmlns:u=\"urn:schemas-upnp-org:service:WANIPConnection:1\">
+<NewConnectionType>IP_Routed</NewConnectionType>
+<NewPossibleConnectionTypes>IP_Routed, IP_Bridged</NewPossibleConnectionTypes>
+</u:GetConnectionTypeInfoResponse>
+</s:Body>
+</s:Envelope>";
+    let (_, response) =
+        parse_response(text.to_string(), "GetConnectionTypeInfoResponse").unwrap();
+    let info = parse_connection_type_info(&response).unwrap();
+    assert_eq!(info.connection_type, "IP_Routed");
+    assert_eq!(info.possible_connection_types, vec!["IP_Routed", "IP_Bridged"]);
+}
+
+#[test]
+fn test_get_external_ip_with_transport() {
+    use mock::{self, MockTransport};
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let gateway = mock::async_gateway(MockTransport::external_ip("203.0.113.1"), &core.handle());
+    assert_eq!(core.run(gateway.get_external_ip()).unwrap(), Ipv4Addr::new(203, 0, 113, 1));
+}
+
+#[test]
+fn test_http_status() {
+    use mock::{self, MockTransport};
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let transport = MockTransport::new(|_, _| {
+        let headers = vec![("WWW-Authenticate".to_string(), "Basic realm=\"igd\"".to_string())];
+        Err(RequestError::HttpStatus(401, "Unauthorized".to_string(), headers))
+    });
+    let mut gateway = mock::async_gateway(transport, &core.handle());
+    match core.run(gateway.get_status_info()) {
+        Err(ref err @ RequestError::HttpStatus(401, ..)) => {
+            if let RequestError::HttpStatus(_, ref text, _) = *err {
+                assert_eq!(text, "Unauthorized");
+            }
+            assert_eq!(err.header("www-authenticate"), Some("Basic realm=\"igd\""));
+            assert_eq!(err.header("Location"), None);
+        }
+        result => panic!("unexpected result: {:?}", result),
+    }
+    gateway.inner.transport = MockTransport::new(|_, _| {
+        let text = mock::fault(606, "Action not authorized").unwrap();
+        Err(RequestError::HttpStatus(500, text, Vec::new()))
+    });
+    match core.run(gateway.get_status_info()) {
+        Err(RequestError::ErrorCode(606, _)) => (),
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn test_add_pinhole() {
+    use mock::{self, MockTransport};
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let transport = MockTransport::answering("<UniqueID>42</UniqueID>");
+    let mut gateway = mock::async_gateway(transport.clone(), &core.handle());
+    let internal_client = "2001:db8::2".parse().unwrap();
+    let add = gateway.add_pinhole(None, 0, internal_client, 8080, PortMappingProtocol::TCP, 3600);
+    match core.run(add) {
+        Err(AddPinholeError::RequestError(RequestError::UnsupportedAction(_))) => (),
+        result => panic!("unexpected result: {:?}", result),
+    }
+
+    gateway.inner.ipv6_firewall_control_url = Some("/ctl/IP6FCtl".to_string());
+    let add = gateway.add_pinhole(None, 0, internal_client, 8080, PortMappingProtocol::TCP, 3600);
+    assert_eq!(core.run(add).unwrap(), 42);
+    let requests = transport.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].url, "http://192.168.1.1:1900/ctl/IP6FCtl");
+    assert!(requests[0].body.contains("<InternalClient>2001:db8::2</InternalClient>"));
+    assert!(requests[0].body.contains("<Protocol>6</Protocol>"));
+}
+
+#[test]
+fn test_parse_response_prefixes() {
+    let responses = [
+        "
+<?xml version=\"1.0\"?>
+<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\">
+<s:Body><m:AddPortMappingResponse xmlns:m=\"urn:schemas-upnp-org:service:WANIPConnection:1\"/>
+</s:Body></s:Envelope>
+",
+        "<?xml version=\"1.0\"?>
+<SOAP-ENV:Envelope xmlns:SOAP-ENV=\"http://schemas.xmlsoap.org/soap/envelope/\">
+<SOAP-ENV:Body>
+  <AddPortMappingResponse xmlns=\"urn:schemas-upnp-org:service:WANIPConnection:1\">
+  </AddPortMappingResponse>
+</SOAP-ENV:Body></SOAP-ENV:Envelope>",
+    ];
+    for text in &responses {
+        assert!(parse_response(text.to_string(), "AddPortMappingResponse").is_ok());
+    }
+}
+
+#[test]
+fn test_wait_for_connection() {
+    use mock::{self, MockTransport};
+    use tokio_core::reactor::Core;
+
+    // Report the connection as connecting for the first polls.
+    let connecting = |connected_after| {
+        MockTransport::new(move |request, polls| {
+            let status = if polls < connected_after { "Connecting" } else { "Connected" };
+            mock::response(request, &format!(
+                "<NewConnectionStatus>{}</NewConnectionStatus>
+<NewLastConnectionError>ERROR_NONE</NewLastConnectionError>
+<NewUptime>0</NewUptime>",
+                status
+            ))
+        })
+    };
+
+    let mut core = Core::new().unwrap();
+    let mut gateway = mock::async_gateway(connecting(1), &core.handle());
+    core.run(gateway.wait_for_connection(Duration::from_secs(5))).unwrap();
+
+    gateway.inner.transport = connecting(usize::max_value());
+    match core.run(gateway.wait_for_connection(Duration::from_millis(200))) {
+        Err(RequestError::Timeout) => (),
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn test_add_ports_rollback() {
+    use mock::{self, MockTransport};
+    use tokio_core::reactor::Core;
+
+    let entry = |protocol| PortMappingEntry {
+        remote_host: String::new(),
+        external_port: 6000,
+        protocol: protocol,
+        internal_port: 6000,
+        internal_client: "192.168.1.2".to_string(),
+        enabled: true,
+        port_mapping_description: "test".to_string(),
+        lease_duration: 0,
+    };
+    let mut core = Core::new().unwrap();
+    // Reject UDP port mappings.
+    let transport = MockTransport::new(|request, _| {
+        if request.body.contains("<NewProtocol>UDP</NewProtocol>") {
+            mock::fault(718, "ConflictInMappingEntry")
+        } else {
+            mock::response(request, "")
+        }
+    });
+    let gateway = mock::async_gateway(transport.clone(), &core.handle());
+    let entries = [entry(PortMappingProtocol::TCP), entry(PortMappingProtocol::UDP)];
+    match core.run(gateway.add_ports(&entries)) {
+        Err(AddPortError::PortInUse) => (),
+        result => panic!("unexpected result: {:?}", result),
+    }
+    assert_eq!(
+        transport.actions(),
+        ["AddPortMapping", "AddPortMapping", "DeletePortMapping"]
+    );
+    let added = core.run(gateway.add_ports(&entries[..1])).unwrap();
+    assert_eq!(added, vec![entry(PortMappingProtocol::TCP)]);
+
+    let sent = transport.requests().len();
+    let local_addr = "192.168.1.2:6000".parse().unwrap();
+    match core.run(gateway.add_port_both_protocols(6000, local_addr, 0, "test")) {
+        Err(AddPortError::PortInUse) => (),
+        result => panic!("unexpected result: {:?}", result),
+    }
+    assert_eq!(
+        transport.actions()[sent..],
+        ["AddPortMapping", "AddPortMapping", "DeletePortMapping"]
+    );
+}
+
+#[test]
+fn test_remove_port_if_exists() {
+    use mock::{self, MockTransport};
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    // Only know about the port mapping of port 6000.
+    let transport = MockTransport::new(|request, _| {
+        if request.body.contains("<NewExternalPort>6000</NewExternalPort>") {
+            mock::response(request, "")
+        } else {
+            mock::fault(714, "NoSuchEntryInArray")
+        }
+    });
+    let gateway = mock::async_gateway(transport, &core.handle());
+    assert!(core.run(gateway.remove_port_if_exists(PortMappingProtocol::TCP, 6000)).unwrap());
+    assert!(!core.run(gateway.remove_port_if_exists(PortMappingProtocol::TCP, 6001)).unwrap());
+    match core.run(gateway.remove_port_if_exists(PortMappingProtocol::TCP, 0)) {
+        Err(RemovePortError::RequestError(RequestError::InvalidArgument(_))) => (),
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn test_add_port_mapping_ports() {
+    use mock::{self, MockTransport};
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let transport = MockTransport::answering("");
+    let gateway = mock::async_gateway(transport.clone(), &core.handle());
+    let internal_client = Ipv4Addr::new(192, 168, 1, 2);
+    let future = gateway.add_port_mapping(PortMappingProtocol::TCP, 8080, internal_client, 80, 0, "web");
+    let entry = core.run(future).unwrap();
+    assert_eq!(entry.external_port, 8080);
+    assert_eq!(entry.internal_client, "192.168.1.2");
+    assert_eq!(entry.internal_port, 80);
+    assert_eq!(entry.port_mapping_description, "web");
+    let body = &transport.requests()[0].body;
+    assert!(body.contains("<NewExternalPort>8080</NewExternalPort>"));
+    assert!(body.contains("<NewInternalClient>192.168.1.2</NewInternalClient>"));
+    assert!(body.contains("<NewInternalPort>80</NewInternalPort>"));
+}
+
+#[test]
+fn test_count_port_mappings() {
+    use mock::{self, MockTransport};
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    // Know about the port mappings of index 0 to 2.
+    let transport = MockTransport::new(|request, _| {
+        let index = (0..3).find(|index| {
+            let argument = format!("<NewPortMappingIndex>{}</NewPortMappingIndex>", index);
+            request.body.contains(&argument)
+        });
+        match index {
+            Some(index) => mock::response(request, &format!(
+                "<NewRemoteHost></NewRemoteHost><NewExternalPort>{}</NewExternalPort>
+<NewProtocol>TCP</NewProtocol><NewInternalPort>6000</NewInternalPort>
+<NewInternalClient>192.168.1.2</NewInternalClient><NewEnabled>1</NewEnabled>
+<NewPortMappingDescription></NewPortMappingDescription><NewLeaseDuration>0</NewLeaseDuration>",
+                6000 + index
+            )),
+            None => mock::fault(713, "SpecifiedArrayIndexInvalid"),
+        }
+    });
+    let gateway = mock::async_gateway(transport, &core.handle());
+    assert_eq!(core.run(gateway.count_port_mappings()).unwrap(), 3);
+}
+
+#[test]
+fn test_add_any_port_mapping() {
+    use mock::{self, MockTransport};
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    // Reserve port 50000 whatever port was asked for.
+    let transport = MockTransport::answering("<NewReservedPort>50000</NewReservedPort>");
+    let mut gateway = mock::async_gateway(transport, &core.handle());
+    let local_addr = "192.168.1.2:6000".parse().unwrap();
+    let future = gateway.add_any_port_mapping(PortMappingProtocol::TCP, 6000, local_addr, 0, "");
+    match core.run(future) {
+        Err(AddAnyPortError::RequestError(RequestError::UnsupportedAction(_))) => (),
+        result => panic!("unexpected result: {:?}", result),
+    }
+    gateway.inner.service_type = messages::WAN_IP_CONNECTION_2.to_string();
+    let future = gateway.add_any_port_mapping(PortMappingProtocol::TCP, 6000, local_addr, 0, "");
+    assert_eq!(core.run(future).unwrap(), 50000);
+}
+
+#[test]
+fn test_add_any_port_with_attempts() {
+    use mock::{self, MockTransport};
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    // Answer ConflictInMappingEntry to the first port mappings.
+    let transport = MockTransport::new(|request, sent| {
+        if sent < 3 {
+            mock::fault(718, "ConflictInMappingEntry")
+        } else {
+            mock::response(request, "")
+        }
+    });
+    let gateway = mock::async_gateway(transport.clone(), &core.handle());
+    let local_addr = "192.168.1.2:6000".parse().unwrap();
+    let future = gateway.add_any_port_with_attempts(PortMappingProtocol::TCP, local_addr, 0, "");
+    let (port, attempts) = core.run(future).unwrap();
+    assert_eq!(attempts, 4);
+    let ports: Vec<u16> = transport
+        .requests()
+        .iter()
+        .map(|request| {
+            let start = request.body.find("<NewExternalPort>").unwrap() + "<NewExternalPort>".len();
+            let end = request.body[start..].find('<').unwrap() + start;
+            request.body[start..end].parse().unwrap()
+        })
+        .collect();
+    assert_eq!(ports.len(), 4);
+    assert_eq!(ports[0], 6000);
+    assert_eq!(ports[3], port);
+    assert!(ports[1..].iter().all(|&port| port >= 49152));
+}
+
+#[test]
+fn test_random_port() {
+    assert!((1..11).map(random_port).all(|port| port >= 49152));
+    assert!((11..100).map(random_port).all(|port| port >= 1024));
+}
+
+#[test]
+fn test_supports_port_mapping() {
+    use tokio_core::reactor::Core;
+
+    let core = Core::new().unwrap();
+    let mut gateway = Gateway::new(
+        "192.168.1.1:1900".parse().unwrap(),
+        "/ctl/IPConn".to_string(),
+        core.handle(),
+    );
+    assert!(gateway.supports_port_mapping());
+    gateway.inner.service_type = messages::WAN_PPP_CONNECTION.to_string();
+    assert!(gateway.supports_port_mapping());
+    gateway.inner.service_type = messages::WAN_COMMON_INTERFACE_CONFIG.to_string();
+    assert!(!gateway.supports_port_mapping());
+    gateway.inner.service_type = messages::WAN_IP_CONNECTION_2.to_string();
+    gateway.inner.control_url = ControlUrl::default();
+    assert!(!gateway.supports_port_mapping());
+}
+
+#[test]
+fn test_dry_run_transport() {
+    use soap::DryRunTransport;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let mut gateway = Gateway::new(
+        "192.168.1.1:1900".parse().unwrap(),
+        "/ctl/IPConn".to_string(),
+        core.handle(),
+    );
+    let transport = Arc::new(DryRunTransport::new());
+    gateway.inner.transport = transport.clone();
+    core.run(gateway.remove_port(PortMappingProtocol::UDP, 6000)).unwrap();
+    match core.run(gateway.get_external_ip()) {
+        Err(GetExternalIpError::RequestError(RequestError::InvalidResponse(_))) => (),
+        result => panic!("unexpected result: {:?}", result),
+    }
+    let requests = transport.requests();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[0].url, "http://192.168.1.1:1900/ctl/IPConn");
+    assert_eq!(
+        requests[0].action,
+        "\"urn:schemas-upnp-org:service:WANIPConnection:1#DeletePortMapping\""
+    );
+    assert!(requests[0].body.contains("<NewExternalPort>6000</NewExternalPort>"));
+    assert!(requests[1].action.ends_with("#GetExternalIPAddress\""));
+}
+
+#[test]
+fn test_remove_entry_remote_host() {
+    use soap::DryRunTransport;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let mut gateway = Gateway::new(
+        "192.168.1.1:1900".parse().unwrap(),
+        "/ctl/IPConn".to_string(),
+        core.handle(),
+    );
+    let transport = Arc::new(DryRunTransport::new());
+    gateway.inner.transport = transport.clone();
+    let entry = PortMappingEntry {
+        remote_host: "203.0.113.7".to_string(),
+        external_port: 6000,
+        protocol: PortMappingProtocol::UDP,
+        internal_port: 6000,
+        internal_client: "192.168.1.2".to_string(),
+        enabled: true,
+        port_mapping_description: "test".to_string(),
+        lease_duration: 0,
+    };
+    core.run(gateway.remove_entry(&entry)).unwrap();
+    let body = &transport.requests()[0].body;
+    assert!(body.contains("<NewRemoteHost>203.0.113.7</NewRemoteHost>"));
+    assert!(body.contains("<NewExternalPort>6000</NewExternalPort>"));
+    assert!(body.contains("<NewProtocol>UDP</NewProtocol>"));
+}
+
+#[test]
+fn test_remove_entry_remote_host_name() {
+    use soap::DryRunTransport;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let mut gateway = Gateway::new(
+        "192.168.1.1:1900".parse().unwrap(),
+        "/ctl/IPConn".to_string(),
+        core.handle(),
+    );
+    let transport = Arc::new(DryRunTransport::new());
+    gateway.inner.transport = transport.clone();
+    let mut entry = PortMappingEntry {
+        remote_host: "peer.example.com".to_string(),
+        external_port: 6000,
+        protocol: PortMappingProtocol::UDP,
+        internal_port: 6000,
+        internal_client: "192.168.1.2".to_string(),
+        enabled: true,
+        port_mapping_description: "test".to_string(),
+        lease_duration: 0,
+    };
+    core.run(gateway.remove_entry(&entry)).unwrap();
+    entry.remote_host = "2001:db8::1&".to_string();
+    core.run(gateway.remove_entry(&entry)).unwrap();
+    let requests = transport.requests();
+    assert!(requests[0].body.contains("<NewRemoteHost>peer.example.com</NewRemoteHost>"));
+    assert!(requests[1].body.contains("<NewRemoteHost>2001:db8::1&amp;</NewRemoteHost>"));
+}
+
+#[test]
+fn test_port_mapping_service_types() {
+    use futures::Stream;
+    use mock::{self, MockTransport};
+    use tokio_core::reactor::Core;
+
+    let local_addr = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 6000);
+    for service_type in &[messages::WAN_IP_CONNECTION, messages::WAN_PPP_CONNECTION] {
+        let mut core = Core::new().unwrap();
+        // The mock answers in the namespace of the service the request was sent to, like a
+        // gateway would.
+        let transport = MockTransport::new(|request, _| {
+            let arguments = match mock::action_name(request) {
+                "GetGenericPortMappingEntry" if request.body.contains(">0</NewPortMappingIndex>") => {
+                    "<NewRemoteHost></NewRemoteHost><NewExternalPort>6000</NewExternalPort>
+<NewProtocol>TCP</NewProtocol><NewInternalPort>6000</NewInternalPort>
+<NewInternalClient>192.168.1.2</NewInternalClient><NewEnabled>1</NewEnabled>
+<NewPortMappingDescription>test</NewPortMappingDescription>
+<NewLeaseDuration>0</NewLeaseDuration>"
+                }
+                "GetGenericPortMappingEntry" => {
+                    return mock::fault(713, "SpecifiedArrayIndexInvalid")
+                }
+                "GetSpecificPortMappingEntry" => {
+                    "<NewInternalPort>6000</NewInternalPort>
+<NewInternalClient>192.168.1.2</NewInternalClient><NewEnabled>1</NewEnabled>
+<NewPortMappingDescription>test</NewPortMappingDescription>
+<NewLeaseDuration>0</NewLeaseDuration>"
+                }
+                _ => "",
+            };
+            mock::response(request, arguments)
+        });
+        let mut gateway = mock::async_gateway(transport.clone(), &core.handle());
+        gateway.inner.service_type = service_type.to_string();
+
+        core.run(gateway.add_port(PortMappingProtocol::TCP, 6000, local_addr, 0, "test"))
+            .unwrap();
+        let entries = core.run(gateway.port_mappings().collect()).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].internal_client, "192.168.1.2");
+        let entry = gateway.get_specific_port_mapping_entry(PortMappingProtocol::TCP, 6000);
+        let entry = core.run(entry).unwrap();
+        assert_eq!(entry.map(|entry| entry.port_mapping_description), Some("test".to_string()));
+        core.run(gateway.remove_port(PortMappingProtocol::TCP, 6000)).unwrap();
+
+        assert_eq!(
+            transport.actions(),
+            [
+                "AddPortMapping",
+                "GetGenericPortMappingEntry",
+                "GetGenericPortMappingEntry",
+                "GetSpecificPortMappingEntry",
+                "DeletePortMapping",
+            ]
+        );
+        for request in transport.requests() {
+            assert!(request.action.starts_with(&format!("\"{}#", service_type)));
+            assert!(request.body.contains(&format!("xmlns:u=\"{}\"", service_type)));
+        }
+    }
+}
+
+#[test]
+fn test_verify_reachable() {
+    use mock::{self, MockTransport};
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let mut gateway = mock::async_gateway(MockTransport::answering(""), &core.handle());
+    let timeout = Duration::from_secs(1);
+    let cases = [
+        ("10.0.0.2", PortMappingProtocol::TCP, Reachability::BehindNat(Ipv4Addr::new(10, 0, 0, 2))),
+        (
+            "100.72.1.9",
+            PortMappingProtocol::TCP,
+            Reachability::CarrierGradeNat(Ipv4Addr::new(100, 72, 1, 9)),
+        ),
+        (
+            "203.0.113.1",
+            PortMappingProtocol::UDP,
+            Reachability::Unverified(Ipv4Addr::new(203, 0, 113, 1)),
+        ),
+    ];
+    for &(ip, protocol, expected) in &cases {
+        gateway.inner.transport = MockTransport::external_ip(ip);
+        let reachability = core.run(gateway.verify_reachable(6000, protocol, timeout)).unwrap();
+        assert_eq!(reachability, expected);
+    }
+    // A listener on the loopback address stands for the hairpinned mapping.
+    let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    gateway.inner.transport = MockTransport::external_ip("127.0.0.1");
+    let reachability = gateway.verify_reachable(port, PortMappingProtocol::TCP, timeout);
+    assert_eq!(core.run(reachability).unwrap(), Reachability::Reachable);
+    assert_eq!(external_ip_reachability(Ipv4Addr::new(100, 128, 0, 1)), None);
+    assert_eq!(
+        external_ip_reachability(Ipv4Addr::new(172, 16, 0, 1)),
+        Some(Reachability::BehindNat(Ipv4Addr::new(172, 16, 0, 1)))
+    );
+}
+
+#[test]
+fn test_soap_action_quoting_retry() {
+    use mock::{self, MockTransport};
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    // Reject the quoted SOAPAction like some firmwares do.
+    let transport = MockTransport::new(|request, _| {
+        if request.action.starts_with('"') {
+            return Err(RequestError::HttpStatus(500, String::new(), Vec::new()));
+        }
+        mock::response(request, "")
+    });
+    let gateway = mock::async_gateway(transport.clone(), &core.handle());
+    core.run(gateway.remove_port(PortMappingProtocol::TCP, 6000)).unwrap();
+    assert_eq!(gateway.inner.action_quoting.get(), Some(false));
+    // The style which worked is used from then on, by the clones of the gateway too.
+    core.run(gateway.clone().remove_port(PortMappingProtocol::UDP, 6000)).unwrap();
+    let actions: Vec<String> =
+        transport.requests().into_iter().map(|request| request.action).collect();
+    assert_eq!(
+        actions,
+        [
+            "\"urn:schemas-upnp-org:service:WANIPConnection:1#DeletePortMapping\"",
+            "urn:schemas-upnp-org:service:WANIPConnection:1#DeletePortMapping",
+            "urn:schemas-upnp-org:service:WANIPConnection:1#DeletePortMapping",
+        ]
+    );
+}
+
+#[test]
+fn test_add_any_port_fallback_requests() {
+    use mock::{self, MockTransport};
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    // Know AddPortMapping but not AddAnyPortMapping, whatever the quoting.
+    let transport = MockTransport::new(|request, _| match mock::action_name(request) {
+        "AddAnyPortMapping" => mock::fault(401, "Invalid Action"),
+        _ => mock::response(request, ""),
+    });
+    let mut gateway = mock::async_gateway(transport.clone(), &core.handle());
+    gateway.inner.service_type = messages::WAN_IP_CONNECTION_2.to_string();
+    let local_addr = "192.168.1.2:6000".parse().unwrap();
+    // The other quoting style is only tried for the first action the gateway rejects.
+    let add = gateway.add_any_port(PortMappingProtocol::TCP, local_addr, 0, "");
+    assert_eq!(core.run(add).unwrap(), 6000);
+    assert_eq!(gateway.inner.action_quoting.get(), Some(true));
+    let add = gateway.add_any_port(PortMappingProtocol::UDP, local_addr, 0, "");
+    assert_eq!(core.run(add).unwrap(), 6000);
+    assert_eq!(
+        transport.actions(),
+        [
+            "AddAnyPortMapping",
+            "AddAnyPortMapping",
+            "AddPortMapping",
+            "AddAnyPortMapping",
+            "AddPortMapping",
+        ]
+    );
+    let quoted: Vec<bool> = transport
+        .requests()
+        .iter()
+        .map(|request| request.action.starts_with('"'))
+        .collect();
+    assert_eq!(quoted, [true, false, true, true, true]);
+}
+
+#[test]
+fn test_metrics_callback() {
+    use mock;
+    use soap::DryRunTransport;
+    use std::sync::Mutex;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let mut gateway = mock::async_gateway(Arc::new(DryRunTransport::new()), &core.handle());
+    let metrics = Arc::new(Mutex::new(Vec::new()));
+    let recorded = metrics.clone();
+    gateway.inner.set_metrics_callback(move |metrics: &RequestMetrics| {
+        recorded.lock().unwrap().push(metrics.clone())
+    });
+    core.run(gateway.remove_port(PortMappingProtocol::UDP, 6000)).unwrap();
+    let metrics = metrics.lock().unwrap();
+    assert_eq!(metrics.len(), 1);
+    assert_eq!(
+        metrics[0].action,
+        "\"urn:schemas-upnp-org:service:WANIPConnection:1#DeletePortMapping\""
+    );
+    assert_eq!(metrics[0].attempts, 1);
+    assert!(metrics[0].response_bytes > 0);
+}
+
+#[test]
+fn test_warn_disconnect_delay() {
+    use mock::{self, MockTransport};
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let transport = MockTransport::new(|request, _| match mock::action_name(request) {
+        "GetWarnDisconnectDelay" => {
+            mock::response(request, "<NewWarnDisconnectDelay>30</NewWarnDisconnectDelay>")
+        }
+        _ => mock::fault(606, "Action not authorized"),
+    });
+    let gateway = mock::async_gateway(transport.clone(), &core.handle());
+    assert_eq!(core.run(gateway.get_warn_disconnect_delay()).unwrap(), Duration::from_secs(30));
+    match core.run(gateway.set_warn_disconnect_delay(Duration::from_secs(60))) {
+        Err(RequestError::ErrorCode(606, _)) => (),
+        result => panic!("unexpected result: {:?}", result),
+    }
+    assert!(transport.requests()[1]
+        .body
+        .contains("<NewWarnDisconnectDelay>60</NewWarnDisconnectDelay>"));
+}
+
+#[test]
+fn test_auto_disconnect_time() {
+    use mock::{self, MockTransport};
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let transport = MockTransport::new(|request, _| match mock::action_name(request) {
+        "GetAutoDisconnectTime" => {
+            mock::response(request, "<NewAutoDisconnectTime>600</NewAutoDisconnectTime>")
+        }
+        "SetAutoDisconnectTime" => mock::response(request, ""),
+        _ => mock::fault(606, "Action not authorized"),
+    });
+    let gateway = mock::async_gateway(transport.clone(), &core.handle());
+    assert_eq!(core.run(gateway.get_auto_disconnect_time()).unwrap(), Duration::from_secs(600));
+    core.run(gateway.set_auto_disconnect_time(Duration::from_secs(1200))).unwrap();
+    assert!(transport.requests()[1]
+        .body
+        .contains("<NewAutoDisconnectTime>1200</NewAutoDisconnectTime>"));
+    match core.run(gateway.get_warn_disconnect_delay()) {
+        Err(RequestError::ErrorCode(606, _)) => (),
+        result => panic!("unexpected result: {:?}", result),
+    }
+    match core.run(gateway.set_auto_disconnect_time(Duration::from_secs(u64::max_value()))) {
+        Err(RequestError::InvalidArgument(_)) => (),
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn test_cancel_flag_stops_retries() {
+    use gateway::CancelFlag;
+    use mock::{self, MockTransport};
+    use std::io;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let cancel = Arc::new(AtomicBool::new(false));
+    // Fail like an unreachable gateway, and cancel after the first attempt.
+    let cancelling = cancel.clone();
+    let transport = MockTransport::new(move |_, _| {
+        cancelling.store(true, Ordering::SeqCst);
+        let err = io::Error::new(io::ErrorKind::ConnectionRefused, "refused");
+        Err(RequestError::IoError(err))
+    });
+    let mut gateway = mock::async_gateway(transport.clone(), &core.handle());
+    gateway.inner.set_cancel_flag(cancel.clone());
+    match core.run(gateway.remove_port(PortMappingProtocol::UDP, 6000)) {
+        Err(RemovePortError::RequestError(RequestError::Cancelled)) => (),
+        result => panic!("unexpected result: {:?}", result),
+    }
+    assert_eq!(transport.requests().len(), 1);
+
+    // Requests started after the cancellation are not sent at all.
+    match core.run(gateway.remove_port(PortMappingProtocol::UDP, 6000)) {
+        Err(RemovePortError::RequestError(RequestError::Cancelled)) => (),
+        result => panic!("unexpected result: {:?}", result),
+    }
+    assert_eq!(transport.requests().len(), 1);
+    assert_eq!(gateway.inner.retry_options.cancel, CancelFlag::new(cancel));
+}
+
+#[test]
+fn test_get_external_ip_raw() {
+    use mock::{self, MockTransport};
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let mut gateway = mock::async_gateway(MockTransport::external_ip("0.0.0.0"), &core.handle());
+    match core.run(gateway.get_external_ip()) {
+        Err(GetExternalIpError::RequestError(RequestError::ExternalIpUnavailable)) => (),
+        result => panic!("unexpected result: {:?}", result),
+    }
+    let ip = core.run(gateway.get_external_ip_raw()).unwrap();
+    assert_eq!(ip, IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)));
+
+    gateway.inner.transport = MockTransport::external_ip("::");
+    let ip = core.run(gateway.get_external_ip_raw()).unwrap();
+    assert!(ip.is_unspecified());
+
+    // An empty address can not be returned as is.
+    gateway.inner.transport = MockTransport::external_ip("");
+    match core.run(gateway.get_external_ip_raw()) {
+        Err(GetExternalIpError::RequestError(RequestError::ExternalIpUnavailable)) => (),
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
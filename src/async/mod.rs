@@ -2,5 +2,6 @@ mod gateway;
 mod search;
 
 pub use self::search::{search_gateway, search_gateway_from, search_gateway_timeout,
-                       search_gateway_from_timeout, get_control_url};
-pub use self::gateway::Gateway;
+                       search_gateway_from_timeout, search_gateway_from_opts, get_control_url,
+                       DeviceDescription};
+pub use self::gateway::{parse_external_ip, Gateway};
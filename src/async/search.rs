@@ -4,17 +4,20 @@ use std::str;
 use std::time::Duration;
 
 use futures::{Future, IntoFuture, Stream};
-use futures::future;
+use futures::{future, stream};
 use tokio_core::reactor::Handle;
 use tokio_core::net::UdpSocket;
 use tokio_timer::Timer;
-use hyper;
 use xml::EventReader;
 use xml::reader::XmlEvent;
 
 use async::Gateway;
+use gateway::{ConnectionService, ControlUrl};
 use errors::SearchError;
-use search::{parse_result, SEARCH_REQUEST};
+use messages;
+use soap;
+use search::{format_search_request, gateway_from_description, parse_result, parse_usn,
+             SearchOptions, RETRANSMISSION_INTERVAL};
 
 /// Search gateway, bind to all interfaces and use a timeout of 3 seconds.
 ///
@@ -27,7 +30,7 @@ pub fn search_gateway(handle: &Handle) -> Box<Future<Item = Gateway, Error = Sea
 /// Search gateway, bind to all interfaces and use the given duration for the timeout.
 ///
 /// Bind to all interfaces.
-/// The request will timeout after the given duration.
+/// The request will timeout after the given duration with `SearchError::Timeout`.
 pub fn search_gateway_timeout(
     timeout: Duration,
     handle: &Handle,
@@ -49,76 +52,267 @@ pub fn search_gateway_from(
 /// Search gateway, bind to the given interface and use the given duration for the timeout.
 ///
 /// Bind to the given interface.
-/// The request will timeout after the given duration.
+/// The request will timeout after the given duration with `SearchError::Timeout`.
 pub fn search_gateway_from_timeout(
     ip: Ipv4Addr,
     timeout: Duration,
     handle: &Handle,
 ) -> Box<Future<Item = Gateway, Error = SearchError>> {
-    let addr = SocketAddr::V4(SocketAddrV4::new(ip, 0));
+    let options = SearchOptions {
+        bind_addr: SocketAddrV4::new(ip, 0),
+        timeout: timeout,
+        ..Default::default()
+    };
+    search_gateway_from_opts(options, handle)
+}
+
+/// Search gateway, using the given `SearchOptions`.
+///
+/// The request will timeout after the configured duration with `SearchError::Timeout`. Responses
+/// which can not be parsed, and devices whose description can not be fetched or which are not
+/// gateways, are skipped while waiting for a gateway.
+pub fn search_gateway_from_opts(
+    options: SearchOptions,
+    handle: &Handle,
+) -> Box<Future<Item = Gateway, Error = SearchError>> {
+    let addr = SocketAddr::V4(options.bind_addr);
+    let broadcast_address = SocketAddr::V4(options.broadcast_address);
+    // Each transmission after the first one waits for the retransmission interval before sending
+    // the request of the first search target.
+    let mut requests = Vec::new();
+    for transmission in 0..options.retransmissions + 1 {
+        for (i, target) in options.search_targets.iter().enumerate() {
+            let request = format_search_request(options.broadcast_address, target).into_bytes();
+            requests.push((transmission > 0 && i == 0, request));
+        }
+    }
+    let timer = Timer::default();
+    let description_timer = timer.clone();
+    let search_timer = timer.clone();
+    let request_timeout = options.request_timeout;
+    let gateway_options = options.clone();
     let handle = handle.clone();
     let task = UdpSocket::bind(&addr, &handle)
         .into_future()
-        .and_then(|socket| {
-            socket.send_dgram(
-                SEARCH_REQUEST.as_bytes(),
-                "239.255.255.250:1900".parse().unwrap(),
-            )
+        .and_then(move |socket| {
+            stream::iter_ok::<_, io::Error>(requests).fold(socket, move |socket, (wait, request)| {
+                let delay: Box<Future<Item = (), Error = io::Error>> = if wait {
+                    Box::new(
+                        timer
+                            .sleep(RETRANSMISSION_INTERVAL)
+                            .map_err(io::Error::from),
+                    )
+                } else {
+                    Box::new(future::ok(()))
+                };
+                delay.and_then(move |_| {
+                    socket.send_dgram(request, broadcast_address).map(|(socket, _)| socket)
+                })
+            })
         })
-        .and_then(|(socket, _)| socket.recv_dgram(vec![0u8; 1500]))
         .map_err(|err| SearchError::from(err))
-        .and_then(|(_sock, buf, n, _addr)| {
-            str::from_utf8(&buf[..n])
-                .map_err(|err| SearchError::from(err))
-                .and_then(|text| parse_result(text).ok_or(SearchError::InvalidResponse))
-        })
-        .and_then(move |location| {
-            get_control_url(&location, &handle)
-                .and_then(move |control_url| Ok(Gateway::new(location.0, control_url, handle)))
+        .and_then(move |socket| {
+            // Skip the devices which are not gateways until one answers.
+            future::loop_fn((socket, Vec::new()), move |(socket, mut skipped)| {
+                let handle = handle.clone();
+                let gateway_options = gateway_options.clone();
+                let timer = description_timer.clone();
+                socket
+                    .recv_dgram(vec![0u8; 1500])
+                    .map_err(|err| SearchError::from(err))
+                    .and_then(move |(socket, buf, n, _addr)| -> Box<Future<Item = _, Error = _>> {
+                        let response = str::from_utf8(&buf[..n]).ok().and_then(|text| {
+                            parse_result(text).map(|location| (location, parse_usn(text)))
+                        });
+                        let (location, usn) = match response {
+                            Some(response) => response,
+                            None => {
+                                debug!("skipping invalid search response");
+                                let next = future::Loop::Continue((socket, skipped));
+                                return Box::new(future::ok(next));
+                            }
+                        };
+                        if skipped.contains(&location) {
+                            return Box::new(future::ok(future::Loop::Continue((socket, skipped))));
+                        }
+                        let description = get_control_url(&location);
+                        let future = timer
+                            .timeout(description, request_timeout)
+                            .then(move |result| match result {
+                                Ok(description) => {
+                                    let gateway = gateway_from_description(
+                                        &location,
+                                        usn,
+                                        description,
+                                        &gateway_options,
+                                    );
+                                    Ok(future::Loop::Break(Gateway::from_gateway(gateway, handle)))
+                                }
+                                Err(SearchError::NoConnectionService) => {
+                                    debug!(
+                                        "skipping http://{}{}, not a gateway",
+                                        location.0,
+                                        location.1
+                                    );
+                                    skipped.push(location);
+                                    Ok(future::Loop::Continue((socket, skipped)))
+                                }
+                                Err(err) => {
+                                    debug!("skipping http://{}{}: {}", location.0, location.1, err);
+                                    skipped.push(location);
+                                    Ok(future::Loop::Continue((socket, skipped)))
+                                }
+                            });
+                        Box::new(future)
+                    })
+            })
         });
-    let timeout = Timer::default().timeout(task, timeout);
+    let timeout = search_timer.timeout(task, options.timeout);
     Box::new(timeout)
 }
 
+/// Services of the gateway found in its device description.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceDescription {
+    /// Type of the connection service, either WANIPConnection or WANPPPConnection
+    pub service_type: String,
+    /// Control url of the connection service
+    pub control_url: String,
+    /// Identifier of the connection service, if any
+    pub service_id: Option<String>,
+    /// All the connection services, in the order of the device description
+    pub connection_services: Vec<ConnectionService>,
+    /// Control url of the WANCommonInterfaceConfig service, if any
+    pub common_interface_control_url: Option<String>,
+    /// Control url of the WANIPv6FirewallControl service, if any
+    pub ipv6_firewall_control_url: Option<String>,
+    /// Name of the root device meant to be shown to users, if any
+    pub friendly_name: Option<String>,
+    /// Manufacturer of the root device, if any
+    pub manufacturer: Option<String>,
+    /// Model of the root device, if any
+    pub model_name: Option<String>,
+}
+
+/// Fetch the device description and find the services of the gateway.
 pub fn get_control_url(
     location: &(SocketAddrV4, String),
-    handle: &Handle,
-) -> Box<Future<Item = String, Error = SearchError>> {
-    let client = hyper::Client::new(handle);
-    let uri = match format!("http://{}{}", location.0, location.1).parse() {
-        Ok(uri) => uri,
-        Err(err) => return Box::new(future::err(SearchError::from(err))),
-    };
-    let future = client.get(uri).and_then(|resp| resp.body().concat2()).then(
-        |result| match result {
-            Ok(body) => parse_control_url(body.as_ref()),
+) -> Box<Future<Item = DeviceDescription, Error = SearchError>> {
+    let location_path = location.1.clone();
+    let future = soap::get(&format!("http://{}{}", location.0, location.1)).then(
+        move |result| match result {
+            Ok(body) => parse_control_url(&body[..]).map(|mut description| {
+                description.control_url = resolve_url(&location_path, &description.control_url);
+                for service in &mut description.connection_services {
+                    let url = resolve_url(&location_path, service.control_url.as_str());
+                    service.control_url = ControlUrl::new(&url);
+                }
+                description.common_interface_control_url = description
+                    .common_interface_control_url
+                    .map(|url| resolve_url(&location_path, &url));
+                description.ipv6_firewall_control_url = description
+                    .ipv6_firewall_control_url
+                    .map(|url| resolve_url(&location_path, &url));
+                description
+            }),
             Err(err) => Err(SearchError::from(err)),
         },
     );
     Box::new(future)
 }
 
-fn parse_control_url<R>(resp: R) -> Result<String, SearchError>
+// Find the services in the device description. The highest version of WANIPConnection is
+// preferred for the connection service, but WANPPPConnection is used when it is the only one
+// available.
+fn parse_control_url<R>(resp: R) -> Result<DeviceDescription, SearchError>
 where
     R: io::Read,
 {
-    let parser = EventReader::new(resp);
-    let mut chain = Vec::<String>::with_capacity(4);
+    let (device, services) = try!(parse_services(resp));
+    let find = |service_type: &str| services.iter().find(|s| s.service_type == service_type);
+    let connection = match find(messages::WAN_IP_CONNECTION_2)
+        .or_else(|| find(messages::WAN_IP_CONNECTION))
+        .or_else(|| find(messages::WAN_PPP_CONNECTION))
+    {
+        Some(connection) => connection,
+        None => return Err(SearchError::NoConnectionService),
+    };
+    let connection_services = services
+        .iter()
+        .filter(|s| {
+            s.service_type == messages::WAN_IP_CONNECTION_2
+                || s.service_type == messages::WAN_IP_CONNECTION
+                || s.service_type == messages::WAN_PPP_CONNECTION
+        })
+        .map(|s| ConnectionService {
+            service_type: s.service_type.clone(),
+            service_id: s.service_id.clone(),
+            control_url: ControlUrl::new(&s.control_url),
+        })
+        .collect();
+    Ok(DeviceDescription {
+        service_type: connection.service_type.clone(),
+        control_url: connection.control_url.clone(),
+        service_id: connection.service_id.clone(),
+        connection_services: connection_services,
+        common_interface_control_url: find(messages::WAN_COMMON_INTERFACE_CONFIG)
+            .map(|s| s.control_url.clone()),
+        ipv6_firewall_control_url: find(messages::WAN_IPV6_FIREWALL_CONTROL)
+            .map(|s| s.control_url.clone()),
+        friendly_name: device.friendly_name,
+        manufacturer: device.manufacturer,
+        model_name: device.model_name,
+    })
+}
 
-    struct Service {
-        service_type: String,
-        control_url: String,
+// Resolve a url of the device description relative to the path of the description itself.
+// Absolute urls are kept as is.
+fn resolve_url(location_path: &str, url: &str) -> String {
+    if url.starts_with('/') || url.starts_with("http://") || url.starts_with("https://") {
+        return url.to_string();
     }
+    let base = match location_path.rfind('/') {
+        Some(index) => &location_path[..index + 1],
+        None => "/",
+    };
+    format!("{}{}", base, url)
+}
+
+struct Service {
+    service_type: String,
+    service_id: Option<String>,
+    control_url: String,
+}
+
+#[derive(Default)]
+struct RootDevice {
+    friendly_name: Option<String>,
+    manufacturer: Option<String>,
+    model_name: Option<String>,
+}
+
+// Read the root device and list the services having a control url, in the order of the device
+// description.
+fn parse_services<R>(resp: R) -> Result<(RootDevice, Vec<Service>), SearchError>
+where
+    R: io::Read,
+{
+    let parser = EventReader::new(resp);
+    let mut chain = Vec::<String>::with_capacity(4);
+    let mut device = RootDevice::default();
+    let mut services = Vec::new();
 
     let mut service = Service {
         service_type: "".to_string(),
+        service_id: None,
         control_url: "".to_string(),
     };
 
     for e in parser.into_iter() {
         match try!(e) {
             XmlEvent::StartElement { name, .. } => {
-                chain.push(name.borrow().to_repr());
+                // Compare local names so that prefixed elements are matched as well.
+                chain.push(name.local_name);
                 let tail = if chain.len() >= 3 {
                     chain.iter().skip(chain.len() - 3)
                 } else {
@@ -131,6 +325,7 @@ where
                     .all(|(l, r)| l == r)
                 {
                     service.service_type.clear();
+                    service.service_id = None;
                     service.control_url.clear();
                 }
             }
@@ -146,15 +341,26 @@ where
                     .iter()
                     .zip(tail)
                     .all(|(l, r)| l == r)
-                    && ("urn:schemas-upnp-org:service:WANIPConnection:1" == service.service_type
-                        || "urn:schemas-upnp-org:service:WANPPPConnection:1"
-                            == service.service_type)
                     && service.control_url.len() != 0
                 {
-                    return Ok(service.control_url);
+                    services.push(Service {
+                        service_type: service.service_type.clone(),
+                        service_id: service.service_id.clone(),
+                        control_url: service.control_url.clone(),
+                    });
                 }
             }
             XmlEvent::Characters(text) => {
+                if chain.len() == 3 && chain[0] == "root" && chain[1] == "device" {
+                    let field = match &chain[2][..] {
+                        "friendlyName" => &mut device.friendly_name,
+                        "manufacturer" => &mut device.manufacturer,
+                        "modelName" => &mut device.model_name,
+                        _ => continue,
+                    };
+                    field.get_or_insert_with(String::new).push_str(text.trim());
+                    continue;
+                }
                 let tail = if chain.len() >= 4 {
                     chain.iter().skip(chain.len() - 4)
                 } else {
@@ -168,6 +374,13 @@ where
                 {
                     service.service_type.push_str(&text);
                 }
+                if vec!["device", "serviceList", "service", "serviceId"]
+                    .iter()
+                    .zip(tail.clone())
+                    .all(|(l, r)| l == r)
+                {
+                    service.service_id.get_or_insert_with(String::new).push_str(text.trim());
+                }
                 if vec!["device", "serviceList", "service", "controlURL"]
                     .iter()
                     .zip(tail)
@@ -179,5 +392,189 @@ where
             _ => (),
         }
     }
-    Err(SearchError::InvalidResponse)
+    Ok((device, services))
+}
+
+#[test]
+fn test_parse_control_url_ppp_only() {
+    let xml = b"<?xml version=\"1.0\"?>
+<root xmlns=\"urn:schemas-upnp-org:device-1-0\">
+  <device>
+    <serviceList>
+      <service>
+        <serviceType>urn:schemas-upnp-org:service:WANPPPConnection:1</serviceType>
+        <controlURL>/ctl/PPPConn</controlURL>
+      </service>
+    </serviceList>
+  </device>
+</root>";
+    let description = parse_control_url(&xml[..]).unwrap();
+    assert_eq!(description.service_type, messages::WAN_PPP_CONNECTION);
+    assert_eq!(description.control_url, "/ctl/PPPConn");
+}
+
+#[test]
+fn test_parse_control_url_prefers_ip() {
+    let xml = b"<?xml version=\"1.0\"?>
+<root xmlns=\"urn:schemas-upnp-org:device-1-0\">
+  <device>
+    <serviceList>
+      <service>
+        <serviceType>urn:schemas-upnp-org:service:WANPPPConnection:1</serviceType>
+        <controlURL>/ctl/PPPConn</controlURL>
+      </service>
+      <service>
+        <serviceType>urn:schemas-upnp-org:service:WANCommonInterfaceConfig:1</serviceType>
+        <controlURL>/ctl/CmnIfCfg</controlURL>
+      </service>
+      <service>
+        <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>
+        <controlURL>/ctl/IPConn</controlURL>
+      </service>
+    </serviceList>
+  </device>
+</root>";
+    let description = parse_control_url(&xml[..]).unwrap();
+    assert_eq!(description.service_type, messages::WAN_IP_CONNECTION);
+    assert_eq!(description.control_url, "/ctl/IPConn");
+    assert_eq!(
+        description.common_interface_control_url,
+        Some("/ctl/CmnIfCfg".to_string())
+    );
+}
+
+#[test]
+fn test_parse_control_url_prefers_ip_version_2() {
+    let xml = b"<?xml version=\"1.0\"?>
+<root xmlns=\"urn:schemas-upnp-org:device-2-0\">
+  <device>
+    <serviceList>
+      <service>
+        <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>
+        <controlURL>/ctl/IPConn</controlURL>
+      </service>
+      <service>
+        <serviceType>urn:schemas-upnp-org:service:WANIPConnection:2</serviceType>
+        <controlURL>/ctl/IPConn2</controlURL>
+      </service>
+    </serviceList>
+  </device>
+</root>";
+    let description = parse_control_url(&xml[..]).unwrap();
+    assert_eq!(description.service_type, messages::WAN_IP_CONNECTION_2);
+    assert_eq!(description.control_url, "/ctl/IPConn2");
+}
+
+#[test]
+fn test_parse_control_url_prefixed() {
+    let xml = b"<?xml version=\"1.0\"?>
+<d:root xmlns:d=\"urn:schemas-upnp-org:device-1-0\">
+  <d:device>
+    <d:serviceList>
+      <d:service>
+        <d:serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</d:serviceType>
+        <d:controlURL>/ctl/IPConn</d:controlURL>
+      </d:service>
+    </d:serviceList>
+  </d:device>
+</d:root>";
+    let description = parse_control_url(&xml[..]).unwrap();
+    assert_eq!(description.control_url, "/ctl/IPConn");
+}
+
+#[test]
+fn test_resolve_url() {
+    assert_eq!(resolve_url("/rootDesc.xml", "/ctl/IPConn"), "/ctl/IPConn");
+    assert_eq!(resolve_url("/rootDesc.xml", "ctl/IPConn"), "/ctl/IPConn");
+    assert_eq!(resolve_url("/igd/desc.xml", "ctl/IPConn"), "/igd/ctl/IPConn");
+    assert_eq!(
+        resolve_url("/rootDesc.xml", "http://192.168.1.1:5000/ctl/IPConn"),
+        "http://192.168.1.1:5000/ctl/IPConn"
+    );
+}
+
+#[test]
+fn test_parse_control_url_root_device() {
+    let xml = b"<?xml version=\"1.0\"?>
+<root xmlns=\"urn:schemas-upnp-org:device-1-0\">
+  <device>
+    <friendlyName>Gateway</friendlyName>
+    <manufacturer>Netgear</manufacturer>
+    <modelName>R7000</modelName>
+    <deviceList>
+      <device>
+        <friendlyName>WAN Device</friendlyName>
+        <serviceList>
+          <service>
+            <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>
+            <controlURL>/ctl/IPConn</controlURL>
+          </service>
+        </serviceList>
+      </device>
+    </deviceList>
+  </device>
+</root>";
+    let description = parse_control_url(&xml[..]).unwrap();
+    assert_eq!(description.friendly_name, Some("Gateway".to_string()));
+    assert_eq!(description.manufacturer, Some("Netgear".to_string()));
+    assert_eq!(description.model_name, Some("R7000".to_string()));
+}
+
+#[test]
+fn test_parse_control_url_ipv6_firewall() {
+    let xml = b"<?xml version=\"1.0\"?>
+<root xmlns=\"urn:schemas-upnp-org:device-2-0\">
+  <device>
+    <deviceList>
+      <device>
+        <serviceList>
+          <service>
+            <serviceType>urn:schemas-upnp-org:service:WANIPConnection:2</serviceType>
+            <controlURL>/ctl/IPConn</controlURL>
+          </service>
+          <service>
+            <serviceType>urn:schemas-upnp-org:service:WANIPv6FirewallControl:1</serviceType>
+            <controlURL>/ctl/IP6FCtl</controlURL>
+          </service>
+        </serviceList>
+      </device>
+    </deviceList>
+  </device>
+</root>";
+    let description = parse_control_url(&xml[..]).unwrap();
+    assert_eq!(description.ipv6_firewall_control_url, Some("/ctl/IP6FCtl".to_string()));
+}
+
+#[test]
+fn test_parse_control_url_service_ids() {
+    let xml = b"<?xml version=\"1.0\"?>
+<root xmlns=\"urn:schemas-upnp-org:device-1-0\">
+  <device>
+    <serviceList>
+      <service>
+        <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>
+        <serviceId>urn:upnp-org:serviceId:WANIPConn1</serviceId>
+        <controlURL>/ctl/IPConn1</controlURL>
+      </service>
+      <service>
+        <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>
+        <serviceId>urn:upnp-org:serviceId:WANIPConn2</serviceId>
+        <controlURL>/ctl/IPConn2</controlURL>
+      </service>
+    </serviceList>
+  </device>
+</root>";
+    let description = parse_control_url(&xml[..]).unwrap();
+    assert_eq!(description.service_id, Some("urn:upnp-org:serviceId:WANIPConn1".to_string()));
+    assert_eq!(description.control_url, "/ctl/IPConn1");
+    assert_eq!(description.connection_services.len(), 2);
+
+    let location = ("192.168.1.1:1900".parse().unwrap(), "/rootDesc.xml".to_string());
+    let mut gateway =
+        gateway_from_description(&location, None, description, &SearchOptions::default());
+    assert!(!gateway.select_connection_service("urn:upnp-org:serviceId:WANIPConn3"));
+    assert_eq!(gateway.control_url, "/ctl/IPConn1");
+    assert!(gateway.select_connection_service("urn:upnp-org:serviceId:WANIPConn2"));
+    assert_eq!(gateway.control_url, "/ctl/IPConn2");
+    assert_eq!(gateway.service_id, Some("urn:upnp-org:serviceId:WANIPConn2".to_string()));
 }
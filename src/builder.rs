@@ -0,0 +1,335 @@
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use gateway::Gateway;
+use async::Gateway as AsyncGateway;
+use errors::{AddPortError, GetSpecificPortMappingEntryError};
+use futures::Future;
+use futures::future;
+use tokio_core::reactor::Core;
+use {PortMappingEntry, PortMappingProtocol};
+
+/// Builder of a port mapping, as an alternative to the positional arguments of
+/// `Gateway::add_port`.
+///
+/// The port mapping is a permanent, enabled TCP mapping for any remote host unless configured
+/// otherwise. The internal client and the external port have to be set.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PortMappingBuilder {
+    protocol: PortMappingProtocol,
+    external_port: u16,
+    internal_client: SocketAddrV4,
+    lease_duration: u32,
+    description: String,
+    enabled: bool,
+    remote_host: Option<Ipv4Addr>,
+    permanent_lease_fallback: bool,
+    verify: bool,
+}
+
+impl PortMappingBuilder {
+    /// Create a builder with the default parameters.
+    pub fn new() -> PortMappingBuilder {
+        PortMappingBuilder {
+            protocol: PortMappingProtocol::TCP,
+            external_port: 0,
+            internal_client: SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0),
+            lease_duration: 0,
+            description: String::new(),
+            enabled: true,
+            remote_host: None,
+            permanent_lease_fallback: false,
+            verify: false,
+        }
+    }
+
+    /// Set the protocol of the port mapping.
+    pub fn protocol(mut self, protocol: PortMappingProtocol) -> PortMappingBuilder {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Set the external port of the port mapping.
+    pub fn external_port(mut self, external_port: u16) -> PortMappingBuilder {
+        self.external_port = external_port;
+        self
+    }
+
+    /// Set the local address and port the traffic is forwarded to.
+    pub fn internal_client(mut self, internal_client: SocketAddrV4) -> PortMappingBuilder {
+        self.internal_client = internal_client;
+        self
+    }
+
+    /// Set the lease duration of the port mapping in seconds. A value of 0 is infinite.
+    pub fn lease_duration(mut self, lease_duration: u32) -> PortMappingBuilder {
+        self.lease_duration = lease_duration;
+        self
+    }
+
+    /// Set the description of the port mapping.
+    pub fn description(mut self, description: &str) -> PortMappingBuilder {
+        self.description = description.to_owned();
+        self
+    }
+
+    /// Set whether the port mapping is enabled.
+    pub fn enabled(mut self, enabled: bool) -> PortMappingBuilder {
+        self.enabled = enabled;
+        self
+    }
+
+    /// Restrict the port mapping to the given remote host. `None` accepts any remote host.
+    pub fn remote_host(mut self, remote_host: Option<Ipv4Addr>) -> PortMappingBuilder {
+        self.remote_host = remote_host;
+        self
+    }
+
+    /// Retry with a permanent lease when the gateway only supports permanent leases.
+    ///
+    /// This is disabled by default, such gateways fail with `OnlyPermanentLeasesSupported`.
+    pub fn permanent_lease_fallback(mut self, fallback: bool) -> PortMappingBuilder {
+        self.permanent_lease_fallback = fallback;
+        self
+    }
+
+    /// Read the port mapping back from the gateway once added, in `commit_entry`.
+    ///
+    /// Some gateways silently change the lease duration or drop the port mapping, reading it back
+    /// returns what the gateway actually stored. This is disabled by default.
+    pub fn verify(mut self, verify: bool) -> PortMappingBuilder {
+        self.verify = verify;
+        self
+    }
+
+    /// Build the port mapping, checking that both ports are set.
+    pub fn build(&self) -> Result<PortMappingEntry, AddPortError> {
+        if self.external_port == 0 {
+            return Err(AddPortError::ExternalPortZeroInvalid);
+        }
+        if self.internal_client.port() == 0 {
+            return Err(AddPortError::InternalPortZeroInvalid);
+        }
+        Ok(PortMappingEntry {
+            remote_host: self.remote_host.map(|ip| ip.to_string()).unwrap_or_default(),
+            external_port: self.external_port,
+            protocol: self.protocol,
+            internal_port: self.internal_client.port(),
+            internal_client: self.internal_client.ip().to_string(),
+            enabled: self.enabled,
+            port_mapping_description: self.description.clone(),
+            lease_duration: self.lease_duration,
+        })
+    }
+
+    /// Add the port mapping to the gateway.
+    ///
+    /// The lease duration accepted by the gateway is returned, which is 0 when it fell back to a
+    /// permanent lease.
+    pub fn commit(&self, gateway: &Gateway) -> Result<u32, AddPortError> {
+        let mut core = Core::new().unwrap();
+        let async = AsyncGateway::from_gateway(gateway.clone(), core.handle());
+        core.run(self.commit_async(&async))
+    }
+
+    /// Add the port mapping to the gateway in a tokio compatible way.
+    ///
+    /// The lease duration accepted by the gateway is returned, which is 0 when it fell back to a
+    /// permanent lease.
+    pub fn commit_async(
+        &self,
+        gateway: &AsyncGateway,
+    ) -> Box<Future<Item = u32, Error = AddPortError>> {
+        let mut entry = match self.build() {
+            Ok(entry) => entry,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let lease_duration = entry.lease_duration;
+        let fallback = self.permanent_lease_fallback && lease_duration != 0;
+        let gateway = gateway.clone();
+        let future = gateway
+            .add_entry(&entry)
+            .map(move |()| lease_duration)
+            .or_else(move |err| -> Box<Future<Item = u32, Error = AddPortError>> {
+                match err {
+                    // The gateway only supports permanent leases, try again with one.
+                    AddPortError::OnlyPermanentLeasesSupported if fallback => {
+                        entry.lease_duration = 0;
+                        Box::new(gateway.add_entry(&entry).map(|()| 0))
+                    }
+                    e => Box::new(future::err(e)),
+                }
+            });
+        Box::new(future)
+    }
+
+    /// Add the port mapping to the gateway and return it.
+    ///
+    /// The returned port mapping is the one read back from the gateway when `verify` is set,
+    /// `AddPortError::NotStored` is returned if the gateway does not report it. Otherwise it is
+    /// the port mapping which was sent, with the lease duration the gateway accepted.
+    pub fn commit_entry(&self, gateway: &Gateway) -> Result<PortMappingEntry, AddPortError> {
+        let mut core = Core::new().unwrap();
+        let async = AsyncGateway::from_gateway(gateway.clone(), core.handle());
+        core.run(self.commit_entry_async(&async))
+    }
+
+    /// Add the port mapping to the gateway and return it in a tokio compatible way.
+    ///
+    /// See `commit_entry` for the port mapping which is returned.
+    pub fn commit_entry_async(
+        &self,
+        gateway: &AsyncGateway,
+    ) -> Box<Future<Item = PortMappingEntry, Error = AddPortError>> {
+        let mut entry = match self.build() {
+            Ok(entry) => entry,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let verify = self.verify;
+        let remote_host = self.remote_host;
+        let gateway = gateway.clone();
+        let future = self.commit_async(&gateway).and_then(
+            move |lease_duration| -> Box<Future<Item = PortMappingEntry, Error = AddPortError>> {
+                entry.lease_duration = lease_duration;
+                if !verify {
+                    return Box::new(future::ok(entry));
+                }
+                let future = gateway
+                    .get_specific_port_mapping_entry_with_remote_host(
+                        entry.protocol,
+                        remote_host,
+                        entry.external_port,
+                    )
+                    .map_err(|err| match err {
+                        GetSpecificPortMappingEntryError::ActionNotAuthorized => {
+                            AddPortError::ActionNotAuthorized
+                        }
+                        GetSpecificPortMappingEntryError::RequestError(e) => {
+                            AddPortError::RequestError(e)
+                        }
+                    })
+                    .and_then(|stored| stored.ok_or(AddPortError::NotStored));
+                Box::new(future)
+            },
+        );
+        Box::new(future)
+    }
+}
+
+impl Default for PortMappingBuilder {
+    fn default() -> PortMappingBuilder {
+        PortMappingBuilder::new()
+    }
+}
+
+#[test]
+fn test_build_port_mapping() {
+    let builder = PortMappingBuilder::new()
+        .protocol(PortMappingProtocol::UDP)
+        .internal_client(SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 5000));
+    match builder.build() {
+        Err(AddPortError::ExternalPortZeroInvalid) => (),
+        result => panic!("unexpected result: {:?}", result),
+    }
+    let entry = builder.external_port(6000).description("test").build().unwrap();
+    assert_eq!(entry.remote_host, "");
+    assert_eq!(entry.external_port, 6000);
+    assert_eq!(entry.protocol, PortMappingProtocol::UDP);
+    assert_eq!(entry.internal_port, 5000);
+    assert_eq!(entry.internal_client, "192.168.1.2");
+    assert!(entry.enabled);
+    assert_eq!(entry.port_mapping_description, "test");
+}
+
+#[test]
+fn test_commit_permanent_lease_fallback() {
+    use mock::{self, MockTransport};
+
+    // Reject finite leases.
+    let transport = MockTransport::new(|request, _| {
+        if request.body.contains("<NewLeaseDuration>0</NewLeaseDuration>") {
+            mock::response(request, "")
+        } else {
+            mock::fault(725, "OnlyPermanentLeasesSupported")
+        }
+    });
+    let gateway = mock::gateway(transport.clone());
+    let builder = PortMappingBuilder::new()
+        .external_port(6000)
+        .internal_client(SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 5000))
+        .lease_duration(3600);
+    match builder.commit(&gateway) {
+        Err(AddPortError::OnlyPermanentLeasesSupported) => (),
+        result => panic!("unexpected result: {:?}", result),
+    }
+    assert_eq!(builder.permanent_lease_fallback(true).commit(&gateway).unwrap(), 0);
+    let leases: Vec<bool> = transport
+        .requests()
+        .iter()
+        .map(|request| request.body.contains("<NewLeaseDuration>3600</NewLeaseDuration>"))
+        .collect();
+    assert_eq!(leases, [true, true, false]);
+}
+
+#[test]
+fn test_commit_entry_verify() {
+    use mock::{self, MockTransport};
+
+    // Accept the port mapping but store it with a shorter lease.
+    let transport = MockTransport::new(|request, _| match mock::action_name(request) {
+        "GetSpecificPortMappingEntry" => mock::response(
+            request,
+            "<NewInternalPort>5000</NewInternalPort>
+<NewInternalClient>192.168.1.2</NewInternalClient>
+<NewEnabled>1</NewEnabled>
+<NewPortMappingDescription>test</NewPortMappingDescription>
+<NewLeaseDuration>1800</NewLeaseDuration>",
+        ),
+        _ => mock::response(request, ""),
+    });
+    let gateway = mock::gateway(transport);
+    let builder = PortMappingBuilder::new()
+        .external_port(6000)
+        .internal_client(SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 5000))
+        .lease_duration(3600)
+        .description("test");
+    assert_eq!(builder.commit_entry(&gateway).unwrap().lease_duration, 3600);
+    let entry = builder.verify(true).commit_entry(&gateway).unwrap();
+    assert_eq!(entry.external_port, 6000);
+    assert_eq!(entry.lease_duration, 1800);
+}
+
+#[test]
+fn test_commit_entry_verify_remote_host() {
+    use mock::{self, MockTransport};
+
+    // Only report the port mapping when it is queried with its remote host, like a gateway
+    // answering 714 for the wildcard entry.
+    let transport = MockTransport::new(|request, _| match mock::action_name(request) {
+        "GetSpecificPortMappingEntry"
+            if request.body.contains("<NewRemoteHost>203.0.113.7</NewRemoteHost>") =>
+        {
+            mock::response(
+                request,
+                "<NewInternalPort>5000</NewInternalPort>
+<NewInternalClient>192.168.1.2</NewInternalClient>
+<NewEnabled>1</NewEnabled>
+<NewPortMappingDescription>test</NewPortMappingDescription>
+<NewLeaseDuration>3600</NewLeaseDuration>",
+            )
+        }
+        "GetSpecificPortMappingEntry" => mock::fault(714, "NoSuchEntryInArray"),
+        _ => mock::response(request, ""),
+    });
+    let gateway = mock::gateway(transport);
+    let entry = PortMappingBuilder::new()
+        .remote_host(Some(Ipv4Addr::new(203, 0, 113, 7)))
+        .external_port(6000)
+        .internal_client(SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 5000))
+        .lease_duration(3600)
+        .description("test")
+        .verify(true)
+        .commit_entry(&gateway)
+        .unwrap();
+    assert_eq!(entry.remote_host, "203.0.113.7");
+    assert_eq!(entry.external_port, 6000);
+}
@@ -4,23 +4,123 @@ use std::error;
 use std::str;
 use std;
 
-use hyper;
 use tokio_timer::TimeoutError;
 use xml::reader::Error as XmlError;
 
-use soap;
+#[cfg(feature = "hyper-client")]
+use hyper;
+#[cfg(feature = "reqwest-client")]
+use reqwest;
+
+/// Error of the HTTP client sending the requests and fetching the device descriptions, hyper or
+/// reqwest depending on the features of the crate.
+///
+/// The error of the client is available through `std::error::Error::source`.
+#[derive(Debug)]
+pub struct HttpError(Box<error::Error + Send + Sync>);
+
+impl HttpError {
+    // Wrap an error of the client, or one found in what it sent or received.
+    pub(crate) fn new<E>(err: E) -> HttpError
+    where
+        E: Into<Box<error::Error + Send + Sync>>,
+    {
+        HttpError(err.into())
+    }
+
+    /// The hyper error, when the request was sent with hyper.
+    ///
+    /// `RequestError::HttpError` and `SearchError::HttpError` used to hold it directly.
+    #[cfg(feature = "hyper-client")]
+    pub fn hyper_error(&self) -> Option<&hyper::Error> {
+        self.0.downcast_ref()
+    }
+
+    /// The reqwest error, when the request was sent with reqwest.
+    #[cfg(feature = "reqwest-client")]
+    pub fn reqwest_error(&self) -> Option<&reqwest::Error> {
+        self.0.downcast_ref()
+    }
+}
+
+#[cfg(feature = "hyper-client")]
+impl From<hyper::Error> for HttpError {
+    fn from(err: hyper::Error) -> HttpError {
+        HttpError::new(err)
+    }
+}
+
+#[cfg(feature = "reqwest-client")]
+impl From<reqwest::Error> for HttpError {
+    fn from(err: reqwest::Error) -> HttpError {
+        HttpError::new(err)
+    }
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl error::Error for HttpError {
+    fn cause(&self) -> Option<&error::Error> {
+        Some(&*self.0)
+    }
+
+    fn source(&self) -> Option<&(error::Error + 'static)> {
+        Some(&*self.0)
+    }
+
+    fn description(&self) -> &str {
+        "HTTP error"
+    }
+}
 
 /// Errors that can occur when sending the request to the gateway.
+///
+/// The wrapped HTTP and IO errors are available through `std::error::Error::source`.
 #[derive(Debug)]
 pub enum RequestError {
-    /// Http/Hyper error
-    HttpError(hyper::Error),
+    /// Error of the HTTP client
+    HttpError(HttpError),
     /// IO Error
     IoError(io::Error),
     /// The response from the gateway could not be parsed.
     InvalidResponse(String),
     /// The gateway returned an unhandled error code and description.
     ErrorCode(u16, String),
+    /// The gateway does not expose the service required by the action.
+    UnsupportedAction(String),
+    /// An argument of the request is invalid, the request was not sent.
+    InvalidArgument(String),
+    /// The gateway did not answer within the request timeout.
+    Timeout,
+    /// The gateway answered with a status other than 2xx and no error code. The body and the
+    /// headers of the response are kept.
+    HttpStatus(u16, String, Vec<(String, String)>),
+    /// The gateway answered with an empty or unspecified (`0.0.0.0`) external IP address, which
+    /// usually means that its WAN connection is down. The address has to be found another way.
+    ExternalIpUnavailable,
+    /// The `CancelFlag` of the gateway was set before the request could be sent again.
+    Cancelled,
+}
+
+impl RequestError {
+    /// Value of a header of the response, looked up case-insensitively, when the gateway answered
+    /// with a status other than 2xx.
+    ///
+    /// This helps understanding why a request failed, for instance the `Location` of a redirect or
+    /// the `WWW-Authenticate` challenge of a gateway requiring authentication.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        match *self {
+            RequestError::HttpStatus(_, _, ref headers) => headers
+                .iter()
+                .find(|header| header.0.eq_ignore_ascii_case(name))
+                .map(|header| &header.1[..]),
+            _ => None,
+        }
+    }
 }
 
 /// Errors returned by `Gateway::get_external_ip`
@@ -43,7 +143,63 @@ pub enum RemovePortError {
     RequestError(RequestError),
 }
 
-/// Errors returned by `Gateway::add_any_port` and `Gateway::get_any_address`
+/// Errors returned by `Gateway::set_port_mapping_enabled`
+#[derive(Debug)]
+pub enum SetPortMappingEnabledError {
+    /// The client is not authorized to perform the operation.
+    ActionNotAuthorized,
+    /// No such port mapping.
+    NoSuchPortMapping,
+    /// Updating the port mapping failed.
+    AddPortError(AddPortError),
+    /// Some other error occured performing the request.
+    RequestError(RequestError),
+}
+
+/// Errors returned by `Gateway::remove_ports_by_description`
+#[derive(Debug)]
+pub enum RemovePortsError {
+    /// Listing the existing port mappings failed.
+    GetGenericPortMappingEntryError(GetGenericPortMappingEntryError),
+    /// Removing one of the port mappings failed.
+    RemovePortError(RemovePortError),
+}
+
+/// Errors returned by `map_port` and `map_port_within`
+#[derive(Debug)]
+pub enum MapLocalPortError {
+    /// No gateway could be found.
+    SearchError(SearchError),
+    /// The local IP address used to reach the gateway could not be found.
+    RequestError(RequestError),
+    /// The port mapping could not be added.
+    AddAnyPortError(AddAnyPortError),
+    /// The deadline of `map_port_within` passed before the port mapping was added.
+    Timeout,
+}
+
+/// Errors returned by `Gateway::get_generic_port_mapping_entry`
+#[derive(Debug)]
+pub enum GetGenericPortMappingEntryError {
+    /// The client is not authorized to perform the operation.
+    ActionNotAuthorized,
+    /// The specified array index is out of bounds.
+    SpecifiedArrayIndexInvalid,
+    /// Some other error occured performing the request.
+    RequestError(RequestError),
+}
+
+/// Errors returned by `Gateway::get_specific_port_mapping_entry`
+#[derive(Debug)]
+pub enum GetSpecificPortMappingEntryError {
+    /// The client is not authorized to perform the operation.
+    ActionNotAuthorized,
+    /// Some other error occured performing the request.
+    RequestError(RequestError),
+}
+
+/// Errors returned by `Gateway::add_any_port`, `Gateway::add_any_port_mapping` and
+/// `Gateway::get_any_address`
 #[derive(Debug)]
 pub enum AddAnyPortError {
     /// The client is not authorized to perform the operation.
@@ -86,6 +242,8 @@ pub enum AddPortError {
     OnlyPermanentLeasesSupported,
     /// The description was too long for the gateway to handle.
     DescriptionTooLong,
+    /// The gateway accepted the port mapping but did not report it when reading it back.
+    NotStored,
     /// Some other error occured performing the request.
     RequestError(RequestError),
 }
@@ -96,12 +254,9 @@ impl From<io::Error> for RequestError {
     }
 }
 
-impl From<soap::Error> for RequestError {
-    fn from(err: soap::Error) -> RequestError {
-        match err {
-            soap::Error::HttpError(e) => RequestError::HttpError(e),
-            soap::Error::IoError(e) => RequestError::IoError(e),
-        }
+impl From<HttpError> for RequestError {
+    fn from(err: HttpError) -> RequestError {
+        RequestError::HttpError(err)
     }
 }
 
@@ -114,6 +269,14 @@ impl fmt::Display for RequestError {
             }
             RequestError::IoError(ref e) => write!(f, "IO error. {}", e),
             RequestError::ErrorCode(n, ref e) => write!(f, "Gateway response error {}: {}", n, e),
+            RequestError::UnsupportedAction(ref e) => write!(f, "Unsupported action: {}", e),
+            RequestError::InvalidArgument(ref e) => write!(f, "Invalid argument: {}", e),
+            RequestError::Timeout => write!(f, "The request timed out"),
+            RequestError::HttpStatus(status, ..) => write!(f, "HTTP status {}", status),
+            RequestError::ExternalIpUnavailable => {
+                write!(f, "The gateway does not know its external IP address")
+            }
+            RequestError::Cancelled => write!(f, "The request was cancelled"),
         }
     }
 }
@@ -125,6 +288,27 @@ impl std::error::Error for RequestError {
             RequestError::InvalidResponse(..) => None,
             RequestError::IoError(ref e) => Some(e),
             RequestError::ErrorCode(..) => None,
+            RequestError::UnsupportedAction(..) => None,
+            RequestError::InvalidArgument(..) => None,
+            RequestError::Timeout => None,
+            RequestError::HttpStatus(..) => None,
+            RequestError::ExternalIpUnavailable => None,
+            RequestError::Cancelled => None,
+        }
+    }
+
+    fn source(&self) -> Option<&(std::error::Error + 'static)> {
+        match *self {
+            RequestError::HttpError(ref e) => Some(e),
+            RequestError::InvalidResponse(..) => None,
+            RequestError::IoError(ref e) => Some(e),
+            RequestError::ErrorCode(..) => None,
+            RequestError::UnsupportedAction(..) => None,
+            RequestError::InvalidArgument(..) => None,
+            RequestError::Timeout => None,
+            RequestError::HttpStatus(..) => None,
+            RequestError::ExternalIpUnavailable => None,
+            RequestError::Cancelled => None,
         }
     }
 
@@ -134,6 +318,12 @@ impl std::error::Error for RequestError {
             RequestError::InvalidResponse(..) => "Invalid response",
             RequestError::IoError(..) => "IO error",
             RequestError::ErrorCode(_, ref e) => &e[..],
+            RequestError::UnsupportedAction(..) => "Unsupported action",
+            RequestError::InvalidArgument(..) => "Invalid argument",
+            RequestError::Timeout => "The request timed out",
+            RequestError::HttpStatus(..) => "HTTP status",
+            RequestError::ExternalIpUnavailable => "External IP address unavailable",
+            RequestError::Cancelled => "The request was cancelled",
         }
     }
 }
@@ -198,6 +388,200 @@ impl std::error::Error for RemovePortError {
     }
 }
 
+impl fmt::Display for SetPortMappingEnabledError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SetPortMappingEnabledError::ActionNotAuthorized => {
+                write!(f, "The client is not authorized to update the port mapping")
+            }
+            SetPortMappingEnabledError::NoSuchPortMapping => write!(f, "The port was not mapped"),
+            SetPortMappingEnabledError::AddPortError(ref e) => {
+                write!(f, "Updating the port mapping failed. {}", e)
+            }
+            SetPortMappingEnabledError::RequestError(ref e) => write!(f, "Request error. {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SetPortMappingEnabledError {
+    fn cause(&self) -> Option<&std::error::Error> {
+        match *self {
+            SetPortMappingEnabledError::AddPortError(ref e) => Some(e),
+            _ => None,
+        }
+    }
+
+    fn description(&self) -> &str {
+        match *self {
+            SetPortMappingEnabledError::ActionNotAuthorized => {
+                "The client is not authorized to update the port mapping"
+            }
+            SetPortMappingEnabledError::NoSuchPortMapping => "The port was not mapped",
+            SetPortMappingEnabledError::AddPortError(..) => "Updating the port mapping failed",
+            SetPortMappingEnabledError::RequestError(..) => "Request error",
+        }
+    }
+}
+
+impl fmt::Display for RemovePortsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RemovePortsError::GetGenericPortMappingEntryError(ref e) => {
+                write!(f, "Listing the port mappings failed. {}", e)
+            }
+            RemovePortsError::RemovePortError(ref e) => {
+                write!(f, "Removing a port mapping failed. {}", e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RemovePortsError {
+    fn cause(&self) -> Option<&std::error::Error> {
+        match *self {
+            RemovePortsError::GetGenericPortMappingEntryError(ref e) => Some(e),
+            RemovePortsError::RemovePortError(ref e) => Some(e),
+        }
+    }
+
+    fn description(&self) -> &str {
+        match *self {
+            RemovePortsError::GetGenericPortMappingEntryError(..) => {
+                "Listing the port mappings failed"
+            }
+            RemovePortsError::RemovePortError(..) => "Removing a port mapping failed",
+        }
+    }
+}
+
+impl From<SearchError> for MapLocalPortError {
+    fn from(err: SearchError) -> MapLocalPortError {
+        MapLocalPortError::SearchError(err)
+    }
+}
+
+impl From<RequestError> for MapLocalPortError {
+    fn from(err: RequestError) -> MapLocalPortError {
+        MapLocalPortError::RequestError(err)
+    }
+}
+
+impl From<AddAnyPortError> for MapLocalPortError {
+    fn from(err: AddAnyPortError) -> MapLocalPortError {
+        MapLocalPortError::AddAnyPortError(err)
+    }
+}
+
+impl<F> From<TimeoutError<F>> for MapLocalPortError {
+    fn from(err: TimeoutError<F>) -> MapLocalPortError {
+        match err {
+            TimeoutError::TimedOut(..) => MapLocalPortError::Timeout,
+            TimeoutError::Timer(_, e) => {
+                MapLocalPortError::RequestError(RequestError::IoError(io::Error::from(e)))
+            }
+        }
+    }
+}
+
+impl fmt::Display for MapLocalPortError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MapLocalPortError::SearchError(ref e) => {
+                write!(f, "Searching the gateway failed. {}", e)
+            }
+            MapLocalPortError::RequestError(ref e) => {
+                write!(f, "Finding the local IP address failed. {}", e)
+            }
+            MapLocalPortError::AddAnyPortError(ref e) => {
+                write!(f, "Adding the port mapping failed. {}", e)
+            }
+            MapLocalPortError::Timeout => write!(f, "The deadline passed"),
+        }
+    }
+}
+
+impl std::error::Error for MapLocalPortError {
+    fn cause(&self) -> Option<&std::error::Error> {
+        match *self {
+            MapLocalPortError::SearchError(ref e) => Some(e),
+            MapLocalPortError::RequestError(ref e) => Some(e),
+            MapLocalPortError::AddAnyPortError(ref e) => Some(e),
+            MapLocalPortError::Timeout => None,
+        }
+    }
+
+    fn description(&self) -> &str {
+        match *self {
+            MapLocalPortError::SearchError(..) => "Searching the gateway failed",
+            MapLocalPortError::RequestError(..) => "Finding the local IP address failed",
+            MapLocalPortError::AddAnyPortError(..) => "Adding the port mapping failed",
+            MapLocalPortError::Timeout => "The deadline passed",
+        }
+    }
+}
+
+impl fmt::Display for GetGenericPortMappingEntryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GetGenericPortMappingEntryError::ActionNotAuthorized => {
+                write!(f, "The client is not authorized to look up port mappings")
+            }
+            GetGenericPortMappingEntryError::SpecifiedArrayIndexInvalid => {
+                write!(f, "The provided index into the port mapping list is invalid")
+            }
+            GetGenericPortMappingEntryError::RequestError(ref e) => {
+                write!(f, "Request error. {}", e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for GetGenericPortMappingEntryError {
+    fn cause(&self) -> Option<&std::error::Error> {
+        None
+    }
+
+    fn description(&self) -> &str {
+        match *self {
+            GetGenericPortMappingEntryError::ActionNotAuthorized => {
+                "The client is not authorized to look up port mappings"
+            }
+            GetGenericPortMappingEntryError::SpecifiedArrayIndexInvalid => {
+                "The provided index into the port mapping list is invalid"
+            }
+            GetGenericPortMappingEntryError::RequestError(..) => "Request error",
+        }
+    }
+}
+
+impl fmt::Display for GetSpecificPortMappingEntryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GetSpecificPortMappingEntryError::ActionNotAuthorized => {
+                write!(f, "The client is not authorized to look up port mappings")
+            }
+            GetSpecificPortMappingEntryError::RequestError(ref e) => {
+                write!(f, "Request error. {}", e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for GetSpecificPortMappingEntryError {
+    fn cause(&self) -> Option<&std::error::Error> {
+        None
+    }
+
+    fn description(&self) -> &str {
+        match *self {
+            GetSpecificPortMappingEntryError::ActionNotAuthorized => {
+                "The client is not authorized to look up port mappings"
+            }
+            GetSpecificPortMappingEntryError::RequestError(..) => "Request error",
+        }
+    }
+}
+
 impl fmt::Display for AddAnyPortError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
@@ -292,6 +676,9 @@ impl fmt::Display for AddPortError {
             AddPortError::DescriptionTooLong => {
                 write!(f, "The description was too long for the gateway to handle.")
             }
+            AddPortError::NotStored => {
+                write!(f, "The gateway did not store the port mapping it accepted.")
+            }
             AddPortError::RequestError(ref e) => write!(f, "Request error. {}", e),
         }
     }
@@ -321,16 +708,20 @@ impl std::error::Error for AddPortError {
             AddPortError::DescriptionTooLong => {
                 "The description was too long for the gateway to handle."
             }
+            AddPortError::NotStored => "The gateway did not store the port mapping it accepted.",
             AddPortError::RequestError(..) => "Request error",
         }
     }
 }
 
 /// Errors than can occur while trying to find the gateway.
+///
+/// These are only returned by the search functions, the requests sent to a gateway once it was
+/// found fail with `RequestError` or one of the errors specific to the action.
 #[derive(Debug)]
 pub enum SearchError {
-    /// Http/Hyper error
-    HttpError(hyper::Error),
+    /// Error of the HTTP client fetching the device description
+    HttpError(HttpError),
     /// Unable to process the response
     InvalidResponse,
     /// IO Error
@@ -339,10 +730,33 @@ pub enum SearchError {
     Utf8Error(str::Utf8Error),
     /// XML processing error
     XmlError(XmlError),
+    /// No gateway answered before the timeout expired, returned by the functions searching for one
+    /// gateway when none is found
+    Timeout,
+    /// No device answered the search at all, returned when searching for all the gateways
+    NoResponse,
+    /// The device description has no WANIPConnection or WANPPPConnection service, the device is
+    /// not a gateway. The search functions skip such devices.
+    NoConnectionService,
+    /// The `CancelFlag` of the `SearchOptions` was set during the search.
+    Cancelled,
 }
 
-impl From<hyper::Error> for SearchError {
-    fn from(err: hyper::Error) -> SearchError {
+impl SearchError {
+    /// Whether the search failed because no gateway answered, as opposed to a network error or
+    /// an invalid response.
+    ///
+    /// This is the case for both `Timeout` and `NoResponse`.
+    pub fn is_no_gateway(&self) -> bool {
+        match *self {
+            SearchError::Timeout | SearchError::NoResponse => true,
+            _ => false,
+        }
+    }
+}
+
+impl From<HttpError> for SearchError {
+    fn from(err: HttpError) -> SearchError {
         SearchError::HttpError(err)
     }
 }
@@ -365,15 +779,21 @@ impl From<XmlError> for SearchError {
     }
 }
 
-impl From<hyper::error::UriError> for SearchError {
-    fn from(err: hyper::error::UriError) -> SearchError {
-        SearchError::HttpError(hyper::Error::from(err))
+impl<F> From<TimeoutError<F>> for RequestError {
+    fn from(err: TimeoutError<F>) -> RequestError {
+        match err {
+            TimeoutError::TimedOut(..) => RequestError::Timeout,
+            TimeoutError::Timer(_, e) => RequestError::IoError(io::Error::from(e)),
+        }
     }
 }
 
 impl<F> From<TimeoutError<F>> for SearchError {
-    fn from(_err: TimeoutError<F>) -> SearchError {
-        SearchError::IoError(io::Error::new(io::ErrorKind::TimedOut, "search timed out"))
+    fn from(err: TimeoutError<F>) -> SearchError {
+        match err {
+            TimeoutError::TimedOut(..) => SearchError::Timeout,
+            TimeoutError::Timer(_, e) => SearchError::IoError(io::Error::from(e)),
+        }
     }
 }
 
@@ -385,6 +805,12 @@ impl fmt::Display for SearchError {
             SearchError::IoError(ref e) => write!(f, "IO error: {}", e),
             SearchError::Utf8Error(ref e) => write!(f, "UTF-8 error: {}", e),
             SearchError::XmlError(ref e) => write!(f, "XML error: {}", e),
+            SearchError::Timeout => write!(f, "The search timed out"),
+            SearchError::NoResponse => write!(f, "No device answered the search"),
+            SearchError::NoConnectionService => {
+                write!(f, "The device has no WAN connection service")
+            }
+            SearchError::Cancelled => write!(f, "The search was cancelled"),
         }
     }
 }
@@ -397,6 +823,10 @@ impl error::Error for SearchError {
             SearchError::IoError(ref e) => Some(e),
             SearchError::Utf8Error(ref e) => Some(e),
             SearchError::XmlError(ref e) => Some(e),
+            SearchError::Timeout => None,
+            SearchError::NoResponse => None,
+            SearchError::NoConnectionService => None,
+            SearchError::Cancelled => None,
         }
     }
 
@@ -407,6 +837,263 @@ impl error::Error for SearchError {
             SearchError::IoError(..) => "IO error",
             SearchError::Utf8Error(..) => "UTF-8 error",
             SearchError::XmlError(..) => "XML error",
+            SearchError::Timeout => "The search timed out",
+            SearchError::NoResponse => "No device answered the search",
+            SearchError::NoConnectionService => "The device has no WAN connection service",
+            SearchError::Cancelled => "The search was cancelled",
+        }
+    }
+}
+
+/// Errors returned by `Gateway::add_pinhole`
+#[derive(Debug)]
+pub enum AddPinholeError {
+    /// The client is not authorized to perform the operation.
+    ActionNotAuthorized,
+    /// The gateway can not open any more pinholes.
+    PinholeSpaceExhausted,
+    /// The firewall of the gateway is disabled, so no pinhole is needed.
+    FirewallDisabled,
+    /// The gateway does not allow opening pinholes for inbound traffic.
+    InboundPinholeNotAllowed,
+    /// Some other error occured performing the request.
+    RequestError(RequestError),
+}
+
+/// Errors returned by `Gateway::delete_pinhole` and `Gateway::get_pinhole_packets`
+#[derive(Debug)]
+pub enum PinholeError {
+    /// The client is not authorized to perform the operation.
+    ActionNotAuthorized,
+    /// The gateway has no pinhole with this unique id.
+    NoSuchEntry,
+    /// Some other error occured performing the request.
+    RequestError(RequestError),
+}
+
+impl From<RequestError> for AddPinholeError {
+    fn from(err: RequestError) -> AddPinholeError {
+        AddPinholeError::RequestError(err)
+    }
+}
+
+impl From<RequestError> for PinholeError {
+    fn from(err: RequestError) -> PinholeError {
+        PinholeError::RequestError(err)
+    }
+}
+
+impl fmt::Display for AddPinholeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AddPinholeError::ActionNotAuthorized => {
+                write!(f, "The client is not authorized to open the pinhole.")
+            }
+            AddPinholeError::PinholeSpaceExhausted => {
+                write!(f, "The gateway can not open any more pinholes.")
+            }
+            AddPinholeError::FirewallDisabled => {
+                write!(f, "The firewall of the gateway is disabled.")
+            }
+            AddPinholeError::InboundPinholeNotAllowed => {
+                write!(f, "The gateway does not allow inbound pinholes.")
+            }
+            AddPinholeError::RequestError(ref e) => write!(f, "Request error. {}", e),
+        }
+    }
+}
+
+impl std::error::Error for AddPinholeError {
+    fn cause(&self) -> Option<&std::error::Error> {
+        match *self {
+            AddPinholeError::RequestError(ref e) => Some(e),
+            _ => None,
+        }
+    }
+
+    fn description(&self) -> &str {
+        match *self {
+            AddPinholeError::ActionNotAuthorized => {
+                "The client is not authorized to open the pinhole."
+            }
+            AddPinholeError::PinholeSpaceExhausted => "The gateway can not open any more pinholes.",
+            AddPinholeError::FirewallDisabled => "The firewall of the gateway is disabled.",
+            AddPinholeError::InboundPinholeNotAllowed => {
+                "The gateway does not allow inbound pinholes."
+            }
+            AddPinholeError::RequestError(..) => "Request error",
+        }
+    }
+}
+
+impl fmt::Display for PinholeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PinholeError::ActionNotAuthorized => {
+                write!(f, "The client is not authorized to access the pinhole.")
+            }
+            PinholeError::NoSuchEntry => write!(f, "The gateway has no such pinhole."),
+            PinholeError::RequestError(ref e) => write!(f, "Request error. {}", e),
         }
     }
 }
+
+impl std::error::Error for PinholeError {
+    fn cause(&self) -> Option<&std::error::Error> {
+        match *self {
+            PinholeError::RequestError(ref e) => Some(e),
+            _ => None,
+        }
+    }
+
+    fn description(&self) -> &str {
+        match *self {
+            PinholeError::ActionNotAuthorized => {
+                "The client is not authorized to access the pinhole."
+            }
+            PinholeError::NoSuchEntry => "The gateway has no such pinhole.",
+            PinholeError::RequestError(..) => "Request error",
+        }
+    }
+}
+
+/// Error returned when parsing a `PortMappingProtocol` from a string which is neither `TCP` nor
+/// `UDP`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseProtocolError(pub String);
+
+impl fmt::Display for ParseProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unknown protocol: {}", self.0)
+    }
+}
+
+impl error::Error for ParseProtocolError {
+    fn description(&self) -> &str {
+        "Unknown protocol"
+    }
+}
+
+/// Errors returned by the NAT-PMP client
+#[cfg(feature = "natpmp")]
+#[derive(Debug)]
+pub enum NatPmpError {
+    /// IO Error
+    IoError(io::Error),
+    /// The gateway did not answer before the timeout expired
+    Timeout,
+    /// The response from the gateway could not be parsed.
+    InvalidResponse,
+    /// The gateway returned a non zero result code.
+    ResultCode(u16),
+}
+
+/// Errors returned by `natpmp::map_port`
+#[cfg(feature = "natpmp")]
+#[derive(Debug)]
+pub enum MapPortError {
+    /// The port mapping failed on the UPnP IGD gateway.
+    AddAnyPortError(AddAnyPortError),
+    /// No UPnP IGD gateway was found and the port mapping failed with NAT-PMP.
+    NatPmpError(NatPmpError),
+}
+
+#[cfg(feature = "natpmp")]
+impl From<io::Error> for NatPmpError {
+    fn from(err: io::Error) -> NatPmpError {
+        NatPmpError::IoError(err)
+    }
+}
+
+#[cfg(feature = "natpmp")]
+impl From<AddAnyPortError> for MapPortError {
+    fn from(err: AddAnyPortError) -> MapPortError {
+        MapPortError::AddAnyPortError(err)
+    }
+}
+
+#[cfg(feature = "natpmp")]
+impl From<NatPmpError> for MapPortError {
+    fn from(err: NatPmpError) -> MapPortError {
+        MapPortError::NatPmpError(err)
+    }
+}
+
+#[cfg(feature = "natpmp")]
+impl fmt::Display for NatPmpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NatPmpError::IoError(ref e) => write!(f, "IO error: {}", e),
+            NatPmpError::Timeout => write!(f, "The request timed out"),
+            NatPmpError::InvalidResponse => write!(f, "Invalid response"),
+            NatPmpError::ResultCode(code) => write!(f, "Gateway result code {}", code),
+        }
+    }
+}
+
+#[cfg(feature = "natpmp")]
+impl error::Error for NatPmpError {
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            NatPmpError::IoError(ref e) => Some(e),
+            _ => None,
+        }
+    }
+
+    fn description(&self) -> &str {
+        match *self {
+            NatPmpError::IoError(..) => "IO error",
+            NatPmpError::Timeout => "The request timed out",
+            NatPmpError::InvalidResponse => "Invalid response",
+            NatPmpError::ResultCode(..) => "Gateway result code",
+        }
+    }
+}
+
+#[cfg(feature = "natpmp")]
+impl fmt::Display for MapPortError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MapPortError::AddAnyPortError(ref e) => write!(f, "UPnP IGD error. {}", e),
+            MapPortError::NatPmpError(ref e) => write!(f, "NAT-PMP error. {}", e),
+        }
+    }
+}
+
+#[cfg(feature = "natpmp")]
+impl error::Error for MapPortError {
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            MapPortError::AddAnyPortError(ref e) => Some(e),
+            MapPortError::NatPmpError(ref e) => Some(e),
+        }
+    }
+
+    fn description(&self) -> &str {
+        match *self {
+            MapPortError::AddAnyPortError(..) => "UPnP IGD error",
+            MapPortError::NatPmpError(..) => "NAT-PMP error",
+        }
+    }
+}
+
+#[test]
+fn test_request_error_source() {
+    use std::error::Error;
+
+    let err = RequestError::from(io::Error::new(io::ErrorKind::Other, "connection reset"));
+    assert_eq!(err.to_string(), "IO error. connection reset");
+    assert!(err.source().is_some());
+    assert!(RequestError::InvalidResponse("".to_string()).source().is_none());
+}
+
+#[test]
+#[cfg(feature = "hyper-client")]
+fn test_http_error_hyper_error() {
+    let err = HttpError::from(hyper::Error::Incomplete);
+    match err.hyper_error() {
+        Some(&hyper::Error::Incomplete) => (),
+        other => panic!("unexpected error: {:?}", other),
+    }
+    assert!(HttpError::new("invalid url").hyper_error().is_none());
+}
@@ -1,28 +1,721 @@
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, UdpSocket};
 use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use tokio_core::reactor::Core;
 
-use errors::{AddAnyPortError, AddPortError, GetExternalIpError, RemovePortError};
-use PortMappingProtocol;
+use errors::{AddAnyPortError, AddPinholeError, AddPortError, GetExternalIpError,
+             GetGenericPortMappingEntryError, GetSpecificPortMappingEntryError, PinholeError,
+             RemovePortError, RemovePortsError, RequestError, SetPortMappingEnabledError};
+use {ConnectionTypeInfo, NatRsipStatus, PortMappingEntry, PortMappingProtocol, Reachability,
+     StatusInfo};
 use async::Gateway as AsyncGateway;
+use soap;
+use soap::{Credentials, HttpOptions, SoapTransport};
+use messages;
+
+/// Delay between two status queries of `Gateway::wait_for_connection`.
+pub const CONNECTION_POLL_INTERVAL: Duration = Duration::from_millis(500);
 
 /// This structure represents a gateway found by the search functions.
-#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+///
+/// Use `Gateway::new` to create one by hand rather than a struct literal, fields are added as
+/// the crate supports more of the device description and of the request settings.
+///
+/// Gateways are compared and hashed by their address and control url only, so that the same
+/// device found twice compares equal.
+///
+/// Every call runs on its own event loop, so connections are only reused by the requests of a
+/// single call, such as `port_mappings`. `tokio::Gateway` reuses them across calls, along with
+/// its event loop, which makes it the better choice for polling the gateway on a tight schedule.
+#[derive(Clone, Debug)]
 pub struct Gateway {
     /// Socket address of the gateway
     pub addr: SocketAddrV4,
     /// Control url of the device
-    pub control_url: String,
+    pub control_url: ControlUrl,
+    /// Type of the connection service exposed by the device, either WANIPConnection or
+    /// WANPPPConnection. The highest version of WANIPConnection is used when several are
+    /// available.
+    pub service_type: String,
+    /// Identifier of the connection service in the device description (`serviceId`), if any
+    pub service_id: Option<String>,
+    /// All the connection services found in the device description, in its order. Gateways with
+    /// several WAN connections expose one per uplink, see `select_connection_service`.
+    pub connection_services: Vec<ConnectionService>,
+    /// Control url of the WANCommonInterfaceConfig service, which holds the traffic counters
+    pub common_interface_control_url: Option<String>,
+    /// Control url of the WANIPv6FirewallControl service, which opens IPv6 pinholes. `None` when
+    /// the gateway does not advertise it, in which case only IPv4 port mappings are possible.
+    pub ipv6_firewall_control_url: Option<String>,
+    /// Name of the gateway meant to be shown to users, if found in its device description
+    pub friendly_name: Option<String>,
+    /// Manufacturer of the gateway, if found in its device description
+    pub manufacturer: Option<String>,
+    /// Model of the gateway, if found in its device description
+    pub model_name: Option<String>,
+    /// Url of the device description, from the `LOCATION` header of the search response
+    pub location: Option<String>,
+    /// Unique service name of the gateway, from the `USN` header of the search response
+    pub usn: Option<String>,
+    /// Policy used to retry requests failing because of a network error
+    pub retry_options: RetryOptions,
+    /// Time to wait for the gateway to answer a request, 10 seconds by default
+    pub request_timeout: Duration,
+    /// User-Agent sent with every request, `rust-igd/<version> UPnP/1.1` by default
+    pub user_agent: String,
+    /// HTTP settings of the requests: credentials, local address, Content-Type and XML
+    /// declaration
+    pub http_options: HttpOptions,
+    /// Transport used to send the requests, `HttpTransport` by default or `ReqwestTransport`
+    /// without the `hyper-client` feature
+    pub transport: Arc<SoapTransport + Send + Sync>,
+    /// Last external IP address returned by `get_external_ip_cached`, shared by the clones of the
+    /// gateway until one selects another connection service
+    pub external_ip_cache: ExternalIpCache,
+    /// Quoting style of the SOAPAction header accepted by the gateway, shared by its clones
+    pub action_quoting: ActionQuoting,
+    /// Callback receiving the `RequestMetrics` of every request, none by default
+    pub metrics_callback: MetricsCallback,
+}
+
+/// WANIPConnection or WANPPPConnection service found in the device description of a gateway.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ConnectionService {
+    /// Type of the service
+    pub service_type: String,
+    /// Identifier of the service (`serviceId`), which tells apart several instances of the same
+    /// type
+    pub service_id: Option<String>,
+    /// Control url of the service
+    pub control_url: ControlUrl,
+}
+
+/// Control url of a service of a gateway, either a path on the gateway or an absolute url.
+///
+/// Paths are normalized to start with a `/`, absolute urls, which some gateways give on another
+/// port than their device description, are kept whole.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct ControlUrl(String);
+
+impl ControlUrl {
+    /// Create a control url from the text found in a device description.
+    pub fn new(url: &str) -> ControlUrl {
+        let url = url.trim();
+        if url.is_empty() || url.starts_with('/') || is_absolute_url(url) {
+            ControlUrl(url.to_string())
+        } else {
+            ControlUrl(format!("/{}", url))
+        }
+    }
+
+    /// The normalized control url.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Whether the control url is empty, as with gateways built from a partial description.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The full url of the service on the gateway at the given address.
+    pub fn resolve(&self, addr: SocketAddrV4) -> String {
+        if is_absolute_url(&self.0) {
+            self.0.clone()
+        } else {
+            format!("http://{}{}", addr, self.0)
+        }
+    }
+}
+
+fn is_absolute_url(url: &str) -> bool {
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+impl fmt::Display for ControlUrl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl<'a> From<&'a str> for ControlUrl {
+    fn from(url: &'a str) -> ControlUrl {
+        ControlUrl::new(url)
+    }
+}
+
+impl From<String> for ControlUrl {
+    fn from(url: String) -> ControlUrl {
+        ControlUrl::new(&url)
+    }
+}
+
+impl<'a> PartialEq<&'a str> for ControlUrl {
+    fn eq(&self, other: &&'a str) -> bool {
+        self.0 == *other
+    }
+}
+
+/// Cache of the external IP address of a gateway, see `Gateway::get_external_ip_cached`.
+#[derive(Clone, Debug, Default)]
+pub struct ExternalIpCache(Arc<Mutex<Option<(Instant, Ipv4Addr)>>>);
+
+impl ExternalIpCache {
+    // Get the cached address if it is younger than the ttl.
+    pub(crate) fn get(&self, ttl: Duration) -> Option<Ipv4Addr> {
+        match *self.0.lock().unwrap() {
+            Some((time, ip)) if time.elapsed() < ttl => Some(ip),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn set(&self, ip: Ipv4Addr) {
+        *self.0.lock().unwrap() = Some((Instant::now(), ip));
+    }
+
+    pub(crate) fn clear(&self) {
+        *self.0.lock().unwrap() = None;
+    }
+}
+
+/// Quoting style of the SOAPAction header accepted by a gateway.
+///
+/// The UPnP specification requires the action to be quoted, but some firmwares only accept it
+/// unquoted. When a gateway rejects its first action, it is sent once more with the other style,
+/// and the style which got an answer is kept for the following requests. Until then it is unknown.
+#[derive(Clone, Debug, Default)]
+pub struct ActionQuoting(Arc<Mutex<Option<bool>>>);
+
+impl ActionQuoting {
+    /// Whether the gateway accepts quoted actions, `None` until it answered one.
+    pub fn get(&self) -> Option<bool> {
+        *self.0.lock().unwrap()
+    }
+
+    pub(crate) fn set(&self, quoted: bool) {
+        *self.0.lock().unwrap() = Some(quoted);
+    }
+}
+
+/// Timing and size of a request sent to a gateway, passed to its `MetricsCallback`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RequestMetrics {
+    /// The SOAPAction of the request
+    pub action: String,
+    /// Time from the first attempt to the response or the final error, including the retry delays
+    pub duration: Duration,
+    /// Number of times the request was sent, more than 1 when it was retried
+    pub attempts: u32,
+    /// Length of the text of the response, 0 when none was received
+    pub response_bytes: usize,
+}
+
+/// Callback receiving the `RequestMetrics` of the requests sent to a gateway, see
+/// `Gateway::set_metrics_callback`. The default callback does nothing.
+#[derive(Clone, Default)]
+pub struct MetricsCallback(Option<Callback>);
+
+type Callback = Arc<Fn(&RequestMetrics) + Send + Sync>;
+
+impl MetricsCallback {
+    /// Create a callback from the given function.
+    pub fn new<F>(callback: F) -> MetricsCallback
+    where
+        F: Fn(&RequestMetrics) + Send + Sync + 'static,
+    {
+        MetricsCallback(Some(Arc::new(callback)))
+    }
+
+    pub(crate) fn record(&self, metrics: &RequestMetrics) {
+        if let Some(ref callback) = self.0 {
+            callback(metrics);
+        }
+    }
+}
+
+impl fmt::Debug for MetricsCallback {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(_) => write!(f, "MetricsCallback(Some(..))"),
+            None => write!(f, "MetricsCallback(None)"),
+        }
+    }
+}
+
+/// Retry policy of the requests sent to a gateway
+///
+/// Requests failing at the connection level are sent again, waiting `base_delay` before the first
+/// retry and doubling the delay after each attempt. Error codes returned by the gateway are never
+/// retried. The default options make 3 attempts with a base delay of 250ms.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RetryOptions {
+    /// Total number of attempts, including the first one
+    pub attempts: u32,
+    /// Delay before the first retry
+    pub base_delay: Duration,
+    /// Flag stopping the retries once set, none by default
+    pub cancel: CancelFlag,
+}
+
+impl RetryOptions {
+    /// Do not retry failed requests.
+    pub fn none() -> RetryOptions {
+        RetryOptions {
+            attempts: 1,
+            base_delay: Duration::from_millis(0),
+            cancel: CancelFlag::default(),
+        }
+    }
+
+    // Delays to wait before each retry.
+    pub(crate) fn delays(&self) -> Vec<Duration> {
+        (0..self.attempts.saturating_sub(1))
+            .map(|n| self.base_delay * 1u32.checked_shl(n).unwrap_or(u32::max_value()))
+            .collect()
+    }
+}
+
+impl Default for RetryOptions {
+    fn default() -> RetryOptions {
+        RetryOptions {
+            attempts: 3,
+            base_delay: Duration::from_millis(250),
+            cancel: CancelFlag::default(),
+        }
+    }
+}
+
+/// Flag shared with another thread to cancel a search or the requests sent to a gateway, for
+/// instance when the user closes the dialog waiting for them.
+///
+/// The flag is checked between the iterations of the search and of the retries, the call then
+/// returns `SearchError::Cancelled` or `RequestError::Cancelled`. A request already sent is not
+/// interrupted. Two flags are equal when they share the same `AtomicBool`.
+#[derive(Clone, Debug, Default)]
+pub struct CancelFlag(Option<Arc<AtomicBool>>);
+
+impl CancelFlag {
+    /// Use the given flag, cancelling once it is set to `true`.
+    pub fn new(flag: Arc<AtomicBool>) -> CancelFlag {
+        CancelFlag(Some(flag))
+    }
+
+    /// Whether the flag was set.
+    pub fn is_cancelled(&self) -> bool {
+        match self.0 {
+            Some(ref flag) => flag.load(Ordering::SeqCst),
+            None => false,
+        }
+    }
+
+    // Whether there is a flag which can be set, in which case blocking waits are kept short.
+    pub(crate) fn is_set(&self) -> bool {
+        self.0.is_some()
+    }
+}
+
+impl PartialEq for CancelFlag {
+    fn eq(&self, other: &CancelFlag) -> bool {
+        match (self.0.as_ref(), other.0.as_ref()) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for CancelFlag {}
+
+impl Hash for CancelFlag {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.as_ref().map(|flag| &**flag as *const AtomicBool).hash(state)
+    }
 }
 
 impl Gateway {
+    /// Create a gateway from the address and the control url of its connection service.
+    ///
+    /// The gateway is assumed to expose the WANIPConnection service, the other fields get their
+    /// default values. Gateways are usually found by the search functions instead, which fill
+    /// them from the device description.
+    pub fn new(addr: SocketAddrV4, control_url: &str) -> Gateway {
+        Gateway {
+            addr: addr,
+            control_url: ControlUrl::new(control_url),
+            service_type: messages::WAN_IP_CONNECTION.to_string(),
+            service_id: None,
+            connection_services: Vec::new(),
+            common_interface_control_url: None,
+            ipv6_firewall_control_url: None,
+            friendly_name: None,
+            manufacturer: None,
+            model_name: None,
+            location: None,
+            usn: None,
+            retry_options: RetryOptions::default(),
+            request_timeout: Duration::from_secs(10),
+            user_agent: soap::DEFAULT_USER_AGENT.to_string(),
+            http_options: HttpOptions::default(),
+            transport: soap::default_transport(),
+            external_ip_cache: Default::default(),
+            action_quoting: Default::default(),
+            metrics_callback: Default::default(),
+        }
+    }
+
+    fn to_async(&self, core: &Core) -> AsyncGateway {
+        AsyncGateway::from_gateway(self.clone(), core.handle())
+    }
+
+    /// Authenticate with the given credentials when the gateway requires HTTP digest
+    /// authentication on its control url.
+    pub fn set_credentials(&mut self, username: &str, password: &str) {
+        self.http_options.credentials = Some(Credentials {
+            username: username.to_owned(),
+            password: password.to_owned(),
+        });
+    }
+
+    /// Send the requests from the given local address, so that they leave through the interface
+    /// facing the gateway on hosts with several interfaces.
+    ///
+    /// Gateways found by a search bound to an address already send their requests from it.
+    pub fn set_local_addr(&mut self, local_addr: Ipv4Addr) {
+        self.http_options.local_addr = Some(local_addr);
+    }
+
+    /// Stop retrying the requests once the given flag is set, returning `RequestError::Cancelled`.
+    ///
+    /// The flag is checked before each attempt, a request already sent still waits for its
+    /// answer or for the request timeout.
+    pub fn set_cancel_flag(&mut self, flag: Arc<AtomicBool>) {
+        self.retry_options.cancel = CancelFlag::new(flag);
+    }
+
+    /// Call the given function with the `RequestMetrics` of every request sent to the gateway,
+    /// for instance to watch its latency.
+    pub fn set_metrics_callback<F>(&mut self, callback: F)
+    where
+        F: Fn(&RequestMetrics) + Send + Sync + 'static,
+    {
+        self.metrics_callback = MetricsCallback::new(callback);
+    }
+
+    /// Send the requests to the connection service with the given `serviceId`.
+    ///
+    /// This selects the uplink used by gateways with several WAN connections. `false` is returned,
+    /// and the gateway left unchanged, when no such service is in `connection_services`. When
+    /// the service changes, the gateway stops sharing the cached external IP address with its
+    /// clones, since the other uplink has its own address.
+    pub fn select_connection_service(&mut self, service_id: &str) -> bool {
+        let service = match self.connection_services
+            .iter()
+            .find(|service| service.service_id.as_ref().map(|id| &id[..]) == Some(service_id))
+        {
+            Some(service) => service.clone(),
+            None => return false,
+        };
+        if service.service_type != self.service_type || service.control_url != self.control_url {
+            self.external_ip_cache = ExternalIpCache::default();
+        }
+        self.service_type = service.service_type;
+        self.service_id = service.service_id;
+        self.control_url = service.control_url;
+        true
+    }
+
+    /// Whether the gateway exposes a WANIPConnection or WANPPPConnection service with a control
+    /// url, which is required to add port mappings.
+    ///
+    /// Gateways found by the search always do, but gateways built by hand or from a partial
+    /// device description may not.
+    pub fn supports_port_mapping(&self) -> bool {
+        let connection_services = [
+            messages::WAN_IP_CONNECTION,
+            messages::WAN_IP_CONNECTION_2,
+            messages::WAN_PPP_CONNECTION,
+        ];
+        connection_services.contains(&self.service_type.as_str()) && !self.control_url.is_empty()
+    }
+
+    /// Get the local IP address the system uses to reach the gateway.
+    ///
+    /// This is the address the traffic of a port mapping should usually be sent to. No packet is
+    /// sent, the address is chosen by the routing table of the system.
+    pub fn get_local_ip(&self) -> Result<Ipv4Addr, RequestError> {
+        let socket = try!(UdpSocket::bind("0.0.0.0:0"));
+        try!(socket.connect(self.addr));
+        match try!(socket.local_addr()) {
+            SocketAddr::V4(addr) => Ok(*addr.ip()),
+            SocketAddr::V6(addr) => Err(RequestError::InvalidResponse(addr.to_string())),
+        }
+    }
+
     /// Get the external IP address of the gateway.
     pub fn get_external_ip(&self) -> Result<Ipv4Addr, GetExternalIpError> {
         let mut core = Core::new().unwrap();
-        let async = AsyncGateway::new(self.addr, self.control_url.clone(), core.handle());
+        let async = self.to_async(&core);
         core.run(async.get_external_ip())
     }
 
+    /// Get the external IP address of the gateway, reusing the last address if it was fetched
+    /// less than `ttl` ago.
+    pub fn get_external_ip_cached(&self, ttl: Duration) -> Result<Ipv4Addr, GetExternalIpError> {
+        let mut core = Core::new().unwrap();
+        let async = self.to_async(&core);
+        core.run(async.get_external_ip_cached(ttl))
+    }
+
+    /// Forget the cached external IP address, so that the next call to `get_external_ip_cached`
+    /// fetches it from the gateway.
+    pub fn invalidate_external_ip(&self) {
+        self.external_ip_cache.clear();
+    }
+
+    /// Get the external IP address of the gateway, accepting both IPv4 and IPv6 addresses.
+    pub fn get_external_ip_any(&self) -> Result<IpAddr, GetExternalIpError> {
+        let mut core = Core::new().unwrap();
+        let async = self.to_async(&core);
+        core.run(async.get_external_ip_any())
+    }
+
+    /// Get the external IP address of the gateway, returning the unspecified address (`0.0.0.0`
+    /// or `::`) as is instead of `RequestError::ExternalIpUnavailable`.
+    pub fn get_external_ip_raw(&self) -> Result<IpAddr, GetExternalIpError> {
+        let mut core = Core::new().unwrap();
+        let async = self.to_async(&core);
+        core.run(async.get_external_ip_raw())
+    }
+
+    /// Check whether the mapped external port can be reached from the internet.
+    ///
+    /// A successful port mapping does not mean traffic arrives, the gateway may sit behind
+    /// another NAT or a carrier-grade NAT, which is detected from its external IP address. When
+    /// that address is public and the protocol is TCP, a connection to it is attempted within the
+    /// timeout, which only succeeds if something listens on the local side of the mapping and the
+    /// gateway supports hairpinning.
+    pub fn verify_reachable(
+        &self,
+        external_port: u16,
+        protocol: PortMappingProtocol,
+        timeout: Duration,
+    ) -> Result<Reachability, GetExternalIpError> {
+        let mut core = Core::new().unwrap();
+        let async = self.to_async(&core);
+        core.run(async.verify_reachable(external_port, protocol, timeout))
+    }
+
+    /// Send an arbitrary action to the connection service of the gateway.
+    ///
+    /// The body must be a complete SOAP envelope. The text of the response is returned as is so
+    /// that data not handled by this crate can be read. Responses with a status other than 2xx,
+    /// such as SOAP faults, are returned as `RequestError::HttpStatus`, which holds their text
+    /// and headers.
+    pub fn send_raw(&self, action: &str, body: &str) -> Result<String, RequestError> {
+        let mut core = Core::new().unwrap();
+        let async = self.to_async(&core);
+        core.run(async.send_raw(action, body))
+    }
+
+    /// Same as `send_raw`, reading the response into the given buffer instead of a new string.
+    ///
+    /// The buffer is cleared first. Reusing it from one call to the next saves an allocation per
+    /// request, which helps applications polling the gateway on a tight schedule. On error the
+    /// buffer is left empty.
+    pub fn send_raw_into(
+        &self,
+        action: &str,
+        body: &str,
+        buffer: &mut String,
+    ) -> Result<(), RequestError> {
+        let mut core = Core::new().unwrap();
+        let async = self.to_async(&core);
+        let request = async.send_raw_into(action, body, mem::replace(buffer, String::new()));
+        *buffer = try!(core.run(request));
+        Ok(())
+    }
+
+    /// Get the status of the WAN connection of the gateway.
+    ///
+    /// Port mappings are unlikely to work unless the connection is `Connected`.
+    pub fn get_status_info(&self) -> Result<StatusInfo, RequestError> {
+        let mut core = Core::new().unwrap();
+        let async = self.to_async(&core);
+        core.run(async.get_status_info())
+    }
+
+    /// Wait for the WAN connection of the gateway to be established.
+    ///
+    /// The status of the connection is polled every `CONNECTION_POLL_INTERVAL` until it is
+    /// `Connected`. `RequestError::Timeout` is returned when it is still not connected once the
+    /// timeout expired.
+    pub fn wait_for_connection(&self, timeout: Duration) -> Result<(), RequestError> {
+        let mut core = Core::new().unwrap();
+        let async = self.to_async(&core);
+        core.run(async.wait_for_connection(timeout))
+    }
+
+    /// Terminate the WAN connection of the gateway.
+    ///
+    /// Faults such as 606 (action not authorized) or 720 (cannot process request) are returned
+    /// as `RequestError::ErrorCode`.
+    pub fn force_termination(&self) -> Result<(), RequestError> {
+        let mut core = Core::new().unwrap();
+        let async = self.to_async(&core);
+        core.run(async.force_termination())
+    }
+
+    /// Establish the WAN connection of the gateway.
+    ///
+    /// Faults such as 606 (action not authorized) or 720 (cannot process request) are returned
+    /// as `RequestError::ErrorCode`.
+    pub fn request_connection(&self) -> Result<(), RequestError> {
+        let mut core = Core::new().unwrap();
+        let async = self.to_async(&core);
+        core.run(async.request_connection())
+    }
+
+    /// Get the delay between the warning of the users and the termination of the WAN connection.
+    ///
+    /// Faults such as 606 (action not authorized) are returned as `RequestError::ErrorCode`.
+    pub fn get_warn_disconnect_delay(&self) -> Result<Duration, RequestError> {
+        let mut core = Core::new().unwrap();
+        let async = self.to_async(&core);
+        core.run(async.get_warn_disconnect_delay())
+    }
+
+    /// Set the delay between the warning of the users and the termination of the WAN connection.
+    ///
+    /// The delay is sent in whole seconds. Faults such as 606 (action not authorized) are returned
+    /// as `RequestError::ErrorCode`.
+    pub fn set_warn_disconnect_delay(&self, delay: Duration) -> Result<(), RequestError> {
+        let mut core = Core::new().unwrap();
+        let async = self.to_async(&core);
+        core.run(async.set_warn_disconnect_delay(delay))
+    }
+
+    /// Get the idle time after which the gateway terminates the WAN connection.
+    ///
+    /// Faults such as 606 (action not authorized) are returned as `RequestError::ErrorCode`.
+    pub fn get_auto_disconnect_time(&self) -> Result<Duration, RequestError> {
+        let mut core = Core::new().unwrap();
+        let async = self.to_async(&core);
+        core.run(async.get_auto_disconnect_time())
+    }
+
+    /// Set the idle time after which the gateway terminates the WAN connection.
+    ///
+    /// The time is sent in whole seconds. Faults such as 606 (action not authorized) are returned
+    /// as `RequestError::ErrorCode`.
+    pub fn set_auto_disconnect_time(&self, time: Duration) -> Result<(), RequestError> {
+        let mut core = Core::new().unwrap();
+        let async = self.to_async(&core);
+        core.run(async.set_auto_disconnect_time(time))
+    }
+
+    /// Get the connection type of the gateway.
+    ///
+    /// Port mappings can not work when the connection type is `IP_Bridged`.
+    pub fn get_connection_type_info(&self) -> Result<ConnectionTypeInfo, RequestError> {
+        let mut core = Core::new().unwrap();
+        let async = self.to_async(&core);
+        core.run(async.get_connection_type_info())
+    }
+
+    /// Get whether NAT and RSIP are available on the gateway.
+    ///
+    /// Port mappings have no effect when NAT is disabled.
+    pub fn get_nat_rsip_status(&self) -> Result<NatRsipStatus, RequestError> {
+        let mut core = Core::new().unwrap();
+        let async = self.to_async(&core);
+        core.run(async.get_nat_rsip_status())
+    }
+
+    /// Open a pinhole in the IPv6 firewall of the gateway.
+    ///
+    /// The remote host and port restrict the traffic allowed through the pinhole, `None` and 0
+    /// are wildcards. The lease_time parameter is in seconds. The unique id the gateway assigned
+    /// to the pinhole is returned, it is needed to delete it.
+    ///
+    /// This requires the WANIPv6FirewallControl service, see `ipv6_firewall_control_url`.
+    pub fn add_pinhole(
+        &self,
+        remote_host: Option<Ipv6Addr>,
+        remote_port: u16,
+        internal_client: Ipv6Addr,
+        internal_port: u16,
+        protocol: PortMappingProtocol,
+        lease_time: u32,
+    ) -> Result<u16, AddPinholeError> {
+        let mut core = Core::new().unwrap();
+        let async = self.to_async(&core);
+        core.run(async.add_pinhole(
+            remote_host,
+            remote_port,
+            internal_client,
+            internal_port,
+            protocol,
+            lease_time,
+        ))
+    }
+
+    /// Delete a pinhole opened by `add_pinhole`.
+    pub fn delete_pinhole(&self, unique_id: u16) -> Result<(), PinholeError> {
+        let mut core = Core::new().unwrap();
+        let async = self.to_async(&core);
+        core.run(async.delete_pinhole(unique_id))
+    }
+
+    /// Get the number of packets which went through a pinhole.
+    pub fn get_pinhole_packets(&self, unique_id: u16) -> Result<u32, PinholeError> {
+        let mut core = Core::new().unwrap();
+        let async = self.to_async(&core);
+        core.run(async.get_pinhole_packets(unique_id))
+    }
+
+    /// Get the total number of bytes sent on the WAN connection.
+    ///
+    /// Many gateways use a 32 bits counter which wraps around at 2^32. The raw value reported by
+    /// the gateway is returned.
+    pub fn get_total_bytes_sent(&self) -> Result<u64, RequestError> {
+        let mut core = Core::new().unwrap();
+        let async = self.to_async(&core);
+        core.run(async.get_total_bytes_sent())
+    }
+
+    /// Get the total number of bytes received on the WAN connection.
+    ///
+    /// Many gateways use a 32 bits counter which wraps around at 2^32. The raw value reported by
+    /// the gateway is returned.
+    pub fn get_total_bytes_received(&self) -> Result<u64, RequestError> {
+        let mut core = Core::new().unwrap();
+        let async = self.to_async(&core);
+        core.run(async.get_total_bytes_received())
+    }
+
+    /// Get the total number of packets sent on the WAN connection.
+    ///
+    /// The counter wraps around at 2^32. The raw value reported by the gateway is returned.
+    pub fn get_total_packets_sent(&self) -> Result<u32, RequestError> {
+        let mut core = Core::new().unwrap();
+        let async = self.to_async(&core);
+        core.run(async.get_total_packets_sent())
+    }
+
+    /// Get the total number of packets received on the WAN connection.
+    ///
+    /// The counter wraps around at 2^32. The raw value reported by the gateway is returned.
+    pub fn get_total_packets_received(&self) -> Result<u32, RequestError> {
+        let mut core = Core::new().unwrap();
+        let async = self.to_async(&core);
+        core.run(async.get_total_packets_received())
+    }
+
     /// Get an external socket address with our external ip and any port. This is a convenience
     /// function that calls `get_external_ip` followed by `add_any_port`
     ///
@@ -40,12 +733,18 @@ impl Gateway {
         description: &str,
     ) -> Result<SocketAddrV4, AddAnyPortError> {
         let mut core = Core::new().unwrap();
-        let async = AsyncGateway::new(self.addr, self.control_url.clone(), core.handle());
+        let async = self.to_async(&core);
         core.run(async.get_any_address(protocol, local_addr, lease_duration, description))
     }
 
     /// Add a port mapping.with any external port.
     ///
+    /// When the gateway supports version 2 of WANIPConnection, the gateway picks the port itself.
+    /// Otherwise the external port matching the local port is tried first. If it is already in
+    /// use, random ports of the dynamic range, 49152 to 65535, are tried instead, widening to
+    /// 1024 to 65535 after repeated conflicts. A short randomized delay growing with each
+    /// conflict is waited between the attempts.
+    ///
     /// The local_addr is the address where the traffic is sent to.
     /// The lease_duration parameter is in seconds. A value of 0 is infinite.
     ///
@@ -60,14 +759,83 @@ impl Gateway {
         description: &str,
     ) -> Result<u16, AddAnyPortError> {
         let mut core = Core::new().unwrap();
-        let async = AsyncGateway::new(self.addr, self.control_url.clone(), core.handle());
+        let async = self.to_async(&core);
         core.run(async.add_any_port(protocol, local_addr, lease_duration, description))
     }
 
-    /// Add a port mapping.
+    /// Add a port mapping with any external port, like `add_any_port`, also returning the number
+    /// of ports tried before one could be mapped.
+    pub fn add_any_port_with_attempts(
+        &self,
+        protocol: PortMappingProtocol,
+        local_addr: SocketAddrV4,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<(u16, u32), AddAnyPortError> {
+        let mut core = Core::new().unwrap();
+        let async = self.to_async(&core);
+        core.run(async.add_any_port_with_attempts(
+            protocol,
+            local_addr,
+            lease_duration,
+            description,
+        ))
+    }
+
+    /// Add a port mapping with the AddAnyPortMapping action, letting the gateway pick the
+    /// external port.
+    ///
+    /// The external_port is only a hint, the gateway returns the port it actually reserved. The
+    /// action only exists in version 2 of WANIPConnection, `UnsupportedAction` is returned by
+    /// older gateways. Use `add_any_port` to fall back to AddPortMapping transparently.
     ///
     /// The local_addr is the address where the traffic is sent to.
     /// The lease_duration parameter is in seconds. A value of 0 is infinite.
+    pub fn add_any_port_mapping(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        local_addr: SocketAddrV4,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<u16, AddAnyPortError> {
+        let mut core = Core::new().unwrap();
+        let async = self.to_async(&core);
+        core.run(async.add_any_port_mapping(
+            protocol,
+            external_port,
+            local_addr,
+            lease_duration,
+            description,
+        ))
+    }
+
+    /// Add a port mapping which is removed when the returned handle is dropped.
+    ///
+    /// The parameters are the same as for `add_port`. Removing the mapping is best effort, a
+    /// failure is only logged. Call `PortMappingHandle::forget` to keep the mapping.
+    pub fn add_port_lease(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        local_addr: SocketAddrV4,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<PortMappingHandle, AddPortError> {
+        try!(self.add_port(protocol, external_port, local_addr, lease_duration, description));
+        Ok(PortMappingHandle {
+            gateway: Some(self.clone()),
+            protocol: protocol,
+            external_port: external_port,
+        })
+    }
+
+    /// Add a port mapping.
+    ///
+    /// The local_addr is the address where the traffic is sent to, its port is the internal port
+    /// of the port mapping. See `add_port_mapping` to name both ports separately.
+    /// The lease_duration parameter is in seconds. A value of 0 is infinite.
+    /// The port mapping added is returned, for instance to renew or remove it later.
     pub fn add_port(
         &self,
         protocol: PortMappingProtocol,
@@ -75,10 +843,102 @@ impl Gateway {
         local_addr: SocketAddrV4,
         lease_duration: u32,
         description: &str,
+    ) -> Result<PortMappingEntry, AddPortError> {
+        self.add_port_mapping(
+            protocol,
+            external_port,
+            *local_addr.ip(),
+            local_addr.port(),
+            lease_duration,
+            description,
+        )
+    }
+
+    /// Add a port mapping, naming the external and the internal port separately.
+    ///
+    /// The traffic received on the external port is sent to the internal port of the
+    /// internal_client. The lease_duration parameter is in seconds. A value of 0 is infinite.
+    /// The port mapping added is returned.
+    pub fn add_port_mapping(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        internal_client: Ipv4Addr,
+        internal_port: u16,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<PortMappingEntry, AddPortError> {
+        self.add_port_with_remote_host(
+            protocol,
+            None,
+            external_port,
+            SocketAddrV4::new(internal_client, internal_port),
+            lease_duration,
+            description,
+        )
+    }
+
+    /// Add a port mapping to the given port of this host.
+    ///
+    /// The traffic is sent to the local IP address the system uses to reach the gateway, see
+    /// `get_local_ip`, and the same port is used externally. The local address mapped is returned.
+    /// The lease_duration parameter is in seconds. A value of 0 is infinite.
+    pub fn add_port_for_local(
+        &self,
+        protocol: PortMappingProtocol,
+        local_port: u16,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<SocketAddrV4, AddPortError> {
+        let local_ip = try!(self.get_local_ip().map_err(AddPortError::RequestError));
+        let local_addr = SocketAddrV4::new(local_ip, local_port);
+        try!(self.add_port(protocol, local_port, local_addr, lease_duration, description));
+        Ok(local_addr)
+    }
+
+    /// Add a port mapping which only accepts traffic from the given remote host.
+    ///
+    /// A remote_host of `None` accepts traffic from any host, like `add_port`.
+    /// The local_addr is the address where the traffic is sent to.
+    /// The lease_duration parameter is in seconds. A value of 0 is infinite.
+    /// The port mapping added is returned.
+    pub fn add_port_with_remote_host(
+        &self,
+        protocol: PortMappingProtocol,
+        remote_host: Option<Ipv4Addr>,
+        external_port: u16,
+        local_addr: SocketAddrV4,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<PortMappingEntry, AddPortError> {
+        let mut core = Core::new().unwrap();
+        let async = self.to_async(&core);
+        core.run(async.add_port_with_remote_host(
+            protocol,
+            remote_host,
+            external_port,
+            local_addr,
+            lease_duration,
+            description,
+        ))
+    }
+
+    /// Renew an existing port mapping.
+    ///
+    /// The port mapping is added again with the given lease duration, which restarts its lease.
+    /// The parameters must match the ones the port mapping was added with. To renew a port
+    /// mapping from a `PortMappingEntry`, use `add_entry`.
+    pub fn renew_port(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        local_addr: SocketAddrV4,
+        lease_duration: u32,
+        description: &str,
     ) -> Result<(), AddPortError> {
         let mut core = Core::new().unwrap();
-        let async = AsyncGateway::new(self.addr, self.control_url.clone(), core.handle());
-        core.run(async.add_port(
+        let async = self.to_async(&core);
+        core.run(async.renew_port(
             protocol,
             external_port,
             local_addr,
@@ -87,20 +947,550 @@ impl Gateway {
         ))
     }
 
+    /// Add a port mapping described by the given entry.
+    ///
+    /// This gives full control over the port mapping, for instance to add a disabled port
+    /// mapping. Adding an entry for an existing external port and protocol updates the existing
+    /// port mapping.
+    pub fn add_entry(&self, entry: &PortMappingEntry) -> Result<(), AddPortError> {
+        let mut core = Core::new().unwrap();
+        let async = self.to_async(&core);
+        core.run(async.add_entry(entry))
+    }
+
+    /// Add several port mappings, removing the ones already added when one of them fails.
+    ///
+    /// The port mappings are added in order. On the first failure, the port mappings added
+    /// before are removed on a best effort basis and the error is returned. The added port
+    /// mappings are returned on success.
+    pub fn add_ports(
+        &self,
+        entries: &[PortMappingEntry],
+    ) -> Result<Vec<PortMappingEntry>, AddPortError> {
+        let mut core = Core::new().unwrap();
+        let async = self.to_async(&core);
+        core.run(async.add_ports(entries))
+    }
+
+    /// Add the same port mapping for both TCP and UDP.
+    ///
+    /// The parameters are the same as for `add_port`. If the UDP port mapping fails, the TCP one
+    /// already added is removed on a best effort basis and the error is returned. The TCP and
+    /// the UDP port mappings added are returned on success.
+    pub fn add_port_both_protocols(
+        &self,
+        external_port: u16,
+        local_addr: SocketAddrV4,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<(PortMappingEntry, PortMappingEntry), AddPortError> {
+        let mut core = Core::new().unwrap();
+        let async = self.to_async(&core);
+        core.run(async.add_port_both_protocols(
+            external_port,
+            local_addr,
+            lease_duration,
+            description,
+        ))
+    }
+
+    /// Enable or disable an existing port mapping.
+    ///
+    /// The port mapping is updated in place with the same parameters, it is not removed.
+    pub fn set_port_mapping_enabled(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        enabled: bool,
+    ) -> Result<(), SetPortMappingEnabledError> {
+        let mut core = Core::new().unwrap();
+        let async = self.to_async(&core);
+        core.run(async.set_port_mapping_enabled(protocol, external_port, enabled))
+    }
+
     /// Remove a port mapping.
     pub fn remove_port(
         &self,
         protocol: PortMappingProtocol,
         external_port: u16,
     ) -> Result<(), RemovePortError> {
+        self.remove_port_with_remote_host(protocol, None, external_port)
+    }
+
+    /// Remove a port mapping if it exists.
+    ///
+    /// `false` is returned when the gateway has no such port mapping instead of failing with
+    /// `NoSuchPortMapping`, which makes cleanup code idempotent.
+    pub fn remove_port_if_exists(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+    ) -> Result<bool, RemovePortError> {
+        let mut core = Core::new().unwrap();
+        let async = self.to_async(&core);
+        core.run(async.remove_port_if_exists(protocol, external_port))
+    }
+
+    /// Remove a port mapping which was added for the given remote host.
+    ///
+    /// The remote_host must match the one the port mapping was added with.
+    pub fn remove_port_with_remote_host(
+        &self,
+        protocol: PortMappingProtocol,
+        remote_host: Option<Ipv4Addr>,
+        external_port: u16,
+    ) -> Result<(), RemovePortError> {
+        let mut core = Core::new().unwrap();
+        let async = self.to_async(&core);
+        core.run(async.remove_port_with_remote_host(protocol, remote_host, external_port))
+    }
+
+    /// Remove the port mapping described by the given entry, such as one returned by
+    /// `iter_port_mappings`.
+    ///
+    /// The remote host of the entry is used, so that port mappings added for a specific remote
+    /// host are removed as well. The remote host is sent as listed by the gateway, even when it is
+    /// not an IPv4 address, so only an empty remote host is the wildcard.
+    pub fn remove_entry(&self, entry: &PortMappingEntry) -> Result<(), RemovePortError> {
+        let mut core = Core::new().unwrap();
+        let async = self.to_async(&core);
+        core.run(async.remove_entry(entry))
+    }
+
+    /// Remove all the port mappings whose description starts with the given prefix.
+    ///
+    /// This is useful to clean up the port mappings left behind by a previous run of an
+    /// application. The protocol and external port of the removed port mappings are returned.
+    pub fn remove_ports_by_description(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<(PortMappingProtocol, u16)>, RemovePortsError> {
+        let mut core = Core::new().unwrap();
+        let async = self.to_async(&core);
+        core.run(async.remove_ports_by_description(prefix))
+    }
+
+    /// Get one of the existing port mappings by its index.
+    ///
+    /// Port mappings are indexed from 0. `SpecifiedArrayIndexInvalid` is returned once the index
+    /// goes past the last port mapping.
+    pub fn get_generic_port_mapping_entry(
+        &self,
+        index: u32,
+    ) -> Result<PortMappingEntry, GetGenericPortMappingEntryError> {
+        let mut core = Core::new().unwrap();
+        let async = self.to_async(&core);
+        core.run(async.get_generic_port_mapping_entry(index))
+    }
+
+    /// Iterate over all the existing port mappings.
+    ///
+    /// The port mappings are requested lazily, one by one, until the gateway reports that the
+    /// index is past the last port mapping. The iteration stops after the first error.
+    ///
+    /// The requests are all sent from the same event loop, so that they reuse the connection
+    /// to the gateway when it keeps it alive.
+    pub fn iter_port_mappings<'a>(&'a self) -> PortMappings<'a> {
+        let core = Core::new().unwrap();
+        let gateway = self.to_async(&core);
+        PortMappings {
+            core: core,
+            gateway: gateway,
+            index: Some(0),
+            marker: PhantomData,
+        }
+    }
+
+    /// Count the existing port mappings.
+    ///
+    /// The port mappings are requested one by one like with `iter_port_mappings`, but only their
+    /// number is kept.
+    pub fn count_port_mappings(&self) -> Result<u32, GetGenericPortMappingEntryError> {
+        let mut count = 0;
+        for entry in self.iter_port_mappings() {
+            try!(entry);
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Get the port mapping of the given external port, if any.
+    ///
+    /// `None` is returned when the gateway has no such port mapping.
+    pub fn get_specific_port_mapping_entry(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+    ) -> Result<Option<PortMappingEntry>, GetSpecificPortMappingEntryError> {
+        let mut core = Core::new().unwrap();
+        let async = self.to_async(&core);
+        core.run(async.get_specific_port_mapping_entry(protocol, external_port))
+    }
+
+    /// Get the port mapping of the given external port which was added for the given remote
+    /// host, if any.
+    ///
+    /// The remote_host must match the one the port mapping was added with.
+    pub fn get_specific_port_mapping_entry_with_remote_host(
+        &self,
+        protocol: PortMappingProtocol,
+        remote_host: Option<Ipv4Addr>,
+        external_port: u16,
+    ) -> Result<Option<PortMappingEntry>, GetSpecificPortMappingEntryError> {
+        let mut core = Core::new().unwrap();
+        let async = self.to_async(&core);
+        core.run(async.get_specific_port_mapping_entry_with_remote_host(
+            protocol,
+            remote_host,
+            external_port,
+        ))
+    }
+
+    /// Get the lease duration left for the port mapping of the given external port, if any.
+    ///
+    /// Most gateways count the lease down and report the time left, but some report the lease
+    /// duration the port mapping was added with. A duration of 0 is a permanent lease. `None` is
+    /// returned when the gateway has no such port mapping.
+    pub fn get_remaining_lease(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+    ) -> Result<Option<Duration>, GetSpecificPortMappingEntryError> {
         let mut core = Core::new().unwrap();
-        let async = AsyncGateway::new(self.addr, self.control_url.clone(), core.handle());
-        core.run(async.remove_port(protocol, external_port))
+        let async = self.to_async(&core);
+        core.run(async.get_remaining_lease(protocol, external_port))
+    }
+}
+
+/// Iterator over the port mappings of a gateway, returned by `Gateway::iter_port_mappings`.
+#[derive(Debug)]
+pub struct PortMappings<'a> {
+    core: Core,
+    gateway: AsyncGateway,
+    index: Option<u32>,
+    marker: PhantomData<&'a Gateway>,
+}
+
+impl<'a> Iterator for PortMappings<'a> {
+    type Item = Result<PortMappingEntry, GetGenericPortMappingEntryError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let index = self.index?;
+        match self.core.run(self.gateway.get_generic_port_mapping_entry(index)) {
+            Ok(entry) => {
+                self.index = Some(index + 1);
+                Some(Ok(entry))
+            }
+            Err(GetGenericPortMappingEntryError::SpecifiedArrayIndexInvalid) => {
+                self.index = None;
+                None
+            }
+            Err(e) => {
+                self.index = None;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Port mapping added by `Gateway::add_port_lease`, removed from the gateway when dropped.
+#[derive(Debug)]
+pub struct PortMappingHandle {
+    gateway: Option<Gateway>,
+    protocol: PortMappingProtocol,
+    external_port: u16,
+}
+
+impl PortMappingHandle {
+    /// The protocol of the port mapping
+    pub fn protocol(&self) -> PortMappingProtocol {
+        self.protocol
+    }
+
+    /// The external port of the port mapping
+    pub fn external_port(&self) -> u16 {
+        self.external_port
+    }
+
+    /// Drop the handle without removing the port mapping, which is then left until its lease
+    /// expires.
+    pub fn forget(mut self) {
+        self.gateway = None;
+    }
+}
+
+impl Drop for PortMappingHandle {
+    fn drop(&mut self) {
+        if let Some(gateway) = self.gateway.take() {
+            if let Err(err) = gateway.remove_port(self.protocol, self.external_port) {
+                warn!(
+                    "failed to remove port mapping {} {}: {}",
+                    self.protocol,
+                    self.external_port,
+                    err
+                );
+            }
+        }
+    }
+}
+
+impl PartialEq for Gateway {
+    fn eq(&self, other: &Gateway) -> bool {
+        self.addr == other.addr && self.control_url == other.control_url
+    }
+}
+
+impl Eq for Gateway {}
+
+impl Hash for Gateway {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.addr.hash(state);
+        self.control_url.hash(state);
     }
 }
 
 impl fmt::Display for Gateway {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "http://{}{}", self.addr, self.control_url)
+        write!(f, "{}", self.control_url.resolve(self.addr))
     }
 }
+
+#[test]
+fn test_control_url_resolve() {
+    let addr = "192.168.1.1:1900".parse().unwrap();
+    assert_eq!(ControlUrl::new("/ctl/IPConn").resolve(addr), "http://192.168.1.1:1900/ctl/IPConn");
+    assert_eq!(ControlUrl::new("ctl/IPConn"), "/ctl/IPConn");
+    assert_eq!(ControlUrl::new(" /ctl/IPConn\n").resolve(addr), "http://192.168.1.1:1900/ctl/IPConn");
+    assert_eq!(
+        ControlUrl::new("http://192.168.1.1:5000/ctl/IPConn").resolve(addr),
+        "http://192.168.1.1:5000/ctl/IPConn"
+    );
+    assert!(ControlUrl::new("").is_empty());
+}
+
+#[test]
+fn test_retry_options_delays() {
+    let delays = RetryOptions::default().delays();
+    assert_eq!(delays, vec![Duration::from_millis(250), Duration::from_millis(500)]);
+    assert!(RetryOptions::none().delays().is_empty());
+}
+
+#[test]
+fn test_gateway_eq_ignores_retry_options() {
+    use std::collections::HashSet;
+
+    let gateway = Gateway::new("192.168.1.1:1900".parse().unwrap(), "/ctl/IPConn");
+    let mut other = gateway.clone();
+    other.retry_options = RetryOptions::none();
+    assert_eq!(gateway, other);
+
+    let mut gateways = HashSet::new();
+    gateways.insert(gateway);
+    gateways.insert(other);
+    assert_eq!(gateways.len(), 1);
+}
+
+#[test]
+fn test_get_local_ip_loopback() {
+    let gateway = Gateway::new("127.0.0.1:1900".parse().unwrap(), "/ctl/IPConn");
+    assert_eq!(gateway.get_local_ip().unwrap(), Ipv4Addr::new(127, 0, 0, 1));
+}
+
+#[test]
+fn test_select_connection_service_cache() {
+    use mock::{self, MockTransport};
+
+    let mut gateway = mock::gateway(MockTransport::external_ip("203.0.113.1"));
+    gateway.connection_services = vec![
+        ConnectionService {
+            service_type: messages::WAN_IP_CONNECTION.to_string(),
+            service_id: Some("urn:upnp-org:serviceId:WANIPConn1".to_string()),
+            control_url: ControlUrl::new("/ctl/IPConn"),
+        },
+        ConnectionService {
+            service_type: messages::WAN_IP_CONNECTION.to_string(),
+            service_id: Some("urn:upnp-org:serviceId:WANIPConn2".to_string()),
+            control_url: ControlUrl::new("/ctl/IPConn2"),
+        },
+    ];
+    let ttl = Duration::from_secs(60);
+    assert_eq!(gateway.get_external_ip_cached(ttl).unwrap(), Ipv4Addr::new(203, 0, 113, 1));
+
+    // Selecting the current service keeps the cache, selecting another one does not share it.
+    let mut other = gateway.clone();
+    assert!(other.select_connection_service("urn:upnp-org:serviceId:WANIPConn1"));
+    assert_eq!(other.external_ip_cache.get(ttl), Some(Ipv4Addr::new(203, 0, 113, 1)));
+    assert!(other.select_connection_service("urn:upnp-org:serviceId:WANIPConn2"));
+    assert_eq!(other.control_url, "/ctl/IPConn2");
+    assert_eq!(other.external_ip_cache.get(ttl), None);
+    other.external_ip_cache.set(Ipv4Addr::new(198, 51, 100, 1));
+    assert_eq!(gateway.external_ip_cache.get(ttl), Some(Ipv4Addr::new(203, 0, 113, 1)));
+    assert!(!other.select_connection_service("urn:upnp-org:serviceId:WANPPPConn1"));
+}
+
+#[test]
+fn test_external_ip_cache() {
+    let cache = ExternalIpCache::default();
+    let ip = Ipv4Addr::new(203, 0, 113, 1);
+    assert_eq!(cache.get(Duration::from_secs(60)), None);
+    cache.clone().set(ip);
+    assert_eq!(cache.get(Duration::from_secs(60)), Some(ip));
+    assert_eq!(cache.get(Duration::from_secs(0)), None);
+    cache.clear();
+    assert_eq!(cache.get(Duration::from_secs(60)), None);
+}
+
+#[test]
+fn test_port_mapping_handle() {
+    use mock::{self, MockTransport};
+
+    let transport = MockTransport::answering("");
+    let gateway = mock::gateway(transport.clone());
+    let local_addr = "192.168.1.2:8080".parse().unwrap();
+    let handle = gateway
+        .add_port_lease(PortMappingProtocol::TCP, 8080, local_addr, 60, "test")
+        .unwrap();
+    assert_eq!(handle.external_port(), 8080);
+    drop(handle);
+    gateway
+        .add_port_lease(PortMappingProtocol::UDP, 8080, local_addr, 60, "test")
+        .unwrap()
+        .forget();
+    assert_eq!(
+        transport.actions(),
+        ["AddPortMapping", "DeletePortMapping", "AddPortMapping"]
+    );
+}
+
+#[test]
+fn test_credentials_and_local_addr() {
+    use mock::{self, MockTransport};
+
+    let transport = MockTransport::external_ip("203.0.113.1");
+    let mut gateway = mock::gateway(transport.clone());
+    gateway.http_options.content_type = Some("text/xml; charset=\"utf-8\"".to_string());
+    gateway.set_local_addr(Ipv4Addr::new(192, 168, 1, 2));
+    gateway.set_credentials("admin", "secret");
+    gateway.get_external_ip().unwrap();
+    let mut other = mock::gateway(transport.clone());
+    other.set_credentials("admin", "secret");
+    other.set_local_addr(Ipv4Addr::new(192, 168, 1, 2));
+    other.http_options.content_type = Some("text/xml; charset=\"utf-8\"".to_string());
+    other.get_external_ip().unwrap();
+
+    let expected = HttpOptions {
+        credentials: Some(Credentials {
+            username: "admin".to_string(),
+            password: "secret".to_string(),
+        }),
+        local_addr: Some(Ipv4Addr::new(192, 168, 1, 2)),
+        content_type: Some("text/xml; charset=\"utf-8\"".to_string()),
+        omit_xml_declaration: false,
+    };
+    assert_eq!(transport.http_options(), [expected.clone(), expected]);
+}
+
+#[test]
+fn test_send_raw_into() {
+    use mock::{self, MockTransport};
+
+    let gateway = mock::gateway(MockTransport::external_ip("203.0.113.1"));
+    let mut buffer = "previous response".to_string();
+    gateway.send_raw_into("GetExternalIPAddress", "", &mut buffer).unwrap();
+    assert!(buffer.contains("<NewExternalIPAddress>203.0.113.1</NewExternalIPAddress>"));
+    assert!(!buffer.contains("previous response"));
+}
+
+#[test]
+fn test_iter_port_mappings_keep_alive() {
+    use std::io::{self, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use mock;
+    use soap::SoapRequest;
+
+    // Serve two port mappings, reading each connection until the client leaves it idle, and
+    // count the connections opened by the client.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = match listener.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+    let server = thread::spawn(move || {
+        let mut connections = 0;
+        let mut requests = 0;
+        while requests < 3 {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+            connections += 1;
+            let mut received = Vec::new();
+            let mut buf = [0u8; 4096];
+            while requests < 3 {
+                let read = match stream.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(read) => read,
+                    Err(ref e)
+                        if e.kind() == io::ErrorKind::WouldBlock
+                            || e.kind() == io::ErrorKind::TimedOut => break,
+                    Err(e) => panic!("{}", e),
+                };
+                received.extend_from_slice(&buf[..read]);
+                let text = String::from_utf8_lossy(&received).into_owned();
+                // Both backends send the header names capitalized.
+                let complete = text.find("\r\n\r\n").map_or(false, |end| {
+                    let length = text[..end]
+                        .lines()
+                        .find(|line| line.starts_with("Content-Length:"))
+                        .and_then(|line| line["Content-Length:".len()..].trim().parse().ok())
+                        .unwrap_or(0);
+                    text.len() >= end + 4 + length
+                });
+                if !complete {
+                    continue;
+                }
+                let request = SoapRequest {
+                    url: String::new(),
+                    action: "\"urn:schemas-upnp-org:service:WANIPConnection:1#\
+                             GetGenericPortMappingEntry\""
+                        .to_string(),
+                    body: String::new(),
+                };
+                let (status, body) = if requests < 2 {
+                    let arguments = format!(
+                        "<NewRemoteHost></NewRemoteHost>
+<NewExternalPort>{}</NewExternalPort>
+<NewProtocol>UDP</NewProtocol>
+<NewInternalPort>5000</NewInternalPort>
+<NewInternalClient>192.168.1.2</NewInternalClient>
+<NewEnabled>1</NewEnabled>
+<NewPortMappingDescription>test</NewPortMappingDescription>
+<NewLeaseDuration>0</NewLeaseDuration>",
+                        6000 + requests
+                    );
+                    ("200 OK", mock::response(&request, &arguments).unwrap())
+                } else {
+                    let fault = mock::fault(713, "SpecifiedArrayIndexInvalid").unwrap();
+                    ("500 Internal Server Error", fault)
+                };
+                let response = format!(
+                    "HTTP/1.1 {}\r\nContent-Type: text/xml\r\nContent-Length: {}\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).unwrap();
+                received.clear();
+                requests += 1;
+            }
+        }
+        connections
+    });
+
+    let gateway = Gateway::new(addr, "/ctl/IPConn");
+    let ports: Vec<u16> = gateway
+        .iter_port_mappings()
+        .map(|entry| entry.unwrap().external_port)
+        .collect();
+    assert_eq!(ports, [6000, 6001]);
+    assert_eq!(server.join().unwrap(), 1);
+}
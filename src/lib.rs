@@ -1,47 +1,100 @@
 //! This library allows you to communicate with an IGD enabled device.
 //! Use one of the `search_gateway` functions to obtain a `Gateway` object.
 //! You can then communicate with the device via this object.
+//!
+//! The `tokio` module contains the equivalent asynchronous API, which the blocking API is built
+//! upon.
 
 #![deny(missing_docs)]
 
+#[cfg(feature = "hyper-client")]
 extern crate hyper;
+#[cfg(feature = "reqwest-client")]
+extern crate reqwest;
 extern crate regex;
+#[macro_use]
+extern crate lazy_static;
 extern crate xml;
 extern crate xmltree;
 extern crate rand;
 extern crate futures;
+extern crate net2;
 extern crate tokio_core;
+#[cfg(feature = "hyper-client")]
+extern crate tokio_current_thread;
+#[cfg(feature = "hyper-client")]
+extern crate tokio_reactor;
+#[cfg(feature = "hyper-client")]
+extern crate tokio_tcp;
 extern crate tokio_timer;
 extern crate tokio_retry;
+#[macro_use]
+extern crate log;
+extern crate flate2;
+extern crate md5;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
 
 // data structures
-pub use self::gateway::Gateway;
-pub use self::errors::{SearchError, RequestError, GetExternalIpError, AddPortError,
-                       AddAnyPortError, RemovePortError};
+pub use self::gateway::{ActionQuoting, CancelFlag, ConnectionService, ControlUrl,
+                        ExternalIpCache, Gateway, MetricsCallback, PortMappingHandle,
+                        PortMappings, RequestMetrics, RetryOptions, CONNECTION_POLL_INTERVAL};
+pub use self::builder::PortMappingBuilder;
+pub use self::soap::{Credentials, DryRunTransport, HttpOptions, SoapRequest, SoapTransport};
+#[cfg(feature = "hyper-client")]
+pub use self::soap::HttpTransport;
+#[cfg(feature = "reqwest-client")]
+pub use self::soap::ReqwestTransport;
+pub use async::parse_external_ip;
+pub use self::errors::{SearchError, RequestError, HttpError, GetExternalIpError, AddPortError,
+                       AddAnyPortError, RemovePortError, RemovePortsError,
+                       GetGenericPortMappingEntryError,
+                       GetSpecificPortMappingEntryError, SetPortMappingEnabledError,
+                       ParseProtocolError, AddPinholeError, PinholeError, MapLocalPortError};
+#[cfg(feature = "natpmp")]
+pub use self::errors::{MapPortError, NatPmpError};
 
 // search of gateway
 pub use self::search::search_gateway;
 pub use self::search::search_gateway_timeout;
 pub use self::search::search_gateway_from;
 pub use self::search::search_gateway_from_timeout;
+pub use self::search::search_gateway_from_opts;
+pub use self::search::search_gateways;
+pub use self::search::search_gateways_with;
+pub use self::search::map_port;
+pub use self::search::map_port_within;
+pub use self::search::{diagnose_gateways, GatewayDiagnostic};
+pub use self::search::{parse_ssdp_response, SsdpResponse};
+pub use self::search::{SearchOptions, INTERNET_GATEWAY_DEVICE, RETRANSMISSION_INTERVAL};
 
 /// Contains Tokio compatible implementations for finding a gateway and configuring port mappings
 pub mod tokio {
     pub use async::{Gateway, search_gateway_from_timeout, search_gateway_from,
-                    search_gateway_timeout, search_gateway};
+                    search_gateway_timeout, search_gateway, search_gateway_from_opts};
 }
 
 // re-export error types
-pub use hyper::Error as HttpError;
 pub use xml::reader::Error as XmlError;
 
 mod gateway;
+mod builder;
 mod search;
 mod soap;
+mod messages;
 mod async;
 mod errors;
+#[cfg(test)]
+mod mock;
+#[cfg(feature = "natpmp")]
+pub mod natpmp;
 
 use std::fmt;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use std::time::Duration;
 
 /// Represents the protocols available for port mapping.
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -64,3 +117,175 @@ impl fmt::Display for PortMappingProtocol {
         )
     }
 }
+
+impl FromStr for PortMappingProtocol {
+    type Err = ParseProtocolError;
+
+    /// Parse `TCP` or `UDP`, ignoring the case.
+    fn from_str(s: &str) -> Result<PortMappingProtocol, ParseProtocolError> {
+        if s.eq_ignore_ascii_case("TCP") {
+            Ok(PortMappingProtocol::TCP)
+        } else if s.eq_ignore_ascii_case("UDP") {
+            Ok(PortMappingProtocol::UDP)
+        } else {
+            Err(ParseProtocolError(s.to_string()))
+        }
+    }
+}
+
+/// Serialized as its string form, `"TCP"` or `"UDP"`.
+#[cfg(feature = "serde")]
+impl serde::Serialize for PortMappingProtocol {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Deserialized from its string form, ignoring the case like `from_str`.
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for PortMappingProtocol {
+    fn deserialize<D>(deserializer: D) -> Result<PortMappingProtocol, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let text = try!(<String as serde::Deserialize>::deserialize(deserializer));
+        text.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// A port mapping present on the gateway, as returned by `Gateway::get_generic_port_mapping_entry`
+/// and `Gateway::get_specific_port_mapping_entry`.
+///
+/// With the `serde` feature, it can also be read from a configuration file listing the port
+/// mappings to add with `Gateway::add_entry`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PortMappingEntry {
+    /// The remote host the mapping is restricted to. Empty for a wildcard mapping.
+    pub remote_host: String,
+    /// The external port of the mapping
+    pub external_port: u16,
+    /// The protocol of the mapping
+    pub protocol: PortMappingProtocol,
+    /// The internal port where the traffic is sent to
+    pub internal_port: u16,
+    /// The internal client where the traffic is sent to
+    pub internal_client: String,
+    /// Whether the mapping is enabled
+    pub enabled: bool,
+    /// The description of the mapping
+    pub port_mapping_description: String,
+    /// The lease duration of the mapping in seconds. A value of 0 is infinite.
+    pub lease_duration: u32,
+}
+
+/// Status of the WAN connection of the gateway.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConnectionStatus {
+    /// The connection is not configured
+    Unconfigured,
+    /// The connection is being established
+    Connecting,
+    /// The connection is established
+    Connected,
+    /// The connection will be terminated once the warning delay expires
+    PendingDisconnect,
+    /// The connection is being terminated
+    Disconnecting,
+    /// The connection is terminated
+    Disconnected,
+}
+
+/// Status of the WAN connection, as returned by `Gateway::get_status_info`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatusInfo {
+    /// The status of the connection
+    pub connection_status: ConnectionStatus,
+    /// The cause of the last connection failure, `ERROR_NONE` if there was none
+    pub last_connection_error: String,
+    /// How long the connection has been up
+    pub uptime: Duration,
+}
+
+/// NAT and RSIP availability, as returned by `Gateway::get_nat_rsip_status`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NatRsipStatus {
+    /// Whether Realm-Specific IP is available
+    pub rsip_available: bool,
+    /// Whether network address translation is enabled
+    pub nat_enabled: bool,
+}
+
+/// Connection type of the gateway, as returned by `Gateway::get_connection_type_info`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ConnectionTypeInfo {
+    /// The current connection type, such as `IP_Routed` or `IP_Bridged`. Port mappings are only
+    /// possible on routed connections.
+    pub connection_type: String,
+    /// The connection types supported by the gateway
+    pub possible_connection_types: Vec<String>,
+}
+
+/// Whether a mapped port can be reached from the internet, as returned by
+/// `Gateway::verify_reachable`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Reachability {
+    /// A TCP connection to the external address of the gateway reached the mapped port
+    Reachable,
+    /// The external address of the gateway is private (RFC 1918), the gateway sits behind another
+    /// NAT which does not forward the port
+    BehindNat(Ipv4Addr),
+    /// The external address of the gateway is in the shared address space of carrier-grade NAT
+    /// (RFC 6598), the ISP does not forward the port
+    CarrierGradeNat(Ipv4Addr),
+    /// The external address of the gateway is public but the mapped port could not be reached
+    /// through it, either because the gateway does not support hairpinning or because the port is
+    /// filtered, or because the protocol is UDP, which can not be tested
+    Unverified(Ipv4Addr),
+}
+
+#[test]
+fn test_parse_protocol() {
+    assert_eq!("TCP".parse(), Ok(PortMappingProtocol::TCP));
+    assert_eq!("udp".parse(), Ok(PortMappingProtocol::UDP));
+    assert_eq!(
+        "SCTP".parse::<PortMappingProtocol>(),
+        Err(ParseProtocolError("SCTP".to_string()))
+    );
+    assert_eq!(PortMappingProtocol::TCP.to_string().parse(), Ok(PortMappingProtocol::TCP));
+}
+
+#[test]
+#[cfg(feature = "serde")]
+fn test_serde_protocol() {
+    assert_eq!(serde_json::to_string(&PortMappingProtocol::TCP).unwrap(), "\"TCP\"");
+    assert_eq!(serde_json::to_string(&PortMappingProtocol::UDP).unwrap(), "\"UDP\"");
+    let protocol: PortMappingProtocol = serde_json::from_str("\"TCP\"").unwrap();
+    assert_eq!(protocol, PortMappingProtocol::TCP);
+    let protocol: PortMappingProtocol = serde_json::from_str("\"udp\"").unwrap();
+    assert_eq!(protocol, PortMappingProtocol::UDP);
+    assert!(serde_json::from_str::<PortMappingProtocol>("\"SCTP\"").is_err());
+    assert!(serde_json::from_str::<PortMappingProtocol>("6").is_err());
+}
+
+#[test]
+#[cfg(feature = "serde")]
+fn test_serde_port_mapping_entry() {
+    let config = r#"{
+        "remote_host": "",
+        "external_port": 6000,
+        "protocol": "udp",
+        "internal_port": 5000,
+        "internal_client": "192.168.1.2",
+        "enabled": true,
+        "port_mapping_description": "game server",
+        "lease_duration": 3600
+    }"#;
+    let entry: PortMappingEntry = serde_json::from_str(config).unwrap();
+    assert_eq!(entry.protocol, PortMappingProtocol::UDP);
+    assert_eq!(entry.external_port, 6000);
+    assert_eq!(entry.port_mapping_description, "game server");
+    let json = serde_json::to_string(&entry).unwrap();
+    assert!(json.contains("\"protocol\":\"UDP\""));
+    assert_eq!(serde_json::from_str::<PortMappingEntry>(&json).unwrap(), entry);
+}
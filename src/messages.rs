@@ -0,0 +1,362 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use {PortMappingEntry, PortMappingProtocol};
+
+/// Service type of the WANIPConnection service.
+pub const WAN_IP_CONNECTION: &str = "urn:schemas-upnp-org:service:WANIPConnection:1";
+/// Service type of version 2 of the WANIPConnection service, which adds AddAnyPortMapping.
+pub const WAN_IP_CONNECTION_2: &str = "urn:schemas-upnp-org:service:WANIPConnection:2";
+/// Service type of the WANPPPConnection service, used by many DSL modems.
+pub const WAN_PPP_CONNECTION: &str = "urn:schemas-upnp-org:service:WANPPPConnection:1";
+
+/// Service type of the WANCommonInterfaceConfig service, which holds the traffic counters.
+pub const WAN_COMMON_INTERFACE_CONFIG: &str =
+    "urn:schemas-upnp-org:service:WANCommonInterfaceConfig:1";
+
+/// Service type of the WANIPv6FirewallControl service, which opens pinholes for IPv6 traffic.
+pub const WAN_IPV6_FIREWALL_CONTROL: &str =
+    "urn:schemas-upnp-org:service:WANIPv6FirewallControl:1";
+
+// Build the SOAPAction header value of an action on the given service.
+pub fn soap_action(service_type: &str, action: &str) -> String {
+    format!("\"{}#{}\"", service_type, action)
+}
+
+// Wrap the arguments of an action in a SOAP envelope.
+fn format_message(service_type: &str, action: &str, arguments: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\"?>
+        <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">
+        <s:Body>
+            <u:{0} xmlns:u=\"{1}\">{2}
+            </u:{0}>
+        </s:Body>
+        </s:Envelope>
+        ",
+        action,
+        service_type,
+        arguments
+    )
+}
+
+pub fn get_external_ip_soap_action(service_type: &str) -> String {
+    soap_action(service_type, "GetExternalIPAddress")
+}
+
+pub fn add_any_port_soap_action(service_type: &str) -> String {
+    soap_action(service_type, "AddAnyPortMapping")
+}
+
+pub fn add_port_soap_action(service_type: &str) -> String {
+    soap_action(service_type, "AddPortMapping")
+}
+
+pub fn delete_port_soap_action(service_type: &str) -> String {
+    soap_action(service_type, "DeletePortMapping")
+}
+
+pub fn get_generic_port_mapping_entry_soap_action(service_type: &str) -> String {
+    soap_action(service_type, "GetGenericPortMappingEntry")
+}
+
+pub fn get_specific_port_mapping_entry_soap_action(service_type: &str) -> String {
+    soap_action(service_type, "GetSpecificPortMappingEntry")
+}
+
+pub fn get_status_info_soap_action(service_type: &str) -> String {
+    soap_action(service_type, "GetStatusInfo")
+}
+
+pub fn get_nat_rsip_status_soap_action(service_type: &str) -> String {
+    soap_action(service_type, "GetNATRSIPStatus")
+}
+
+pub fn force_termination_soap_action(service_type: &str) -> String {
+    soap_action(service_type, "ForceTermination")
+}
+
+pub fn request_connection_soap_action(service_type: &str) -> String {
+    soap_action(service_type, "RequestConnection")
+}
+
+pub fn get_connection_type_info_soap_action(service_type: &str) -> String {
+    soap_action(service_type, "GetConnectionTypeInfo")
+}
+
+pub fn get_total_bytes_sent_soap_action() -> String {
+    soap_action(WAN_COMMON_INTERFACE_CONFIG, "GetTotalBytesSent")
+}
+
+pub fn get_total_bytes_received_soap_action() -> String {
+    soap_action(WAN_COMMON_INTERFACE_CONFIG, "GetTotalBytesReceived")
+}
+
+pub fn get_total_packets_sent_soap_action() -> String {
+    soap_action(WAN_COMMON_INTERFACE_CONFIG, "GetTotalPacketsSent")
+}
+
+pub fn get_total_packets_received_soap_action() -> String {
+    soap_action(WAN_COMMON_INTERFACE_CONFIG, "GetTotalPacketsReceived")
+}
+
+pub fn add_pinhole_soap_action() -> String {
+    soap_action(WAN_IPV6_FIREWALL_CONTROL, "AddPinhole")
+}
+
+pub fn delete_pinhole_soap_action() -> String {
+    soap_action(WAN_IPV6_FIREWALL_CONTROL, "DeletePinhole")
+}
+
+pub fn get_pinhole_packets_soap_action() -> String {
+    soap_action(WAN_IPV6_FIREWALL_CONTROL, "GetPinholePackets")
+}
+
+pub fn format_get_external_ip_message(service_type: &str) -> String {
+    format_message(service_type, "GetExternalIPAddress", "")
+}
+
+pub fn format_get_status_info_message(service_type: &str) -> String {
+    format_message(service_type, "GetStatusInfo", "")
+}
+
+pub fn format_get_nat_rsip_status_message(service_type: &str) -> String {
+    format_message(service_type, "GetNATRSIPStatus", "")
+}
+
+pub fn format_force_termination_message(service_type: &str) -> String {
+    format_message(service_type, "ForceTermination", "")
+}
+
+pub fn format_request_connection_message(service_type: &str) -> String {
+    format_message(service_type, "RequestConnection", "")
+}
+
+// Message of the Get<name> actions reading a duration, such as GetWarnDisconnectDelay.
+pub fn format_get_duration_message(service_type: &str, name: &str) -> String {
+    format_message(service_type, &format!("Get{}", name), "")
+}
+
+// Message of the Set<name> actions writing a duration in seconds, such as SetWarnDisconnectDelay.
+pub fn format_set_duration_message(service_type: &str, name: &str, secs: u32) -> String {
+    format_message(
+        service_type,
+        &format!("Set{}", name),
+        &format!(
+            "
+                <New{0}>{1}</New{0}>",
+            name,
+            secs
+        ),
+    )
+}
+
+pub fn format_get_connection_type_info_message(service_type: &str) -> String {
+    format_message(service_type, "GetConnectionTypeInfo", "")
+}
+
+pub fn format_get_total_bytes_sent_message() -> String {
+    format_message(WAN_COMMON_INTERFACE_CONFIG, "GetTotalBytesSent", "")
+}
+
+pub fn format_get_total_bytes_received_message() -> String {
+    format_message(WAN_COMMON_INTERFACE_CONFIG, "GetTotalBytesReceived", "")
+}
+
+pub fn format_get_total_packets_sent_message() -> String {
+    format_message(WAN_COMMON_INTERFACE_CONFIG, "GetTotalPacketsSent", "")
+}
+
+pub fn format_get_total_packets_received_message() -> String {
+    format_message(WAN_COMMON_INTERFACE_CONFIG, "GetTotalPacketsReceived", "")
+}
+
+pub fn format_add_any_port_mapping_message(service_type: &str, entry: &PortMappingEntry) -> String {
+    format_message(service_type, "AddAnyPortMapping", &format_mapping_arguments(entry))
+}
+
+pub fn format_add_port_mapping_message(service_type: &str, entry: &PortMappingEntry) -> String {
+    format_message(service_type, "AddPortMapping", &format_mapping_arguments(entry))
+}
+
+pub fn format_delete_port_message(
+    service_type: &str,
+    protocol: PortMappingProtocol,
+    remote_host: &str,
+    external_port: u16,
+) -> String {
+    format_message(
+        service_type,
+        "DeletePortMapping",
+        &format!(
+            "
+                <NewProtocol>{}</NewProtocol>
+                <NewExternalPort>{}</NewExternalPort>
+                <NewRemoteHost>{}</NewRemoteHost>",
+            protocol,
+            external_port,
+            escape(remote_host)
+        ),
+    )
+}
+
+pub fn format_add_pinhole_message(
+    remote_host: Option<Ipv6Addr>,
+    remote_port: u16,
+    internal_client: Ipv6Addr,
+    internal_port: u16,
+    protocol: PortMappingProtocol,
+    lease_time: u32,
+) -> String {
+    format_message(
+        WAN_IPV6_FIREWALL_CONTROL,
+        "AddPinhole",
+        &format!(
+            "
+                <RemoteHost>{}</RemoteHost>
+                <RemotePort>{}</RemotePort>
+                <InternalClient>{}</InternalClient>
+                <InternalPort>{}</InternalPort>
+                <Protocol>{}</Protocol>
+                <LeaseTime>{}</LeaseTime>",
+            remote_host.map(|ip| ip.to_string()).unwrap_or_default(),
+            remote_port,
+            internal_client,
+            internal_port,
+            protocol_number(protocol),
+            lease_time
+        ),
+    )
+}
+
+pub fn format_delete_pinhole_message(unique_id: u16) -> String {
+    format_message(
+        WAN_IPV6_FIREWALL_CONTROL,
+        "DeletePinhole",
+        &format!(
+            "
+                <UniqueID>{}</UniqueID>",
+            unique_id
+        ),
+    )
+}
+
+pub fn format_get_pinhole_packets_message(unique_id: u16) -> String {
+    format_message(
+        WAN_IPV6_FIREWALL_CONTROL,
+        "GetPinholePackets",
+        &format!(
+            "
+                <UniqueID>{}</UniqueID>",
+            unique_id
+        ),
+    )
+}
+
+// The firewall control service identifies protocols by their IANA number.
+fn protocol_number(protocol: PortMappingProtocol) -> u8 {
+    match protocol {
+        PortMappingProtocol::TCP => 6,
+        PortMappingProtocol::UDP => 17,
+    }
+}
+
+pub fn format_get_generic_port_mapping_entry_message(service_type: &str, index: u32) -> String {
+    format_message(
+        service_type,
+        "GetGenericPortMappingEntry",
+        &format!(
+            "
+                <NewPortMappingIndex>{}</NewPortMappingIndex>",
+            index
+        ),
+    )
+}
+
+pub fn format_get_specific_port_mapping_entry_message(
+    service_type: &str,
+    protocol: PortMappingProtocol,
+    remote_host: &str,
+    external_port: u16,
+) -> String {
+    format_message(
+        service_type,
+        "GetSpecificPortMappingEntry",
+        &format!(
+            "
+                <NewRemoteHost>{}</NewRemoteHost>
+                <NewExternalPort>{}</NewExternalPort>
+                <NewProtocol>{}</NewProtocol>",
+            escape(remote_host),
+            external_port,
+            protocol
+        ),
+    )
+}
+
+// An empty remote host is a wildcard matching any host.
+pub fn format_remote_host(remote_host: Option<Ipv4Addr>) -> String {
+    remote_host.map(|ip| ip.to_string()).unwrap_or_default()
+}
+
+fn format_mapping_arguments(entry: &PortMappingEntry) -> String {
+    format!(
+        "
+                <NewProtocol>{}</NewProtocol>
+                <NewExternalPort>{}</NewExternalPort>
+                <NewInternalClient>{}</NewInternalClient>
+                <NewInternalPort>{}</NewInternalPort>
+                <NewLeaseDuration>{}</NewLeaseDuration>
+                <NewPortMappingDescription>{}</NewPortMappingDescription>
+                <NewEnabled>{}</NewEnabled>
+                <NewRemoteHost>{}</NewRemoteHost>",
+        entry.protocol,
+        entry.external_port,
+        escape(&entry.internal_client),
+        entry.internal_port,
+        entry.lease_duration,
+        escape(&entry.port_mapping_description),
+        if entry.enabled { 1 } else { 0 },
+        escape(&entry.remote_host)
+    )
+}
+
+// Escape the characters which have a special meaning in XML text.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[test]
+fn test_format_add_port_mapping_message_escapes_description() {
+    use xmltree::Element;
+
+    let entry = PortMappingEntry {
+        remote_host: String::new(),
+        external_port: 8080,
+        protocol: PortMappingProtocol::TCP,
+        internal_port: 8080,
+        internal_client: "192.168.1.2".to_string(),
+        enabled: true,
+        port_mapping_description: "My & \"cool\" <app>".to_string(),
+        lease_duration: 0,
+    };
+    let message = format_add_port_mapping_message(WAN_IP_CONNECTION, &entry);
+    let envelope = Element::parse(message.as_bytes()).unwrap();
+    let description = envelope
+        .get_child("Body")
+        .and_then(|body| body.get_child("AddPortMapping"))
+        .and_then(|action| action.get_child("NewPortMappingDescription"))
+        .and_then(|description| description.text.clone());
+    assert_eq!(description, Some("My & \"cool\" <app>".to_string()));
+}
+
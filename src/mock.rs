@@ -0,0 +1,140 @@
+//! Fixtures shared by the tests.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use futures::Future;
+use futures::future;
+use tokio_core::reactor::Handle;
+
+use async::Gateway as AsyncGateway;
+use errors::RequestError;
+use gateway::Gateway;
+use soap::{HttpOptions, SoapRequest, SoapTransport};
+
+type Respond = Fn(&SoapRequest, usize) -> Result<String, RequestError> + Send + Sync;
+
+/// Transport answering the requests with canned responses and recording them.
+pub struct MockTransport {
+    respond: Box<Respond>,
+    requests: Mutex<Vec<SoapRequest>>,
+    http_options: Mutex<Vec<HttpOptions>>,
+}
+
+impl MockTransport {
+    /// Answer every request with the given function, which is also passed the number of requests
+    /// received before this one.
+    pub fn new<F>(respond: F) -> Arc<MockTransport>
+    where
+        F: Fn(&SoapRequest, usize) -> Result<String, RequestError> + Send + Sync + 'static,
+    {
+        Arc::new(MockTransport {
+            respond: Box::new(respond),
+            requests: Mutex::new(Vec::new()),
+            http_options: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Answer every request with a successful response holding the given arguments.
+    pub fn answering(arguments: &str) -> Arc<MockTransport> {
+        let arguments = arguments.to_string();
+        MockTransport::new(move |request, _| response(request, &arguments))
+    }
+
+    /// Answer every request with the given external IP address.
+    pub fn external_ip(ip: &str) -> Arc<MockTransport> {
+        MockTransport::answering(&format!("<NewExternalIPAddress>{}</NewExternalIPAddress>", ip))
+    }
+
+    /// Get the requests received so far, in order.
+    pub fn requests(&self) -> Vec<SoapRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Get the HTTP options the requests received so far were sent with, in order.
+    pub fn http_options(&self) -> Vec<HttpOptions> {
+        self.http_options.lock().unwrap().clone()
+    }
+
+    /// Get the names of the actions received so far, such as `AddPortMapping`.
+    pub fn actions(&self) -> Vec<String> {
+        self.requests()
+            .iter()
+            .map(|request| action_name(request).to_string())
+            .collect()
+    }
+}
+
+impl fmt::Debug for MockTransport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MockTransport")
+            .field("requests", &self.requests)
+            .finish()
+    }
+}
+
+impl SoapTransport for MockTransport {
+    fn send(
+        &self,
+        url: &str,
+        action: &str,
+        body: &str,
+        _user_agent: &str,
+        options: &HttpOptions,
+    ) -> Box<Future<Item = String, Error = RequestError>> {
+        let request = SoapRequest {
+            url: url.to_owned(),
+            action: action.to_owned(),
+            body: body.to_owned(),
+        };
+        let mut requests = self.requests.lock().unwrap();
+        let result = (self.respond)(&request, requests.len());
+        requests.push(request);
+        self.http_options.lock().unwrap().push(options.clone());
+        Box::new(future::result(result))
+    }
+}
+
+/// Name of the action of the request, without its service type.
+pub fn action_name(request: &SoapRequest) -> &str {
+    request.action.trim_matches('"').rsplit('#').next().unwrap()
+}
+
+/// Successful response to the request, in the namespace of its service.
+pub fn response(request: &SoapRequest, arguments: &str) -> Result<String, RequestError> {
+    let service_type = request.action.trim_matches('"').split('#').next().unwrap();
+    Ok(format!(
+        "<?xml version=\"1.0\"?>
+<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\"><s:Body>
+<u:{0}Response xmlns:u=\"{1}\">{2}</u:{0}Response>
+</s:Body></s:Envelope>",
+        action_name(request),
+        service_type,
+        arguments
+    ))
+}
+
+/// UPnP error response with the given code.
+pub fn fault(code: u16, description: &str) -> Result<String, RequestError> {
+    Ok(format!(
+        "<?xml version=\"1.0\"?>
+<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\"><s:Body>
+<s:Fault><detail><UPnPError xmlns=\"urn:schemas-upnp-org:control-1-0\">
+<errorCode>{}</errorCode><errorDescription>{}</errorDescription>
+</UPnPError></detail></s:Fault>
+</s:Body></s:Envelope>",
+        code, description
+    ))
+}
+
+/// Gateway at 192.168.1.1 sending its requests through the given transport.
+pub fn gateway(transport: Arc<SoapTransport + Send + Sync>) -> Gateway {
+    let mut gateway = Gateway::new("192.168.1.1:1900".parse().unwrap(), "/ctl/IPConn");
+    gateway.transport = transport;
+    gateway
+}
+
+/// Same gateway as `gateway`, running on the given event loop.
+pub fn async_gateway(transport: Arc<SoapTransport + Send + Sync>, handle: &Handle) -> AsyncGateway {
+    AsyncGateway::from_gateway(gateway(transport), handle.clone())
+}
@@ -0,0 +1,228 @@
+//! NAT-PMP (RFC 6886) client, used as a fallback for gateways which do not support UPnP IGD.
+//!
+//! This module is only available with the `natpmp` feature.
+
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::time::{Duration, Instant};
+
+use errors::{MapPortError, NatPmpError};
+use search::search_gateway_timeout;
+use PortMappingProtocol;
+
+/// Port NAT-PMP servers listen on.
+pub const NAT_PMP_PORT: u16 = 5351;
+
+/// Lifetime in seconds recommended by the RFC for the mappings, requested by `map_port` with
+/// NAT-PMP when it is given an infinite lifetime.
+pub const RECOMMENDED_LIFETIME: u32 = 7200;
+
+/// A port mapping added by `map_port`, either with UPnP IGD or NAT-PMP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PortMapping {
+    /// The external address of the gateway
+    pub external_ip: Ipv4Addr,
+    /// The external port which was mapped
+    pub external_port: u16,
+    /// The lifetime of the mapping in seconds. With UPnP IGD this is the requested lifetime, 0
+    /// being infinite, since the gateway does not report it. With NAT-PMP this is the lifetime
+    /// granted by the gateway.
+    pub lifetime: u32,
+}
+
+/// Map the local address on any external port, using UPnP IGD or NAT-PMP.
+///
+/// A UPnP IGD gateway is searched first. When none answers within the timeout, the mapping is
+/// requested with NAT-PMP from the default gateway of the system. The lifetime is in seconds.
+/// NAT-PMP does not support infinite lifetimes, a lifetime of 0 deletes the mapping there, so
+/// `RECOMMENDED_LIFETIME` is requested instead of 0 with NAT-PMP.
+pub fn map_port(
+    protocol: PortMappingProtocol,
+    local_addr: SocketAddrV4,
+    lifetime: u32,
+    description: &str,
+    timeout: Duration,
+) -> Result<PortMapping, MapPortError> {
+    match search_gateway_timeout(timeout) {
+        Ok(gateway) => {
+            let addr = try!(gateway.get_any_address(protocol, local_addr, lifetime, description));
+            Ok(PortMapping {
+                external_ip: *addr.ip(),
+                external_port: addr.port(),
+                lifetime: lifetime,
+            })
+        }
+        Err(_) => {
+            let gateway = try!(default_gateway().map_err(NatPmpError::IoError));
+            let client = NatPmpClient::new(gateway, timeout);
+            let lifetime = if lifetime == 0 { RECOMMENDED_LIFETIME } else { lifetime };
+            let external_ip = try!(client.get_external_ip());
+            let (external_port, lifetime) = try!(client.add_port(
+                protocol,
+                local_addr.port(),
+                local_addr.port(),
+                lifetime,
+            ));
+            Ok(PortMapping {
+                external_ip: external_ip,
+                external_port: external_port,
+                lifetime: lifetime,
+            })
+        }
+    }
+}
+
+/// Client sending NAT-PMP requests to a gateway.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct NatPmpClient {
+    /// Address of the NAT-PMP server
+    pub addr: SocketAddrV4,
+    /// Time to wait for the gateway to answer a request
+    pub timeout: Duration,
+}
+
+impl NatPmpClient {
+    /// Create a client for the gateway with the given IP address.
+    pub fn new(gateway: Ipv4Addr, timeout: Duration) -> NatPmpClient {
+        NatPmpClient {
+            addr: SocketAddrV4::new(gateway, NAT_PMP_PORT),
+            timeout: timeout,
+        }
+    }
+
+    /// Get the external IP address of the gateway.
+    pub fn get_external_ip(&self) -> Result<Ipv4Addr, NatPmpError> {
+        let response = try!(self.send(&[0, 0]));
+        parse_external_ip_response(&response)
+    }
+
+    /// Map the internal port, suggesting the given external port to the gateway.
+    ///
+    /// The external port actually mapped and the lifetime granted by the gateway are returned.
+    /// A lifetime of 0 removes the mapping.
+    pub fn add_port(
+        &self,
+        protocol: PortMappingProtocol,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: u32,
+    ) -> Result<(u16, u32), NatPmpError> {
+        let opcode = protocol_opcode(protocol);
+        let mut request = [0u8; 12];
+        request[1] = opcode;
+        request[4..6].copy_from_slice(&internal_port.to_be_bytes());
+        request[6..8].copy_from_slice(&external_port.to_be_bytes());
+        request[8..12].copy_from_slice(&lifetime.to_be_bytes());
+        let response = try!(self.send(&request));
+        parse_mapping_response(&response, opcode)
+    }
+
+    // Send the request and wait for the response, retransmitting the request as required by the
+    // RFC: after 250ms, then doubling the delay, until the timeout expires.
+    fn send(&self, request: &[u8]) -> Result<Vec<u8>, NatPmpError> {
+        let socket = try!(UdpSocket::bind("0.0.0.0:0"));
+        try!(socket.connect(self.addr));
+        let deadline = Instant::now() + self.timeout;
+        let mut delay = Duration::from_millis(250);
+        let mut buf = [0u8; 16];
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(NatPmpError::Timeout);
+            }
+            try!(socket.send(request));
+            let wait = if deadline - now < delay { deadline - now } else { delay };
+            try!(socket.set_read_timeout(Some(wait)));
+            match socket.recv(&mut buf) {
+                Ok(read) => return Ok(buf[..read].to_vec()),
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => return Err(NatPmpError::IoError(e)),
+            }
+            delay *= 2;
+        }
+    }
+}
+
+/// Find the default gateway of the system.
+///
+/// This is only supported on Linux, where the routing table is read from `/proc/net/route`.
+pub fn default_gateway() -> io::Result<Ipv4Addr> {
+    let mut routes = String::new();
+    try!(try!(File::open("/proc/net/route")).read_to_string(&mut routes));
+    parse_default_gateway(&routes)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "no default gateway"))
+}
+
+fn protocol_opcode(protocol: PortMappingProtocol) -> u8 {
+    match protocol {
+        PortMappingProtocol::UDP => 1,
+        PortMappingProtocol::TCP => 2,
+    }
+}
+
+// Check the header of a response, common to all the operations.
+fn check_response(response: &[u8], opcode: u8, len: usize) -> Result<(), NatPmpError> {
+    if response.len() < 4 || response[0] != 0 || response[1] != 128 + opcode {
+        return Err(NatPmpError::InvalidResponse);
+    }
+    match u16::from_be_bytes([response[2], response[3]]) {
+        0 if response.len() >= len => Ok(()),
+        0 => Err(NatPmpError::InvalidResponse),
+        code => Err(NatPmpError::ResultCode(code)),
+    }
+}
+
+fn parse_external_ip_response(response: &[u8]) -> Result<Ipv4Addr, NatPmpError> {
+    try!(check_response(response, 0, 12));
+    Ok(Ipv4Addr::new(response[8], response[9], response[10], response[11]))
+}
+
+fn parse_mapping_response(response: &[u8], opcode: u8) -> Result<(u16, u32), NatPmpError> {
+    try!(check_response(response, opcode, 16));
+    let external_port = u16::from_be_bytes([response[10], response[11]]);
+    let lifetime = u32::from_be_bytes([response[12], response[13], response[14], response[15]]);
+    Ok((external_port, lifetime))
+}
+
+// Find the gateway of the default route in the content of /proc/net/route, where the addresses
+// are written in hexadecimal in the byte order of the system.
+fn parse_default_gateway(routes: &str) -> Option<Ipv4Addr> {
+    for line in routes.lines().skip(1) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 3 || fields[1] != "00000000" {
+            continue;
+        }
+        let gateway = u32::from_str_radix(fields[2], 16).ok()?;
+        return Some(Ipv4Addr::from(gateway.to_ne_bytes()));
+    }
+    None
+}
+
+#[test]
+fn test_parse_mapping_response() {
+    let response = [0, 130, 0, 0, 0, 0, 0, 1, 0x13, 0x88, 0x17, 0x70, 0, 0, 0x0e, 0x10];
+    assert_eq!(parse_mapping_response(&response, 2).unwrap(), (6000, 3600));
+    match parse_mapping_response(&[0, 130, 0, 2], 2) {
+        Err(NatPmpError::ResultCode(2)) => (),
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn test_parse_external_ip_response() {
+    let response = [0, 128, 0, 0, 0, 0, 0, 1, 203, 0, 113, 1];
+    assert_eq!(parse_external_ip_response(&response).unwrap(), Ipv4Addr::new(203, 0, 113, 1));
+}
+
+#[test]
+fn test_parse_default_gateway() {
+    let routes = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask
+eth0\t0001A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF
+eth0\t00000000\t0101A8C0\t0003\t0\t0\t0\t00000000
+";
+    assert_eq!(parse_default_gateway(routes), Some(Ipv4Addr::new(192, 168, 1, 1)));
+}
@@ -1,21 +1,149 @@
+use std::io;
 use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
 use std::str;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use futures::Future;
 use tokio_core::reactor::Core;
+use tokio_timer::Timer;
 use regex::Regex;
 
-use gateway::Gateway;
-use errors::SearchError;
-use async::get_control_url as get_control_url_async;
+use gateway::{CancelFlag, Gateway};
+use errors::{GetExternalIpError, MapLocalPortError, SearchError};
+use async::{get_control_url as get_control_url_async, DeviceDescription};
+use async::Gateway as AsyncGateway;
+use PortMappingProtocol;
 
-// Content of the request.
-pub const SEARCH_REQUEST: &'static str = "M-SEARCH * HTTP/1.1\r
-Host:239.255.255.250:1900\r
-ST:urn:schemas-upnp-org:device:InternetGatewayDevice:1\r
+/// Search target of version 1 of the InternetGatewayDevice, searched by default.
+pub const INTERNET_GATEWAY_DEVICE: &str =
+    "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+
+// Content of the request for the given search target, sent to the given address.
+pub fn format_search_request(broadcast_address: SocketAddrV4, search_target: &str) -> String {
+    format!(
+        "M-SEARCH * HTTP/1.1\r
+Host:{}\r
+ST:{}\r
 Man:\"ssdp:discover\"\r
-MX:3\r\n\r\n";
+MX:3\r\n\r\n",
+        broadcast_address,
+        search_target
+    )
+}
+
+/// Delay between two transmissions of the search requests.
+pub const RETRANSMISSION_INTERVAL: Duration = Duration::from_millis(100);
+
+// Longest wait for a response between two checks of the cancel flag of the search.
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Sends the search requests, one per search target, then sends them again every
+// RETRANSMISSION_INTERVAL as long as retransmissions are left.
+struct SearchSender<'a> {
+    socket: &'a UdpSocket,
+    options: &'a SearchOptions,
+    deadline: Instant,
+    sent: u32,
+    next: Instant,
+}
+
+impl<'a> SearchSender<'a> {
+    fn new(socket: &'a UdpSocket, options: &'a SearchOptions) -> SearchSender<'a> {
+        let now = Instant::now();
+        SearchSender {
+            socket: socket,
+            options: options,
+            deadline: now + options.timeout,
+            sent: 0,
+            next: now,
+        }
+    }
+
+    // Send the requests if they are due, then return how long to wait for a response before the
+    // next transmission, or `None` once the timeout expired. The wait is kept short when the search
+    // can be cancelled.
+    fn poll(&mut self) -> Result<Option<Duration>, SearchError> {
+        if self.options.cancel.is_cancelled() {
+            return Err(SearchError::Cancelled);
+        }
+        let now = Instant::now();
+        if now >= self.deadline {
+            return Ok(None);
+        }
+        if self.sent <= self.options.retransmissions && now >= self.next {
+            for search_target in &self.options.search_targets {
+                let request = format_search_request(self.options.broadcast_address, search_target);
+                try!(self.socket.send_to(request.as_bytes(), self.options.broadcast_address));
+            }
+            self.sent += 1;
+            self.next = now + RETRANSMISSION_INTERVAL;
+        }
+        let wait = if self.sent <= self.options.retransmissions && self.next < self.deadline {
+            self.next - now
+        } else {
+            self.deadline - now
+        };
+        if self.options.cancel.is_set() && wait > CANCEL_POLL_INTERVAL {
+            Ok(Some(CANCEL_POLL_INTERVAL))
+        } else {
+            Ok(Some(wait))
+        }
+    }
+}
 
+// Whether the error is the read timeout of the socket, which is reported as either of these
+// depending on the platform.
+fn is_timeout(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut
+}
+
+/// Gateway search configuration
+///
+/// The default options bind to all interfaces, send the request for the InternetGatewayDevice:1
+/// search target to the standard SSDP multicast address and wait for 3 seconds.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SearchOptions {
+    /// Address the UDP socket used for the search is bound to. The port is 0 by default, letting
+    /// the system pick an ephemeral port, set it to receive the responses on a fixed port, for
+    /// instance one allowed by a firewall. When an IP address is set, the gateways found send their
+    /// requests from it too.
+    pub bind_addr: SocketAddrV4,
+    /// Address the search request is sent to, the standard SSDP multicast address by default.
+    /// It can be changed to use a relay or a local responder.
+    pub broadcast_address: SocketAddrV4,
+    /// Time to wait for a gateway to answer
+    pub timeout: Duration,
+    /// SSDP search targets (ST header), a separate request is sent for each of them.
+    ///
+    /// Wildcard targets such as `ssdp:all` are also answered by devices which are not gateways,
+    /// they are best used with `search_gateways`, which skips them.
+    pub search_targets: Vec<String>,
+    /// Number of times the requests are sent again, `RETRANSMISSION_INTERVAL` apart, to make up
+    /// for lost packets. Responses to the different transmissions are merged.
+    pub retransmissions: u32,
+    /// Time to wait for the device description of a gateway which answered, `SearchError::Timeout`
+    /// is returned when it is not received in time. It also becomes the `request_timeout` of the
+    /// gateways found.
+    pub request_timeout: Duration,
+    /// Flag cancelling the search with `SearchError::Cancelled` once set, none by default. It is
+    /// checked at least every 50ms while waiting for responses, but not while a device description
+    /// is fetched. The gateways found keep it to cancel their requests too.
+    pub cancel: CancelFlag,
+}
+
+impl Default for SearchOptions {
+    fn default() -> SearchOptions {
+        SearchOptions {
+            bind_addr: SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0),
+            broadcast_address: SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900),
+            timeout: Duration::from_secs(3),
+            search_targets: vec![INTERNET_GATEWAY_DEVICE.to_string()],
+            retransmissions: 2,
+            request_timeout: Duration::from_secs(10),
+            cancel: CancelFlag::default(),
+        }
+    }
+}
 
 /// Search gateway, bind to all interfaces and use a timeout of 3 seconds.
 ///
@@ -28,7 +156,7 @@ pub fn search_gateway() -> Result<Gateway, SearchError> {
 /// Search gateway, bind to all interfaces and use the given duration for the timeout.
 ///
 /// Bind to all interfaces.
-/// The request will timeout after the given duration.
+/// The request will timeout after the given duration with `SearchError::Timeout`.
 pub fn search_gateway_timeout(timeout: Duration) -> Result<Gateway, SearchError> {
     search_gateway_from_timeout(Ipv4Addr::new(0, 0, 0, 0), timeout)
 }
@@ -44,64 +172,362 @@ pub fn search_gateway_from(ip: Ipv4Addr) -> Result<Gateway, SearchError> {
 /// Search gateway, bind to the given interface and use the given duration for the timeout.
 ///
 /// Bind to the given interface.
-/// The request will timeout after the given duration.
+/// The request will timeout after the given duration with `SearchError::Timeout`.
 pub fn search_gateway_from_timeout(
     ip: Ipv4Addr,
     timeout: Duration,
 ) -> Result<Gateway, SearchError> {
-    let addr = SocketAddrV4::new(ip, 0);
-    let socket = try!(UdpSocket::bind(addr));
-    try!(socket.set_read_timeout(Some(timeout)));
-
-    try!(socket.send_to(
-        SEARCH_REQUEST.as_bytes(),
-        "239.255.255.250:1900",
-    ));
+    search_gateway_from_opts(SearchOptions {
+        bind_addr: SocketAddrV4::new(ip, 0),
+        timeout: timeout,
+        ..Default::default()
+    })
+}
+
+/// Search gateway, using the given `SearchOptions`.
+///
+/// The request will timeout after the configured duration with `SearchError::Timeout`. Responses
+/// which can not be parsed, and devices whose description can not be fetched or which are not
+/// gateways, are skipped while waiting for a gateway.
+pub fn search_gateway_from_opts(options: SearchOptions) -> Result<Gateway, SearchError> {
+    search_gateway_until(options, None)
+}
+
+// Search a gateway, cutting the fetch of its device description short at the deadline when one
+// is given.
+fn search_gateway_until(
+    options: SearchOptions,
+    deadline: Option<Instant>,
+) -> Result<Gateway, SearchError> {
+    let socket = try!(UdpSocket::bind(options.bind_addr));
+    let mut sender = SearchSender::new(&socket, &options);
+    let timer = Timer::default();
+    let mut skipped = Vec::new();
+    let mut buf = [0u8; 1024];
+    loop {
+        let wait = match try!(sender.poll()) {
+            Some(wait) => wait,
+            None => return Err(SearchError::Timeout),
+        };
+        try!(socket.set_read_timeout(Some(wait)));
+        let read = match socket.recv_from(&mut buf) {
+            Ok((read, _)) => read,
+            Err(ref e) if is_timeout(e) => continue,
+            Err(e) => return Err(SearchError::from(e)),
+        };
+        let response = match parse_ssdp_response(&buf[..read]) {
+            Ok(response) => response,
+            Err(err) => {
+                debug!("skipping invalid search response: {}", err);
+                continue;
+            }
+        };
+        let location = (response.addr, response.location_path);
+        if skipped.contains(&location) {
+            continue;
+        }
+        let timeout = match deadline {
+            Some(deadline) => match time_left(deadline) {
+                Some(left) if left < options.request_timeout => left,
+                Some(_) => options.request_timeout,
+                None => return Err(SearchError::Timeout),
+            },
+            None => options.request_timeout,
+        };
+        match get_control_url(&location, timeout, &timer) {
+            Ok(description) => {
+                let usn = response.usn;
+                return Ok(gateway_from_description(&location, usn, description, &options));
+            }
+            // Other UPnP devices, such as printers or media servers, answer wildcard searches.
+            Err(SearchError::NoConnectionService) => {
+                debug!("skipping http://{}{}, not a gateway", location.0, location.1);
+                skipped.push(location);
+            }
+            Err(err) => {
+                debug!("skipping http://{}{}: {}", location.0, location.1, err);
+                skipped.push(location);
+            }
+        }
+    }
+}
+
+/// Search all the gateways answering within the timeout, using the given `SearchOptions`.
+///
+/// Unlike the other search functions, this waits for the whole timeout before returning. Gateways
+/// answering several times are only returned once, and responses which can not be parsed or
+/// whose device description can not be fetched are skipped. `SearchError::NoResponse` is returned
+/// when no device answered at all, so that it can be told apart from devices which answered but
+/// are not usable gateways, for which an empty list is returned.
+pub fn search_gateways(options: SearchOptions) -> Result<Vec<Gateway>, SearchError> {
+    search_gateways_with(options, |_| ())
+}
+
+/// Search all the gateways answering within the timeout like `search_gateways`, calling
+/// `on_gateway` with each gateway as soon as it is found.
+///
+/// The device description of a gateway is fetched when its first response arrives, so that
+/// progress can be shown before the timeout expires.
+pub fn search_gateways_with<F>(
+    options: SearchOptions,
+    mut on_gateway: F,
+) -> Result<Vec<Gateway>, SearchError>
+where
+    F: FnMut(&Gateway),
+{
+    let socket = try!(UdpSocket::bind(options.bind_addr));
+    let mut sender = SearchSender::new(&socket, &options);
+    let timer = Timer::default();
+    let mut locations = Vec::new();
+    let mut gateways = Vec::new();
+    let mut answered = false;
     let mut buf = [0u8; 1024];
-    let (read, _) = try!(socket.recv_from(&mut buf));
-    let text = try!(str::from_utf8(&buf[..read]));
-
-    match parse_result(text) {
-        None => Err(SearchError::InvalidResponse),
-        Some(location) => {
-            let control_url = try!(get_control_url(&location));
-            Ok(Gateway {
-                addr: location.0,
-                control_url: control_url,
-            })
+    while let Some(wait) = try!(sender.poll()) {
+        try!(socket.set_read_timeout(Some(wait)));
+        let read = match socket.recv_from(&mut buf) {
+            Ok((read, _)) => read,
+            Err(ref e) if is_timeout(e) => continue,
+            Err(e) => return Err(SearchError::from(e)),
+        };
+        answered = true;
+        let response = match parse_ssdp_response(&buf[..read]) {
+            Ok(response) => response,
+            Err(_) => continue,
+        };
+        let location = (response.addr, response.location_path);
+        if locations.contains(&location) {
+            continue;
         }
+        locations.push(location.clone());
+        let gateway = match get_control_url(&location, options.request_timeout, &timer) {
+            Ok(description) => {
+                gateway_from_description(&location, response.usn, description, &options)
+            }
+            Err(err) => {
+                debug!("skipping http://{}{}: {}", location.0, location.1, err);
+                continue;
+            }
+        };
+        if !gateways.contains(&gateway) {
+            on_gateway(&gateway);
+            gateways.push(gateway);
+        }
+    }
+
+    if !answered {
+        return Err(SearchError::NoResponse);
+    }
+    Ok(gateways)
+}
+
+/// External IP address of a gateway, as returned by `diagnose_gateways`.
+#[derive(Debug)]
+pub struct GatewayDiagnostic {
+    /// The gateway
+    pub gateway: Gateway,
+    /// The external IP address of the gateway, or the error returned when asking for it
+    pub external_ip: Result<Ipv4Addr, GetExternalIpError>,
+}
+
+impl GatewayDiagnostic {
+    /// Whether the external IP address of the gateway is private (RFC 1918), meaning that it sits
+    /// behind another NAT and that the port mappings added on it are not reachable from the
+    /// internet on their own.
+    pub fn behind_nat(&self) -> bool {
+        self.external_ip.as_ref().map(|ip| ip.is_private()).unwrap_or(false)
     }
 }
 
+/// Ask each of the gateways for its external IP address, such as the gateways returned by
+/// `search_gateways`, to find the ones which sit behind another NAT.
+///
+/// When several gateways answer the search, one of them being behind another NAT usually means
+/// the network has two routers in a row, which the application may warn the user about.
+pub fn diagnose_gateways(gateways: &[Gateway]) -> Vec<GatewayDiagnostic> {
+    gateways
+        .iter()
+        .map(|gateway| GatewayDiagnostic {
+            gateway: gateway.clone(),
+            external_ip: gateway.get_external_ip(),
+        })
+        .collect()
+}
+
+/// Search a gateway and map the given local port on any external port.
+///
+/// The gateway is searched with the default `SearchOptions`, and the port mapping is added for
+/// the local IP address used to reach it, see `Gateway::get_local_ip` and `Gateway::add_any_port`.
+/// The gateway is returned along with the external port, for instance to remove the port mapping
+/// later. The lease_duration parameter is in seconds. A value of 0 is infinite.
+pub fn map_port(
+    protocol: PortMappingProtocol,
+    local_port: u16,
+    lease_duration: u32,
+    description: &str,
+) -> Result<(Gateway, u16), MapLocalPortError> {
+    let gateway = try!(search_gateway_from_opts(Default::default()));
+    let local_addr = SocketAddrV4::new(try!(gateway.get_local_ip()), local_port);
+    let external_port =
+        try!(gateway.add_any_port(protocol, local_addr, lease_duration, description));
+    Ok((gateway, external_port))
+}
+
+/// Search a gateway and map the given local port on any external port, giving up at the deadline.
+///
+/// This is `map_port` with a single deadline for the whole sequence instead of separate timeouts:
+/// the search, the fetch of the device description and the requests adding the port mapping only
+/// get the time left, and `MapLocalPortError::Timeout` is returned when it runs out. The gateway
+/// returned keeps the default `request_timeout`.
+pub fn map_port_within(
+    deadline: Instant,
+    protocol: PortMappingProtocol,
+    local_port: u16,
+    lease_duration: u32,
+    description: &str,
+) -> Result<(Gateway, u16), MapLocalPortError> {
+    let options = SearchOptions {
+        timeout: try!(time_left(deadline).ok_or(MapLocalPortError::Timeout)),
+        ..Default::default()
+    };
+    let gateway = match search_gateway_until(options, Some(deadline)) {
+        Ok(gateway) => gateway,
+        Err(SearchError::Timeout) => return Err(MapLocalPortError::Timeout),
+        Err(err) => return Err(MapLocalPortError::SearchError(err)),
+    };
+    let local_addr = SocketAddrV4::new(try!(gateway.get_local_ip()), local_port);
+    let left = try!(time_left(deadline).ok_or(MapLocalPortError::Timeout));
+    let mut core = Core::new().unwrap();
+    let async = AsyncGateway::from_gateway(gateway.clone(), core.handle());
+    let future = async
+        .add_any_port(protocol, local_addr, lease_duration, description)
+        .map_err(MapLocalPortError::from);
+    let external_port = try!(core.run(Timer::default().timeout(future, left)));
+    Ok((gateway, external_port))
+}
+
+// Time left until the deadline, None once it has passed.
+fn time_left(deadline: Instant) -> Option<Duration> {
+    let now = Instant::now();
+    if now < deadline {
+        Some(deadline - now)
+    } else {
+        None
+    }
+}
+
+lazy_static! {
+    // Location header of a search response, compiled once since it is matched against every
+    // response received.
+    static ref LOCATION: Regex =
+        Regex::new(r"(?i:Location):\s*http://(\d+\.\d+\.\d+\.\d+):(\d+)(/[^\r]*)").unwrap();
+}
+
 // Parse the result.
 pub fn parse_result(text: &str) -> Option<(SocketAddrV4, String)> {
-    let re = Regex::new(
-        r"(?i:Location):\s*http://(\d+\.\d+\.\d+\.\d+):(\d+)(/[^\r]*)",
-    ).unwrap();
     for line in text.lines() {
-        match re.captures(line) {
+        match LOCATION.captures(line) {
             None => continue,
             Some(cap) => {
-                // these shouldn't fail if the regex matched.
-                let addr = &cap[1];
-                let port = &cap[2];
-                return Some((
-                    SocketAddrV4::new(
-                        addr.parse::<Ipv4Addr>().unwrap(),
-                        port.parse::<u16>().unwrap(),
-                    ),
-                    cap[3].to_string(),
-                ));
+                // the regex accepts out of range values such as 999.999.999.999
+                let addr = cap[1].parse::<Ipv4Addr>().ok()?;
+                let port = cap[2].parse::<u16>().ok()?;
+                return Some((SocketAddrV4::new(addr, port), cap[3].to_string()));
             }
         }
     }
     None
 }
 
-fn get_control_url(location: &(SocketAddrV4, String)) -> Result<String, SearchError> {
+// Find the unique service name in the search response.
+pub fn parse_usn(text: &str) -> Option<String> {
+    parse_header(text, "USN")
+}
+
+// Value of the first header with the given name, compared case-insensitively.
+fn parse_header(text: &str, name: &str) -> Option<String> {
+    text.lines()
+        .filter_map(|line| {
+            let colon = line.find(':')?;
+            if line[..colon].trim().eq_ignore_ascii_case(name) {
+                Some(line[colon + 1..].trim().to_string())
+            } else {
+                None
+            }
+        })
+        .next()
+}
+
+/// Headers of an SSDP search response or `NOTIFY` advertisement, see `parse_ssdp_response`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SsdpResponse {
+    /// Address of the device, from the `LOCATION` header
+    pub addr: SocketAddrV4,
+    /// Path of the device description on the device, from the `LOCATION` header
+    pub location_path: String,
+    /// Search target (`ST` header) of a search response, or notification type (`NT` header) of
+    /// an advertisement
+    pub search_target: Option<String>,
+    /// Unique service name (`USN` header)
+    pub usn: Option<String>,
+}
+
+impl SsdpResponse {
+    /// Url of the device description.
+    pub fn location(&self) -> String {
+        format!("http://{}{}", self.addr, self.location_path)
+    }
+}
+
+/// Parse an SSDP search response, or a `NOTIFY` advertisement received on the multicast group.
+///
+/// This is the parser used by the search functions, for programs receiving the SSDP messages
+/// themselves. `SearchError::InvalidResponse` is returned when the message has no `LOCATION` header
+/// with an IPv4 address, as is the case for `ssdp:byebye` advertisements.
+pub fn parse_ssdp_response(response: &[u8]) -> Result<SsdpResponse, SearchError> {
+    let text = try!(str::from_utf8(response));
+    let (addr, location_path) = try!(parse_result(text).ok_or(SearchError::InvalidResponse));
+    Ok(SsdpResponse {
+        addr: addr,
+        location_path: location_path,
+        search_target: parse_header(text, "ST").or_else(|| parse_header(text, "NT")),
+        usn: parse_usn(text),
+    })
+}
+
+// Build the gateway from the services found in its device description.
+pub fn gateway_from_description(
+    location: &(SocketAddrV4, String),
+    usn: Option<String>,
+    description: DeviceDescription,
+    options: &SearchOptions,
+) -> Gateway {
+    let mut gateway = Gateway::new(location.0, &description.control_url);
+    gateway.service_type = description.service_type;
+    gateway.service_id = description.service_id;
+    gateway.connection_services = description.connection_services;
+    gateway.common_interface_control_url = description.common_interface_control_url;
+    gateway.ipv6_firewall_control_url = description.ipv6_firewall_control_url;
+    gateway.friendly_name = description.friendly_name;
+    gateway.manufacturer = description.manufacturer;
+    gateway.model_name = description.model_name;
+    gateway.location = Some(format!("http://{}{}", location.0, location.1));
+    gateway.usn = usn;
+    gateway.retry_options.cancel = options.cancel.clone();
+    gateway.request_timeout = options.request_timeout;
+    // Control requests go through the interface the search was bound to.
+    if !options.bind_addr.ip().is_unspecified() {
+        gateway.http_options.local_addr = Some(*options.bind_addr.ip());
+    }
+    gateway
+}
+
+fn get_control_url(
+    location: &(SocketAddrV4, String),
+    timeout: Duration,
+    timer: &Timer,
+) -> Result<DeviceDescription, SearchError> {
     let mut core = Core::new()?;
-    let handle = core.handle();
-    core.run(get_control_url_async(location, &handle))
+    core.run(timer.timeout(get_control_url_async(location), timeout))
 }
 
 
@@ -118,3 +544,345 @@ fn test_parse_result() {
     assert_eq!(result.0.port(), 0);
     assert_eq!(&result.1[..], "/control_url");
 }
+
+#[test]
+fn test_parse_usn() {
+    let text = "HTTP/1.1 200 OK\r
+LOCATION: http://192.168.1.1:5000/rootDesc.xml\r
+usn: uuid:2e8f::urn:schemas-upnp-org:device:InternetGatewayDevice:1\r
+\r
+";
+    assert_eq!(
+        parse_usn(text),
+        Some("uuid:2e8f::urn:schemas-upnp-org:device:InternetGatewayDevice:1".to_string())
+    );
+    assert_eq!(parse_usn("LOCATION: http://192.168.1.1:5000/rootDesc.xml"), None);
+}
+
+#[test]
+fn test_parse_ssdp_response() {
+    let notify = b"NOTIFY * HTTP/1.1\r
+HOST: 239.255.255.250:1900\r
+LOCATION: http://192.168.1.1:5000/rootDesc.xml\r
+NT: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r
+NTS: ssdp:alive\r
+USN: uuid:2e8f::urn:schemas-upnp-org:device:InternetGatewayDevice:1\r
+\r
+";
+    let response = parse_ssdp_response(notify).unwrap();
+    assert_eq!(response.addr, "192.168.1.1:5000".parse().unwrap());
+    assert_eq!(response.location_path, "/rootDesc.xml");
+    assert_eq!(response.location(), "http://192.168.1.1:5000/rootDesc.xml");
+    assert_eq!(response.search_target, Some(INTERNET_GATEWAY_DEVICE.to_string()));
+    assert_eq!(
+        response.usn,
+        Some("uuid:2e8f::urn:schemas-upnp-org:device:InternetGatewayDevice:1".to_string())
+    );
+
+    let search = b"HTTP/1.1 200 OK\r
+st: upnp:rootdevice\r
+Location: http://10.0.0.1:80/d.xml\r
+\r
+";
+    let response = parse_ssdp_response(search).unwrap();
+    assert_eq!(response.search_target, Some("upnp:rootdevice".to_string()));
+    assert_eq!(response.usn, None);
+
+    let byebye = b"NOTIFY * HTTP/1.1\r\nNTS: ssdp:byebye\r\nUSN: uuid:2e8f\r\n\r\n";
+    match parse_ssdp_response(byebye) {
+        Err(SearchError::InvalidResponse) => (),
+        result => panic!("unexpected result: {:?}", result),
+    }
+    match parse_ssdp_response(&[0xff, 0xfe]) {
+        Err(SearchError::Utf8Error(_)) => (),
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn test_parse_result_out_of_range() {
+    assert!(parse_result("location:http://999.999.999.999:0/control_url").is_none());
+    assert!(parse_result("location:http://0.0.0.0:99999/control_url").is_none());
+}
+
+#[test]
+fn test_format_search_request_host() {
+    let request = format_search_request("127.0.0.1:1901".parse().unwrap(), "ssdp:all");
+    assert!(request.contains("Host:127.0.0.1:1901\r\n"));
+    assert!(request.contains("ST:ssdp:all\r\n"));
+}
+
+#[test]
+fn test_search_retransmissions() {
+    use std::thread;
+
+    let responder = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let broadcast_address = match responder.local_addr().unwrap() {
+        ::std::net::SocketAddr::V4(addr) => addr,
+        addr => panic!("unexpected address: {}", addr),
+    };
+    let options = SearchOptions {
+        bind_addr: "127.0.0.1:0".parse().unwrap(),
+        broadcast_address: broadcast_address,
+        timeout: Duration::from_secs(1),
+        search_targets: vec![INTERNET_GATEWAY_DEVICE.to_string(), "ssdp:all".to_string()],
+        retransmissions: 2,
+        request_timeout: Duration::from_secs(10),
+        cancel: CancelFlag::default(),
+    };
+    let socket = UdpSocket::bind(options.bind_addr).unwrap();
+    let mut sender = SearchSender::new(&socket, &options);
+    while let Some(wait) = sender.poll().unwrap() {
+        if sender.sent > options.retransmissions {
+            break;
+        }
+        thread::sleep(wait);
+    }
+
+    responder.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+    let mut buf = [0u8; 1024];
+    let mut received = 0;
+    while responder.recv_from(&mut buf).is_ok() {
+        received += 1;
+    }
+    assert_eq!(received, 6);
+}
+
+#[test]
+fn test_search_no_gateway() {
+    // Nothing listens on the discard port of the loopback interface.
+    let options = SearchOptions {
+        bind_addr: "127.0.0.1:0".parse().unwrap(),
+        broadcast_address: "127.0.0.1:9".parse().unwrap(),
+        timeout: Duration::from_millis(200),
+        ..Default::default()
+    };
+    match search_gateway_from_opts(options.clone()) {
+        Err(ref err) if err.is_no_gateway() => (),
+        result => panic!("unexpected result: {:?}", result),
+    }
+    match search_gateways(options) {
+        Err(SearchError::NoResponse) => (),
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn test_search_cancelled() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    let cancel = Arc::new(AtomicBool::new(false));
+    let options = SearchOptions {
+        bind_addr: "127.0.0.1:0".parse().unwrap(),
+        broadcast_address: "127.0.0.1:9".parse().unwrap(),
+        timeout: Duration::from_secs(30),
+        cancel: CancelFlag::new(cancel.clone()),
+        ..Default::default()
+    };
+    let flag = cancel.clone();
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(200));
+        flag.store(true, Ordering::SeqCst);
+    });
+    let start = Instant::now();
+    match search_gateway_from_opts(options.clone()) {
+        Err(SearchError::Cancelled) => (),
+        result => panic!("unexpected result: {:?}", result),
+    }
+    assert!(start.elapsed() < Duration::from_secs(5));
+    match search_gateways(options) {
+        Err(SearchError::Cancelled) => (),
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn test_get_control_url_timeout() {
+    use std::net::{SocketAddr, TcpListener};
+
+    // Accept the connection but never answer.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = match listener.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+    let location = (addr, "/rootDesc.xml".to_string());
+    match get_control_url(&location, Duration::from_millis(100), &Timer::default()) {
+        Err(SearchError::Timeout) => (),
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn test_map_port_within_deadline_passed() {
+    let deadline = Instant::now();
+    match map_port_within(deadline, PortMappingProtocol::TCP, 8080, 0, "test") {
+        Err(MapLocalPortError::Timeout) => (),
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn test_search_gateways_with() {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread;
+
+    // Serve the device description once.
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let location = server.local_addr().unwrap();
+    thread::spawn(move || {
+        let description = "<?xml version=\"1.0\"?>
+<root xmlns=\"urn:schemas-upnp-org:device-1-0\"><device><serviceList><service>
+<serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>
+<controlURL>/ctl/IPConn</controlURL>
+</service></serviceList></device></root>";
+        let (mut stream, _) = server.accept().unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !request.ends_with(b"\r\n\r\n") {
+            let read = stream.read(&mut buf).unwrap();
+            request.extend_from_slice(&buf[..read]);
+        }
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            description.len(),
+            description
+        ).unwrap();
+    });
+
+    // Answer every search request, so that the gateway answers several times.
+    let responder = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let broadcast_address = match responder.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        addr => panic!("unexpected address: {}", addr),
+    };
+    thread::spawn(move || {
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nLOCATION: http://{}/rootDesc.xml\r\nUSN: uuid:test\r\n\r\n",
+            location
+        );
+        let mut buf = [0u8; 1024];
+        responder.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        while let Ok((_, from)) = responder.recv_from(&mut buf) {
+            responder.send_to(response.as_bytes(), from).unwrap();
+        }
+    });
+
+    let options = SearchOptions {
+        bind_addr: "127.0.0.1:0".parse().unwrap(),
+        broadcast_address: broadcast_address,
+        timeout: Duration::from_millis(500),
+        ..Default::default()
+    };
+    let mut found = Vec::new();
+    let gateways = search_gateways_with(options, |gateway| found.push(gateway.clone())).unwrap();
+    assert_eq!(gateways.len(), 1);
+    assert_eq!(found, gateways);
+    assert_eq!(gateways[0].control_url, "/ctl/IPConn");
+    assert_eq!(gateways[0].usn, Some("uuid:test".to_string()));
+}
+
+#[test]
+fn test_diagnose_gateways() {
+    use mock::MockTransport;
+
+    let gateway = |addr: &str, external_ip| {
+        let mut gateway = Gateway::new(addr.parse().unwrap(), "/ctl/IPConn");
+        gateway.transport = MockTransport::external_ip(external_ip);
+        gateway
+    };
+    let gateways = [
+        gateway("192.168.1.1:1900", "192.168.0.2"),
+        gateway("192.168.0.1:1900", "203.0.113.1"),
+    ];
+    let diagnostics = diagnose_gateways(&gateways);
+    assert_eq!(diagnostics.len(), 2);
+    assert_eq!(diagnostics[0].gateway, gateways[0]);
+    assert!(diagnostics[0].behind_nat());
+    assert_eq!(diagnostics[1].external_ip.as_ref().ok(), Some(&Ipv4Addr::new(203, 0, 113, 1)));
+    assert!(!diagnostics[1].behind_nat());
+}
+
+#[test]
+fn test_search_gateway_skips_other_devices() {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread;
+
+    // Serve the same response to every client.
+    let serve = |status: &'static str, body: String| {
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let location = server.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in server.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let read = stream.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..read]);
+                }
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                ).unwrap();
+            }
+        });
+        location
+    };
+    let description = |service_type| {
+        format!(
+            "<?xml version=\"1.0\"?>
+<root xmlns=\"urn:schemas-upnp-org:device-1-0\"><device><serviceList><service>
+<serviceType>{}</serviceType>
+<controlURL>/ctl/Service</controlURL>
+</service></serviceList></device></root>",
+            service_type
+        )
+    };
+    let printer = serve("200 OK", description("urn:schemas-upnp-org:service:PrintBasic:1"));
+    let missing = serve("404 Not Found", String::new());
+    let gateway = serve("200 OK", description("urn:schemas-upnp-org:service:WANIPConnection:1"));
+
+    // An invalid response, the printer and a device without description answer each search
+    // request before the gateway.
+    let responder = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let broadcast_address = match responder.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        addr => panic!("unexpected address: {}", addr),
+    };
+    thread::spawn(move || {
+        let mut buf = [0u8; 1024];
+        responder.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        while let Ok((_, from)) = responder.recv_from(&mut buf) {
+            responder.send_to(b"HTTP/1.1 200 OK\r\n\r\n", from).unwrap();
+            for location in &[printer, missing, gateway] {
+                let response =
+                    format!("HTTP/1.1 200 OK\r\nLOCATION: http://{}/rootDesc.xml\r\n\r\n", location);
+                responder.send_to(response.as_bytes(), from).unwrap();
+            }
+        }
+    });
+
+    let options = SearchOptions {
+        bind_addr: "127.0.0.1:0".parse().unwrap(),
+        broadcast_address: broadcast_address,
+        timeout: Duration::from_secs(1),
+        ..Default::default()
+    };
+    let found = search_gateway_from_opts(options.clone()).unwrap();
+    assert_eq!(SocketAddr::V4(found.addr), gateway);
+    assert_eq!(found.control_url, "/ctl/Service");
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let found = core.run(::async::search_gateway_from_opts(options, &handle)).unwrap();
+    assert_eq!(format!("{}", found), format!("http://{}/ctl/Service", gateway));
+}
@@ -0,0 +1,179 @@
+//! HTTP backend built on hyper 0.11, enabled by the `hyper-client` feature.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs};
+
+use futures::{Future, Stream};
+use futures::future;
+use hyper;
+use hyper::{Client, Method, Request, Response, Uri};
+use hyper::client::Service;
+use hyper::header::ContentLength;
+use net2::TcpBuilder;
+use tokio_current_thread::TaskExecutor;
+use tokio_reactor::Handle;
+use tokio_tcp::TcpStream;
+
+use errors::{HttpError, RequestError};
+use super::{send_with, HttpOptions, HttpRequest, HttpResponse, SoapTransport};
+
+thread_local! {
+    // Client shared by the requests polled on this thread, so that the requests sent in a row
+    // reuse the same connection when the gateway keeps it alive.
+    static CLIENT: Client<Connector> = new_client(None);
+}
+
+/// Transport sending the SOAP requests over HTTP with hyper, used by default.
+///
+/// The connections are opened on the event loop polling the requests, and reused by the requests
+/// polled on the same event loop unless a local address is set. The blocking `Gateway` runs each
+/// request on its own event loop, except the ones of `iter_port_mappings`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct HttpTransport;
+
+impl HttpTransport {
+    /// Create a transport.
+    pub fn new() -> HttpTransport {
+        HttpTransport
+    }
+}
+
+impl SoapTransport for HttpTransport {
+    fn send(
+        &self,
+        url: &str,
+        action: &str,
+        body: &str,
+        user_agent: &str,
+        options: &HttpOptions,
+    ) -> Box<Future<Item = String, Error = RequestError>> {
+        self.send_into(url, action, body, user_agent, options, String::new())
+    }
+
+    fn send_into(
+        &self,
+        url: &str,
+        action: &str,
+        body: &str,
+        user_agent: &str,
+        options: &HttpOptions,
+        buffer: String,
+    ) -> Box<Future<Item = String, Error = RequestError>> {
+        let local_addr = options.local_addr;
+        let post = move |request: &HttpRequest, buffer| post(request, local_addr, buffer);
+        send_with(post, url, action, body, user_agent, options, buffer)
+    }
+}
+
+/// Fetch the document at the url, such as the device description of a gateway.
+pub fn get(url: &str) -> Box<Future<Item = Vec<u8>, Error = HttpError>> {
+    let uri: Uri = match url.parse() {
+        Ok(uri) => uri,
+        Err(err) => return Box::new(future::err(HttpError::from(hyper::Error::from(err)))),
+    };
+    let future = CLIENT
+        .with(|client| client.get(uri))
+        .and_then(|response| response.body().concat2())
+        .map(|body| body.to_vec())
+        .map_err(HttpError::from);
+    Box::new(future)
+}
+
+fn post(
+    request: &HttpRequest,
+    local_addr: Option<Ipv4Addr>,
+    buffer: Vec<u8>,
+) -> Box<Future<Item = HttpResponse, Error = HttpError>> {
+    let uri: Uri = match request.url.parse() {
+        Ok(uri) => uri,
+        Err(err) => return Box::new(future::err(HttpError::from(hyper::Error::from(err)))),
+    };
+    let mut req = Request::new(Method::Post, uri);
+    for &(name, ref value) in &request.headers {
+        req.headers_mut().set_raw(name, value.clone());
+    }
+    req.headers_mut().set(ContentLength(request.body.len() as u64));
+    req.set_body(request.body.clone());
+    let response = match local_addr {
+        Some(_) => new_client(local_addr).request(req),
+        None => CLIENT.with(|client| client.request(req)),
+    };
+    let future = response.and_then(move |response| read_response(response, buffer));
+    Box::new(future.map_err(HttpError::from))
+}
+
+fn read_response(
+    response: Response,
+    buffer: Vec<u8>,
+) -> Box<Future<Item = HttpResponse, Error = hyper::Error>> {
+    let status = response.status().as_u16();
+    let headers = response
+        .headers()
+        .iter()
+        .map(|header| (header.name().to_owned(), header.value_string()))
+        .collect();
+    let body = response.body().fold(buffer, |mut body, chunk| {
+        body.extend_from_slice(&chunk);
+        Ok::<_, hyper::Error>(body)
+    });
+    let future = body.map(move |body| HttpResponse {
+        status: status,
+        headers: headers,
+        body: body,
+    });
+    Box::new(future)
+}
+
+// The client runs its connections on the event loop polling the requests, so that it does not
+// need a handle to it.
+fn new_client(local_addr: Option<Ipv4Addr>) -> Client<Connector> {
+    Client::configure()
+        .connector(Connector { local_addr: local_addr })
+        .executor(TaskExecutor::current())
+}
+
+// Connector opening the connections on the reactor of the event loop, from the local address when
+// one is set.
+#[derive(Clone, Copy, Debug)]
+struct Connector {
+    local_addr: Option<Ipv4Addr>,
+}
+
+impl Service for Connector {
+    type Request = Uri;
+    type Response = TcpStream;
+    type Error = io::Error;
+    type Future = Box<Future<Item = TcpStream, Error = io::Error>>;
+
+    fn call(&self, uri: Uri) -> Self::Future {
+        let addr = match resolve(&uri) {
+            Ok(addr) => addr,
+            Err(err) => return Box::new(future::err(err)),
+        };
+        let local_addr = match self.local_addr {
+            Some(local_addr) => local_addr,
+            None => return Box::new(TcpStream::connect(&addr)),
+        };
+        let builder = match TcpBuilder::new_v4() {
+            Ok(builder) => builder,
+            Err(err) => return Box::new(future::err(err)),
+        };
+        let stream = builder
+            .bind(SocketAddrV4::new(local_addr, 0))
+            .and_then(|builder| builder.to_tcp_stream());
+        match stream {
+            Ok(stream) => Box::new(TcpStream::connect_std(stream, &addr, &Handle::default())),
+            Err(err) => Box::new(future::err(err)),
+        }
+    }
+}
+
+// Find the IPv4 address of the host of the url. Control urls hold the IP address of the gateway,
+// so there is usually no host name to resolve.
+fn resolve(uri: &Uri) -> io::Result<SocketAddr> {
+    let host = uri.host().unwrap_or_default();
+    let mut addrs = try!((host, uri.port().unwrap_or(80)).to_socket_addrs());
+    addrs
+        .find(|addr| addr.is_ipv4())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, uri.to_string()))
+}
@@ -0,0 +1,716 @@
+use std::fmt;
+use std::io::Read;
+use std::net::Ipv4Addr;
+use std::str;
+use std::sync::{Arc, Mutex};
+
+use futures::Future;
+use futures::future;
+use flate2::read::GzDecoder;
+use md5;
+use rand;
+
+use errors::{HttpError, RequestError};
+
+#[cfg(feature = "hyper-client")]
+mod hyper_client;
+#[cfg(feature = "reqwest-client")]
+mod reqwest_client;
+
+#[cfg(feature = "hyper-client")]
+pub use self::hyper_client::{get, HttpTransport};
+#[cfg(feature = "reqwest-client")]
+pub use self::reqwest_client::ReqwestTransport;
+#[cfg(all(feature = "reqwest-client", not(feature = "hyper-client")))]
+pub use self::reqwest_client::get;
+
+#[cfg(not(any(feature = "hyper-client", feature = "reqwest-client")))]
+compile_error!("one of the hyper-client and reqwest-client features is required");
+
+// User-Agent sent unless the gateway is configured otherwise.
+pub const DEFAULT_USER_AGENT: &str =
+    concat!("rust-igd/", env!("CARGO_PKG_VERSION"), " UPnP/1.1");
+
+// Content-Type sent with the requests unless the gateway is configured otherwise.
+pub const DEFAULT_CONTENT_TYPE: &str = "text/xml";
+
+#[derive(Clone, Debug)]
+pub struct Action(String);
+
+impl Action {
+    pub fn new(action: &str) -> Action {
+        Action(action.into())
+    }
+
+    /// The value of the header.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Whether the action is enclosed in double quotes, as required by the UPnP specification.
+    pub fn is_quoted(&self) -> bool {
+        self.0.len() >= 2 && self.0.starts_with('"') && self.0.ends_with('"')
+    }
+
+    /// The same action with the other quoting style: quoted actions lose their double quotes and
+    /// unquoted ones get them. Some gateways only accept one of the styles.
+    pub fn with_flipped_quoting(&self) -> Action {
+        if self.is_quoted() {
+            Action(self.0[1..self.0.len() - 1].to_owned())
+        } else {
+            Action(format!("\"{}\"", self.0))
+        }
+    }
+
+    /// The same action, quoted or not as asked.
+    pub fn with_quoting(&self, quoted: bool) -> Action {
+        if self.is_quoted() == quoted {
+            self.clone()
+        } else {
+            self.with_flipped_quoting()
+        }
+    }
+}
+
+// Request posted by the client of an HTTP transport.
+#[derive(Clone, Debug)]
+pub struct HttpRequest {
+    pub url: String,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: String,
+}
+
+// Response read whole by the client of an HTTP transport, into the buffer given with the
+// request.
+#[derive(Debug)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|header| header.0.eq_ignore_ascii_case(name))
+            .map(|header| &header.1[..])
+    }
+}
+
+// Send a SOAP request with the given function posting it, which is all the HTTP transports have
+// to provide. The digest challenge of gateways protecting their control url is answered here, and
+// the body of the response is decoded in the buffer.
+fn send_with<P>(
+    post: P,
+    url: &str,
+    action: &str,
+    body: &str,
+    user_agent: &str,
+    options: &HttpOptions,
+    buffer: String,
+) -> Box<Future<Item = String, Error = RequestError>>
+where
+    P: Fn(&HttpRequest, Vec<u8>) -> Box<Future<Item = HttpResponse, Error = HttpError>> + 'static,
+{
+    let content_type = match options.content_type {
+        Some(ref content_type) => content_type,
+        None => DEFAULT_CONTENT_TYPE,
+    };
+    let body = if options.omit_xml_declaration {
+        strip_xml_declaration(body)
+    } else {
+        body
+    };
+    let request = HttpRequest {
+        url: url.to_owned(),
+        headers: vec![
+            ("SOAPAction", action.to_owned()),
+            ("Content-Type", content_type.to_owned()),
+            ("User-Agent", user_agent.to_owned()),
+        ],
+        body: body.to_owned(),
+    };
+    let credentials = options.credentials.clone();
+    let mut buffer = buffer.into_bytes();
+    buffer.clear();
+    let future = post(&request, buffer)
+        .and_then(move |response| -> Box<Future<Item = HttpResponse, Error = HttpError>> {
+            let authorization = match credentials {
+                Some(ref credentials) if response.status == 401 => response
+                    .header("WWW-Authenticate")
+                    .and_then(parse_digest_challenge)
+                    .map(|challenge| {
+                        let cnonce = format!("{:016x}", rand::random::<u64>());
+                        let uri = url_path(&request.url);
+                        digest_authorization(&challenge, credentials, "POST", uri, &cnonce)
+                    }),
+                _ => None,
+            };
+            match authorization {
+                Some(authorization) => {
+                    let mut request = request;
+                    request.headers.push(("Authorization", authorization));
+                    post(&request, response.body)
+                }
+                None => Box::new(future::ok(response)),
+            }
+        })
+        .map_err(RequestError::HttpError)
+        .and_then(|response| {
+            let gzip = response
+                .header("Content-Encoding")
+                .map(|encoding| {
+                    encoding
+                        .split(',')
+                        .any(|encoding| encoding.trim().eq_ignore_ascii_case("gzip"))
+                })
+                .unwrap_or(false);
+            let text = try!(decode_body(response.body, gzip));
+            if response.status >= 200 && response.status < 300 {
+                Ok(text)
+            } else {
+                // Keep the headers, they tell where a redirect points to or which authentication
+                // the gateway asks for.
+                Err(RequestError::HttpStatus(response.status, text, response.headers))
+            }
+        });
+    Box::new(future)
+}
+
+// Path of the url, which the digest authorization is computed on.
+fn url_path(url: &str) -> &str {
+    let rest = match url.find("://") {
+        Some(start) => &url[start + 3..],
+        None => url,
+    };
+    match rest.find('/') {
+        Some(start) => rest[start..].split('?').next().unwrap_or("/"),
+        None => "/",
+    }
+}
+
+/// Credentials used to authenticate to gateways which protect their control url with HTTP
+/// digest authentication.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Credentials {
+    /// The user name
+    pub username: String,
+    /// The password
+    pub password: String,
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Keep the password out of the logs.
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .finish()
+    }
+}
+
+// Parameters of a digest challenge sent in the WWW-Authenticate header.
+#[derive(Debug, PartialEq)]
+struct DigestChallenge {
+    realm: String,
+    nonce: String,
+    opaque: Option<String>,
+    qop_auth: bool,
+}
+
+fn parse_digest_challenge(header: &str) -> Option<DigestChallenge> {
+    let header = header.trim();
+    if header.len() < 6 || !header[..6].eq_ignore_ascii_case("digest") {
+        return None;
+    }
+    let mut params = Vec::new();
+    let mut rest = header[6..].trim_start();
+    while !rest.is_empty() {
+        let eq = rest.find('=')?;
+        let name = rest[..eq].trim().to_lowercase();
+        rest = rest[eq + 1..].trim_start();
+        let value = if rest.starts_with('"') {
+            let end = rest[1..].find('"')? + 1;
+            let value = rest[1..end].to_string();
+            rest = &rest[end + 1..];
+            value
+        } else {
+            let end = rest.find(',').unwrap_or(rest.len());
+            let value = rest[..end].trim().to_string();
+            rest = &rest[end..];
+            value
+        };
+        rest = rest.trim_start().trim_start_matches(',').trim_start();
+        params.push((name, value));
+    }
+    let param = |name: &str| {
+        params
+            .iter()
+            .find(|param| param.0 == name)
+            .map(|param| param.1.clone())
+    };
+    // Only MD5 is supported, which is the default algorithm.
+    match param("algorithm") {
+        Some(ref algorithm) if !algorithm.eq_ignore_ascii_case("md5") => return None,
+        _ => (),
+    }
+    Some(DigestChallenge {
+        realm: param("realm")?,
+        nonce: param("nonce")?,
+        opaque: param("opaque"),
+        qop_auth: param("qop")
+            .map(|qop| qop.split(',').any(|qop| qop.trim() == "auth"))
+            .unwrap_or(false),
+    })
+}
+
+// Build the Authorization header answering the challenge, as described in RFC 2617.
+fn digest_authorization(
+    challenge: &DigestChallenge,
+    credentials: &Credentials,
+    method: &str,
+    uri: &str,
+    cnonce: &str,
+) -> String {
+    let ha1 = md5::compute(format!(
+        "{}:{}:{}",
+        credentials.username, challenge.realm, credentials.password
+    ));
+    let ha2 = md5::compute(format!("{}:{}", method, uri));
+    let mut authorization = format!(
+        "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\"",
+        credentials.username, challenge.realm, challenge.nonce, uri
+    );
+    if challenge.qop_auth {
+        let nc = "00000001";
+        let response = md5::compute(format!(
+            "{:x}:{}:{}:{}:auth:{:x}",
+            ha1, challenge.nonce, nc, cnonce, ha2
+        ));
+        authorization.push_str(&format!(
+            ", qop=auth, nc={}, cnonce=\"{}\", response=\"{:x}\"",
+            nc, cnonce, response
+        ));
+    } else {
+        let response = md5::compute(format!("{:x}:{}:{:x}", ha1, challenge.nonce, ha2));
+        authorization.push_str(&format!(", response=\"{:x}\"", response));
+    }
+    if let Some(ref opaque) = challenge.opaque {
+        authorization.push_str(&format!(", opaque=\"{}\"", opaque));
+    }
+    authorization
+}
+
+// Decode the body of a response, which may be compressed with gzip. Plain bodies are trimmed in
+// place, so that the text keeps the buffer they were read into. Bodies which can not be decoded
+// are invalid responses rather than transport errors, since sending the request again would not
+// fix them.
+fn decode_body(body: Vec<u8>, gzip: bool) -> Result<String, RequestError> {
+    let mut decoded = if gzip {
+        let mut decoded = Vec::new();
+        if let Err(err) = GzDecoder::new(&body[..]).read_to_end(&mut decoded) {
+            return Err(RequestError::InvalidResponse(format!("invalid gzip body: {}", err)));
+        }
+        decoded
+    } else {
+        body
+    };
+    let start = decoded.len() - strip_preamble(&decoded).len();
+    decoded.drain(..start);
+    String::from_utf8(decoded).map_err(|err| {
+        RequestError::InvalidResponse(String::from_utf8_lossy(err.as_bytes()).into_owned())
+    })
+}
+
+// Skip what some gateways send before the XML document, like a UTF-8 BOM or stray bytes. Bodies
+// which are not XML are kept whole.
+fn strip_preamble(bytes: &[u8]) -> &[u8] {
+    match bytes.iter().position(|&byte| byte == b'<') {
+        Some(start) => &bytes[start..],
+        None => bytes,
+    }
+}
+
+/// HTTP settings of the SOAP requests sent to a gateway, passed to its transport with every
+/// request.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct HttpOptions {
+    /// Credentials used when the gateway requires HTTP digest authentication, none by default
+    pub credentials: Option<Credentials>,
+    /// Local address the requests are sent from, so that they leave through the interface facing
+    /// the gateway on hosts with several interfaces. The connections are not reused from one
+    /// request to the next when it is set.
+    pub local_addr: Option<Ipv4Addr>,
+    /// Content-Type header of the requests, `text/xml` by default. Some gateways insist on
+    /// `text/xml; charset="utf-8"`.
+    pub content_type: Option<String>,
+    /// Send the requests without the `<?xml ...?>` declaration, which some firmwares reject
+    pub omit_xml_declaration: bool,
+}
+
+/// Transport used to send the SOAP requests to the gateway.
+///
+/// `HttpTransport` sends the requests over HTTP with hyper and is used by default,
+/// `ReqwestTransport` sends them with reqwest. Another transport can be set on the `Gateway`, for
+/// instance to test code using this crate without a gateway, or to send the requests with the HTTP
+/// client the application already uses.
+///
+/// The future returned by a transport is polled on the event loop of the gateway.
+pub trait SoapTransport: fmt::Debug {
+    /// Send the body to the url with the given SOAPAction and User-Agent headers, and return the
+    /// text of the response.
+    ///
+    /// The options are the HTTP settings of the gateway, transports which do not send the
+    /// requests over HTTP can ignore them.
+    fn send(
+        &self,
+        url: &str,
+        action: &str,
+        body: &str,
+        user_agent: &str,
+        options: &HttpOptions,
+    ) -> Box<Future<Item = String, Error = RequestError>>;
+
+    /// Same as `send`, reading the response into the given buffer, which is cleared first and
+    /// returned holding the text of the response. Reusing the buffer from one request to the
+    /// next saves an allocation per request once it is large enough.
+    ///
+    /// The default implementation ignores the buffer and calls `send`.
+    fn send_into(
+        &self,
+        url: &str,
+        action: &str,
+        body: &str,
+        user_agent: &str,
+        options: &HttpOptions,
+        _buffer: String,
+    ) -> Box<Future<Item = String, Error = RequestError>> {
+        self.send(url, action, body, user_agent, options)
+    }
+}
+
+// Transport of the gateways which were not given one: `HttpTransport`, or `ReqwestTransport` when
+// the `hyper-client` feature is disabled.
+#[cfg(feature = "hyper-client")]
+pub fn default_transport() -> Arc<SoapTransport + Send + Sync> {
+    Arc::new(HttpTransport::new())
+}
+
+// Transport of the gateways which were not given one: `HttpTransport`, or `ReqwestTransport` when
+// the `hyper-client` feature is disabled.
+#[cfg(not(feature = "hyper-client"))]
+pub fn default_transport() -> Arc<SoapTransport + Send + Sync> {
+    Arc::new(ReqwestTransport::new())
+}
+
+// Remove the XML declaration heading the body, if any.
+fn strip_xml_declaration(body: &str) -> &str {
+    let trimmed = body.trim_start();
+    if !trimmed.starts_with("<?xml") {
+        return body;
+    }
+    match trimmed.find("?>") {
+        Some(end) => trimmed[end + 2..].trim_start(),
+        None => body,
+    }
+}
+
+/// SOAP request recorded by `DryRunTransport`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SoapRequest {
+    /// Control url the request would be sent to
+    pub url: String,
+    /// Value of the SOAPAction header, such as `"urn:...:WANIPConnection:1#AddPortMapping"`
+    pub action: String,
+    /// SOAP envelope of the request
+    pub body: String,
+}
+
+/// Transport recording the SOAP requests instead of sending them.
+///
+/// This shows the exact requests an operation sends, without a gateway. Every request is answered
+/// with an empty response to its action, so operations which only need the gateway to accept the
+/// request succeed, while the ones reading values from the response fail with `InvalidResponse`
+/// after the request is recorded.
+#[derive(Debug, Default)]
+pub struct DryRunTransport {
+    requests: Mutex<Vec<SoapRequest>>,
+}
+
+impl DryRunTransport {
+    /// Create a transport which has not recorded any request yet.
+    pub fn new() -> DryRunTransport {
+        DryRunTransport::default()
+    }
+
+    /// Get the requests recorded so far, in the order they were sent.
+    pub fn requests(&self) -> Vec<SoapRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl SoapTransport for DryRunTransport {
+    fn send(
+        &self,
+        url: &str,
+        action: &str,
+        body: &str,
+        _user_agent: &str,
+        _options: &HttpOptions,
+    ) -> Box<Future<Item = String, Error = RequestError>> {
+        self.requests.lock().unwrap().push(SoapRequest {
+            url: url.to_owned(),
+            action: action.to_owned(),
+            body: body.to_owned(),
+        });
+        let mut parts = action.trim_matches('"').splitn(2, '#');
+        let service_type = parts.next().unwrap_or_default();
+        let action = parts.next().unwrap_or_default();
+        Box::new(future::ok(format!(
+            "<?xml version=\"1.0\"?>
+<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\"><s:Body>
+<u:{}Response xmlns:u=\"{}\"/>
+</s:Body></s:Envelope>",
+            action,
+            service_type
+        )))
+    }
+}
+
+#[test]
+fn test_decode_body_gzip() {
+    use std::io::Write;
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+
+    let text = "<?xml version=\"1.0\"?>
+<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\">
+<s:Body>
+<u:GetExternalIPAddressResponse xmlns:u=\"urn:schemas-upnp-org:service:WANIPConnection:1\">
+<NewExternalIPAddress>203.0.113.1</NewExternalIPAddress>
+</u:GetExternalIPAddressResponse>
+</s:Body>
+</s:Envelope>";
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(text.as_bytes()).unwrap();
+    let compressed = encoder.finish().unwrap();
+    match decode_body(compressed, true) {
+        Ok(decoded) => assert_eq!(decoded, text),
+        Err(_) => panic!("the body could not be decoded"),
+    }
+}
+
+#[test]
+fn test_decode_body_invalid() {
+    match decode_body(b"<s:Envelope>\xff</s:Envelope>".to_vec(), false) {
+        Err(RequestError::InvalidResponse(text)) => {
+            assert_eq!(text, "<s:Envelope>\u{fffd}</s:Envelope>")
+        }
+        result => panic!("unexpected result: {:?}", result),
+    }
+    match decode_body(b"<s:Envelope/>".to_vec(), true) {
+        Err(RequestError::InvalidResponse(_)) => (),
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn test_decode_body_bom() {
+    use xmltree::Element;
+
+    let text = "\u{feff}<?xml version=\"1.0\"?>
+<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\">
+<s:Body>
+<u:GetExternalIPAddressResponse xmlns:u=\"urn:schemas-upnp-org:service:WANIPConnection:1\">
+<NewExternalIPAddress>203.0.113.1</NewExternalIPAddress>
+</u:GetExternalIPAddressResponse>
+</s:Body>
+</s:Envelope>";
+    let mut bytes = vec![0, b'\n'];
+    bytes.extend_from_slice(text.as_bytes());
+    for body in &[text.as_bytes(), &bytes[..]] {
+        let decoded = decode_body(body.to_vec(), false).unwrap();
+        assert!(decoded.starts_with("<?xml"));
+        let address = Element::parse(decoded.as_bytes())
+            .unwrap()
+            .get_child("Body")
+            .and_then(|body| body.get_child("GetExternalIPAddressResponse"))
+            .and_then(|response| response.get_child("NewExternalIPAddress"))
+            .and_then(|address| address.text.clone());
+        assert_eq!(address, Some("203.0.113.1".to_string()));
+    }
+}
+
+#[test]
+fn test_http_transport_redirect_headers() {
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::thread;
+    use tokio_core::reactor::Core;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/ctl/IPConn", listener.local_addr().unwrap());
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = [0u8; 4096];
+        let _ = stream.read(&mut request).unwrap();
+        stream
+            .write_all(
+                b"HTTP/1.1 302 Found\r\nLocation: http://192.168.1.1/login\r\n\
+                  Content-Length: 0\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+    });
+
+    let mut core = Core::new().unwrap();
+    let action = "\"urn:schemas-upnp-org:service:WANIPConnection:1#GetStatusInfo\"";
+    let options = HttpOptions::default();
+    let result = core.run(default_transport().send(&url, action, "", "igd", &options));
+    server.join().unwrap();
+    match result {
+        Err(ref err @ RequestError::HttpStatus(302, ..)) => {
+            assert_eq!(err.header("Location"), Some("http://192.168.1.1/login"))
+        }
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn test_http_transport_local_addr() {
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::thread;
+    use tokio_core::reactor::Core;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/ctl/IPConn", listener.local_addr().unwrap());
+    let server = thread::spawn(move || {
+        let (mut stream, peer) = listener.accept().unwrap();
+        let mut request = [0u8; 4096];
+        let _ = stream.read(&mut request).unwrap();
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok")
+            .unwrap();
+        peer
+    });
+
+    let mut core = Core::new().unwrap();
+    let options = HttpOptions {
+        local_addr: Some(Ipv4Addr::new(127, 0, 0, 2)),
+        ..HttpOptions::default()
+    };
+    let action = "\"urn:schemas-upnp-org:service:WANIPConnection:1#GetStatusInfo\"";
+    let response = core.run(default_transport().send(&url, action, "", "igd", &options)).unwrap();
+    assert_eq!(response, "ok");
+    assert_eq!(server.join().unwrap().ip(), Ipv4Addr::new(127, 0, 0, 2));
+}
+
+#[test]
+fn test_http_transport_content_type() {
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::thread;
+    use tokio_core::reactor::Core;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/ctl/IPConn", listener.local_addr().unwrap());
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0u8; 4096];
+        // The body may come in its own packet.
+        while !request.ends_with(b"/>") {
+            let len = stream.read(&mut buf).unwrap();
+            request.extend_from_slice(&buf[..len]);
+        }
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok")
+            .unwrap();
+        String::from_utf8_lossy(&request).into_owned()
+    });
+
+    let mut core = Core::new().unwrap();
+    let options = HttpOptions {
+        content_type: Some("text/xml; charset=\"utf-8\"".to_string()),
+        omit_xml_declaration: true,
+        ..HttpOptions::default()
+    };
+    let action = "\"urn:schemas-upnp-org:service:WANIPConnection:1#GetStatusInfo\"";
+    let body = "<?xml version=\"1.0\"?>\n<s:Envelope/>";
+    let response = core.run(default_transport().send(&url, action, body, "igd", &options)).unwrap();
+    assert_eq!(response, "ok");
+    let request = server.join().unwrap().to_lowercase();
+    assert!(request.contains("content-type: text/xml; charset=\"utf-8\"\r\n"), "{}", request);
+    assert!(request.ends_with("\r\n\r\n<s:envelope/>"), "{}", request);
+}
+
+#[test]
+fn test_http_transport_send_into() {
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::thread;
+    use tokio_core::reactor::Core;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/ctl/IPConn", listener.local_addr().unwrap());
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = [0u8; 4096];
+        let _ = stream.read(&mut request).unwrap();
+        stream
+            .write_all(
+                b"HTTP/1.1 200 OK\r\nContent-Length: 7\r\nConnection: close\r\n\r\n\n<ok/>\n",
+            )
+            .unwrap();
+    });
+
+    let mut core = Core::new().unwrap();
+    let mut buffer = String::with_capacity(1024);
+    buffer.push_str("previous response");
+    let address = buffer.as_ptr();
+    let action = "\"urn:schemas-upnp-org:service:WANIPConnection:1#GetStatusInfo\"";
+    let options = HttpOptions::default();
+    let request = default_transport().send_into(&url, action, "", "igd", &options, buffer);
+    let response = core.run(request).unwrap();
+    server.join().unwrap();
+    assert_eq!(response, "<ok/>\n");
+    assert_eq!(response.as_ptr(), address);
+}
+
+#[test]
+fn test_strip_xml_declaration() {
+    assert_eq!(strip_xml_declaration("<?xml version=\"1.0\"?>\n  <s:Envelope/>"), "<s:Envelope/>");
+    assert_eq!(strip_xml_declaration("<s:Envelope/>"), "<s:Envelope/>");
+    assert_eq!(strip_xml_declaration("<?xml version"), "<?xml version");
+}
+
+#[test]
+fn test_action_quoting() {
+    let action = Action::new("\"urn:schemas-upnp-org:service:WANIPConnection:1#GetStatusInfo\"");
+    assert!(action.is_quoted());
+    let unquoted = action.with_flipped_quoting();
+    assert_eq!(unquoted.as_str(), "urn:schemas-upnp-org:service:WANIPConnection:1#GetStatusInfo");
+    assert!(!unquoted.is_quoted());
+    assert_eq!(unquoted.with_flipped_quoting().as_str(), action.as_str());
+    assert_eq!(action.with_quoting(true).as_str(), action.as_str());
+    assert_eq!(action.with_quoting(false).as_str(), unquoted.as_str());
+}
+
+#[test]
+fn test_digest_authorization() {
+    // Example of RFC 2617, section 3.5.
+    let challenge = parse_digest_challenge(
+        "Digest realm=\"testrealm@host.com\", qop=\"auth,auth-int\", \
+         nonce=\"dcd98b7102dd2f0e8b11d0f600bfb0c093\", opaque=\"5ccc069c403ebaf9f0171e9517f40e41\"",
+    ).unwrap();
+    assert_eq!(challenge.realm, "testrealm@host.com");
+    assert!(challenge.qop_auth);
+    let credentials = Credentials {
+        username: "Mufasa".to_string(),
+        password: "Circle Of Life".to_string(),
+    };
+    let authorization =
+        digest_authorization(&challenge, &credentials, "GET", "/dir/index.html", "0a4f113b");
+    assert!(authorization.contains("response=\"6629fae49393a05397450978507c4ef1\""));
+    assert!(authorization.contains("opaque=\"5ccc069c403ebaf9f0171e9517f40e41\""));
+}
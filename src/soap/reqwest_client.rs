@@ -0,0 +1,177 @@
+//! HTTP backend built on reqwest 0.9, enabled by the `reqwest-client` feature.
+
+use std::net::{IpAddr, Ipv4Addr};
+
+use futures::{Future, Stream};
+use futures::future;
+use reqwest;
+use reqwest::RedirectPolicy;
+use reqwest::async::{Client, Response};
+
+use errors::{HttpError, RequestError};
+use super::{send_with, HttpOptions, HttpRequest, HttpResponse, SoapTransport};
+
+/// Transport sending the SOAP requests over HTTP with reqwest, for applications which already
+/// depend on it.
+///
+/// The connections of the client are reused by the requests sent in a row. When the gateway has a
+/// local address, the requests are sent with a client bound to it instead, whose connections are
+/// not reused.
+#[derive(Clone, Debug)]
+pub struct ReqwestTransport {
+    client: Client,
+}
+
+impl ReqwestTransport {
+    /// Create a transport with a client which does not follow redirects, so that they are
+    /// returned as `RequestError::HttpStatus`.
+    ///
+    /// # Panics
+    ///
+    /// This panics if the client can not be built, like `reqwest::async::Client::new`.
+    pub fn new() -> ReqwestTransport {
+        let client = new_client(None).expect("the reqwest client could not be built");
+        ReqwestTransport::with_client(client)
+    }
+
+    /// Create a transport sending the requests with the given client.
+    pub fn with_client(client: Client) -> ReqwestTransport {
+        ReqwestTransport { client: client }
+    }
+}
+
+impl Default for ReqwestTransport {
+    fn default() -> ReqwestTransport {
+        ReqwestTransport::new()
+    }
+}
+
+impl SoapTransport for ReqwestTransport {
+    fn send(
+        &self,
+        url: &str,
+        action: &str,
+        body: &str,
+        user_agent: &str,
+        options: &HttpOptions,
+    ) -> Box<Future<Item = String, Error = RequestError>> {
+        self.send_into(url, action, body, user_agent, options, String::new())
+    }
+
+    fn send_into(
+        &self,
+        url: &str,
+        action: &str,
+        body: &str,
+        user_agent: &str,
+        options: &HttpOptions,
+        buffer: String,
+    ) -> Box<Future<Item = String, Error = RequestError>> {
+        let client = match options.local_addr {
+            Some(local_addr) => match new_client(Some(local_addr)) {
+                Ok(client) => client,
+                Err(err) => return Box::new(future::err(RequestError::HttpError(err))),
+            },
+            None => self.client.clone(),
+        };
+        let post = move |request: &HttpRequest, buffer| post(&client, request, buffer);
+        send_with(post, url, action, body, user_agent, options, buffer)
+    }
+}
+
+/// Fetch the document at the url, such as the device description of a gateway.
+///
+/// hyper fetches them instead when the `hyper-client` feature is enabled.
+#[cfg(not(feature = "hyper-client"))]
+pub fn get(url: &str) -> Box<Future<Item = Vec<u8>, Error = HttpError>> {
+    let client = match new_client(None) {
+        Ok(client) => client,
+        Err(err) => return Box::new(future::err(err)),
+    };
+    let future = client
+        .get(url)
+        .send()
+        .and_then(|response| response.into_body().concat2())
+        .map(|body| body.to_vec())
+        .map_err(HttpError::from);
+    Box::new(future)
+}
+
+fn post(
+    client: &Client,
+    request: &HttpRequest,
+    buffer: Vec<u8>,
+) -> Box<Future<Item = HttpResponse, Error = HttpError>> {
+    let mut builder = client.post(&request.url).body(request.body.clone());
+    for &(name, ref value) in &request.headers {
+        builder = builder.header(name, &value[..]);
+    }
+    let future = builder.send().and_then(move |response| read_response(response, buffer));
+    Box::new(future.map_err(HttpError::from))
+}
+
+fn read_response(
+    response: Response,
+    buffer: Vec<u8>,
+) -> Box<Future<Item = HttpResponse, Error = reqwest::Error>> {
+    let status = response.status().as_u16();
+    let headers = response
+        .headers()
+        .iter()
+        .map(|(name, value)| {
+            let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+            (name.as_str().to_owned(), value)
+        })
+        .collect();
+    let body = response.into_body().fold(buffer, |mut body, chunk| {
+        body.extend_from_slice(&chunk);
+        Ok::<_, reqwest::Error>(body)
+    });
+    let future = body.map(move |body| HttpResponse {
+        status: status,
+        headers: headers,
+        body: body,
+    });
+    Box::new(future)
+}
+
+// Client sending the header names capitalized like the other UPnP clients, since some gateways
+// compare them case-sensitively.
+fn new_client(local_addr: Option<Ipv4Addr>) -> Result<Client, HttpError> {
+    Client::builder()
+        .redirect(RedirectPolicy::none())
+        .http1_title_case_headers()
+        .local_address(local_addr.map(IpAddr::V4))
+        .build()
+        .map_err(HttpError::from)
+}
+
+#[test]
+fn test_reqwest_transport_local_addr() {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use tokio_core::reactor::Core;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/ctl/IPConn", listener.local_addr().unwrap());
+    let server = thread::spawn(move || {
+        let (mut stream, peer) = listener.accept().unwrap();
+        let mut request = [0u8; 4096];
+        let _ = stream.read(&mut request).unwrap();
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok")
+            .unwrap();
+        peer
+    });
+
+    let mut core = Core::new().unwrap();
+    let options = HttpOptions {
+        local_addr: Some(Ipv4Addr::new(127, 0, 0, 2)),
+        ..HttpOptions::default()
+    };
+    let action = "\"urn:schemas-upnp-org:service:WANIPConnection:1#GetStatusInfo\"";
+    let request = ReqwestTransport::new().send(&url, action, "", "igd", &options);
+    assert_eq!(core.run(request).unwrap(), "ok");
+    assert_eq!(server.join().unwrap().ip(), Ipv4Addr::new(127, 0, 0, 2));
+}